@@ -0,0 +1,684 @@
+// File: broker.rs
+//
+// The purpose of this file is to stand in for the chistributed broker: it starts
+// the nodes, routes their messages and acknowledges every one of them, and injects
+// dropped messages, delays, node failures and partitions into the network, driven
+// by a chistributed script or by commands typed in.
+
+#[path = "../chi.rs"]
+mod chi;
+
+use chi::Command;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::{self, Child};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+// Time between hellos to a node that has not answered one yet (500ms)
+pub const HELLO_INTERVAL: u64 = 500;
+// Longest time the broker waits for a message from a node before checking for commands (10ms)
+pub const POLL_TIMEOUT: i64 = 10;
+
+/// Holds data parsed from the command line to initialize the broker
+#[derive(StructOpt, Debug)]
+#[structopt(name = "broker")]
+pub struct CLI {
+  /// chistributed configuration naming the node executable and the nodes
+  #[structopt(long, default_value = "chistributed.conf", parse(from_os_str))]
+  config: PathBuf,
+
+  /// Script to run instead of reading commands from standard input
+  #[structopt(long, parse(from_os_str))]
+  run: Option<PathBuf>,
+
+  #[structopt(long, default_value = "tcp://127.0.0.1:23310")]
+  pub_endpoint: String,
+
+  #[structopt(long, default_value = "tcp://127.0.0.1:23311")]
+  router_endpoint: String,
+
+  /// Probability that a message between nodes is dropped
+  #[structopt(long, default_value = "0.0")]
+  drop_rate: f64,
+
+  /// Longest time in milliseconds a message between nodes is held back, each message
+  /// being held back for a random time up to it
+  #[structopt(long, default_value = "0")]
+  max_delay: u64,
+
+  /// Extra argument passed to every node, such as --node-arg=--vnodes=4
+  #[structopt(long)]
+  node_arg: Vec<String>,
+}
+
+/// The node executable and the names of the nodes, read from a chistributed configuration
+/// A relative executable path is relative to the configuration's directory
+struct Config {
+  executable: PathBuf,
+  nodes: Vec<String>,
+}
+
+impl Config {
+  /// Reads a chistributed configuration
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path of the configuration
+  fn read(path: &PathBuf) -> Result<Config, String> {
+    let contents =
+      fs::read_to_string(path).map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+    let mut executable = None;
+    let mut nodes = Vec::new();
+    for line in contents.lines() {
+      match line.split_once(':') {
+        Some(("node-executable", value)) => executable = Some(PathBuf::from(value.trim())),
+        Some(("nodes", value)) => nodes = value.split_whitespace().map(String::from).collect(),
+        _ => {}
+      }
+    }
+    let executable = executable.ok_or("missing node-executable")?;
+    let dir = path.parent().unwrap_or_else(|| "".as_ref());
+    Ok(Config {
+      executable: dir.join(executable),
+      nodes,
+    })
+  }
+}
+
+/// A message held back: when it is due, its order of sending, source, destination and contents
+type Delayed = (Instant, u64, String, String, String);
+
+/// The faults injected into the network: the failed nodes, the partitions by name,
+/// the probability that a message between nodes is dropped, and the messages held back
+struct Network {
+  failed: HashSet<String>,
+  partitions: HashMap<String, (HashSet<String>, HashSet<String>)>,
+  drop_rate: f64,
+  delayed: BinaryHeap<Reverse<Delayed>>,
+  seq: u64,
+}
+
+impl Network {
+  /// Returns a new Network object with no failed nodes and no partitions
+  ///
+  /// # Arguments
+  ///
+  /// * `drop_rate` - Probability that a message between nodes is dropped
+  fn new(drop_rate: f64) -> Network {
+    Network {
+      failed: HashSet::new(),
+      partitions: HashMap::new(),
+      drop_rate,
+      delayed: BinaryHeap::new(),
+      seq: 0,
+    }
+  }
+
+  /// Cuts one group of nodes off from another
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the partition
+  /// * `side` - The nodes on one side
+  /// * `other` - The nodes on the other side, or None for every other node
+  /// * `nodes` - Every node
+  fn partition(
+    &mut self,
+    name: String,
+    side: Vec<String>,
+    other: Option<Vec<String>>,
+    nodes: &[String],
+  ) {
+    let side: HashSet<String> = side.into_iter().collect();
+    let other = match other {
+      Some(other) => other.into_iter().collect(),
+      None => nodes
+        .iter()
+        .filter(|n| !side.contains(*n))
+        .cloned()
+        .collect(),
+    };
+    self.partitions.insert(name, (side, other));
+  }
+
+  /// Returns whether a message can travel to a node that was started
+  /// Clients reach every node that is not failed
+  ///
+  /// # Arguments
+  ///
+  /// * `source` - Name of the sending node, if it is not a client
+  /// * `destination` - Name of the receiving node
+  fn reachable(&self, source: Option<&str>, destination: &str) -> bool {
+    if self.failed.contains(destination) {
+      return false;
+    }
+    match source {
+      Some(source) => {
+        !self.failed.contains(source)
+          && self.partitions.values().all(|(side, other)| {
+            !(side.contains(source) && other.contains(destination)
+              || other.contains(source) && side.contains(destination))
+          })
+      }
+      None => true,
+    }
+  }
+
+  /// Returns whether a message from one node reaches another, neither cut off nor dropped
+  ///
+  /// # Arguments
+  ///
+  /// * `rng` - Random number generator deciding the drop
+  /// * `source` - Name of the sending node
+  /// * `destination` - Name of the receiving node
+  fn delivers<R: Rng>(&self, rng: &mut R, source: &str, destination: &str) -> bool {
+    self.reachable(Some(source), destination) && !rng.gen_bool(self.drop_rate)
+  }
+
+  /// Holds a message from one node to another back until it is due
+  ///
+  /// # Arguments
+  ///
+  /// * `due` - When the message is due
+  /// * `source` - Name of the sending node
+  /// * `destination` - Name of the receiving node
+  /// * `contents` - The message
+  fn delay(&mut self, due: Instant, source: &str, destination: &str, contents: String) {
+    self.seq += 1;
+    self.delayed.push(Reverse((
+      due,
+      self.seq,
+      source.to_string(),
+      destination.to_string(),
+      contents,
+    )));
+  }
+
+  /// Returns the destination and contents of the messages held back that are due,
+  /// leaving out those that can no longer travel from their source to their destination
+  ///
+  /// # Arguments
+  ///
+  /// * `now` - The current time
+  fn release(&mut self, now: Instant) -> Vec<(String, String)> {
+    let mut released = Vec::new();
+    while self
+      .delayed
+      .peek()
+      .is_some_and(|Reverse((due, _, _, _, _))| *due <= now)
+    {
+      let Reverse((_, _, source, destination, contents)) = self.delayed.pop().unwrap();
+      if self.reachable(Some(&source), &destination) {
+        released.push((destination, contents));
+      }
+    }
+    released
+  }
+}
+
+/// The broker's sockets, the nodes it started and the ones still waiting for a hello answer,
+/// and the faults injected into the network
+struct Broker {
+  pub_socket: zmq::Socket,
+  router_socket: zmq::Socket,
+  pub_endpoint: String,
+  router_endpoint: String,
+  config: Config,
+  node_args: Vec<String>,
+  processes: HashMap<String, Child>,
+  greeting: HashMap<String, Option<Instant>>,
+  network: Network,
+  max_delay: u64,
+  next_id: i32,
+  rng: ThreadRng,
+}
+
+impl Broker {
+  /// Returns a new Broker object with its sockets bound
+  ///
+  /// # Arguments
+  ///
+  /// * `ctx` - A ZeroMQ Context for creating sockets
+  /// * `cli` - The command line options
+  /// * `config` - The chistributed configuration
+  fn new(ctx: &zmq::Context, cli: CLI, config: Config) -> Broker {
+    let pub_socket = ctx.socket(zmq::PUB).unwrap();
+    pub_socket
+      .bind(&cli.pub_endpoint)
+      .expect("failed binding publisher");
+    let router_socket = ctx.socket(zmq::ROUTER).unwrap();
+    router_socket
+      .bind(&cli.router_endpoint)
+      .expect("failed binding router");
+    Broker {
+      pub_socket,
+      router_socket,
+      pub_endpoint: cli.pub_endpoint,
+      router_endpoint: cli.router_endpoint,
+      config,
+      node_args: cli.node_arg,
+      processes: HashMap::new(),
+      greeting: HashMap::new(),
+      network: Network::new(cli.drop_rate),
+      max_delay: cli.max_delay,
+      next_id: 0,
+      rng: rand::thread_rng(),
+    }
+  }
+
+  /// Routes messages and runs commands until the quit command or the end of the commands
+  ///
+  /// # Arguments
+  ///
+  /// * `commands` - The commands, other than waits
+  fn run(&mut self, commands: Receiver<Command>) {
+    loop {
+      match commands.try_recv() {
+        Ok(Command::Quit) | Err(TryRecvError::Disconnected) => return,
+        Ok(command) => self.handle_command(command),
+        Err(TryRecvError::Empty) => {}
+      }
+      self.greet();
+      self.release_delayed();
+      if self
+        .router_socket
+        .poll(zmq::POLLIN, POLL_TIMEOUT)
+        .expect("cannot poll router")
+        > 0
+      {
+        self.receive();
+      }
+    }
+  }
+
+  /// Runs a command
+  ///
+  /// # Arguments
+  ///
+  /// * `command` - The command
+  fn handle_command(&mut self, command: Command) {
+    match command {
+      Command::Start(node) => self.start(&node),
+      Command::Set { node, key, value } => self.request(
+        &node,
+        serde_json::json!({ "type": "set", "key": key, "value": value }),
+      ),
+      Command::Get { node, key } => {
+        self.request(&node, serde_json::json!({ "type": "get", "key": key }))
+      }
+      Command::FailNode(node) => {
+        self.network.failed.insert(node);
+      }
+      Command::RecoverNode(node) => {
+        self.network.failed.remove(&node);
+      }
+      Command::CreatePartition { name, side, other } => {
+        self
+          .network
+          .partition(name, side, other, &self.config.nodes)
+      }
+      Command::RemovePartition(name) => {
+        self.network.partitions.remove(&name);
+      }
+      Command::Wait(_) | Command::Quit => {}
+    }
+  }
+
+  /// Starts a node process, which is greeted until it answers
+  ///
+  /// # Arguments
+  ///
+  /// * `node` - Name of the node
+  fn start(&mut self, node: &str) {
+    if self.processes.contains_key(node) {
+      return;
+    }
+    let mut process = process::Command::new(&self.config.executable);
+    process
+      .arg("--node-name")
+      .arg(node)
+      .arg("--pub-endpoint")
+      .arg(&self.pub_endpoint)
+      .arg("--router-endpoint")
+      .arg(&self.router_endpoint);
+    for peer in self.config.nodes.iter().filter(|n| *n != node) {
+      process.arg("--peer").arg(peer);
+    }
+    process.args(&self.node_args);
+    match process.spawn() {
+      Ok(child) => {
+        self.processes.insert(node.to_string(), child);
+        self.greeting.insert(node.to_string(), None);
+      }
+      Err(e) => eprintln!("cannot start {}: {}", node, e),
+    }
+  }
+
+  /// Says hello to every started node that has not answered yet
+  /// A node misses what is published before it subscribes, so hellos are repeated
+  fn greet(&mut self) {
+    let now = Instant::now();
+    let due: Vec<String> = self
+      .greeting
+      .iter()
+      .filter(|(_, last)| last.is_none_or(|l| now >= l + Duration::from_millis(HELLO_INTERVAL)))
+      .map(|(node, _)| node.to_string())
+      .collect();
+    for node in due {
+      let hello = serde_json::json!({ "type": "hello", "destination": node });
+      self.publish(&node, &hello.to_string());
+      self.greeting.insert(node, Some(now));
+    }
+  }
+
+  /// Sends a client request to a node, giving it the next id
+  ///
+  /// # Arguments
+  ///
+  /// * `node` - Name of the node
+  /// * `request` - The request without its id and destination
+  fn request(&mut self, node: &str, mut request: serde_json::Value) {
+    self.next_id += 1;
+    request["id"] = serde_json::json!(self.next_id);
+    request["destination"] = serde_json::json!(node);
+    println!("request {}: {}", self.next_id, request);
+    if self.reachable(node) {
+      self.publish(node, &request.to_string());
+    }
+  }
+
+  /// Receives a message from a node, acknowledges it, and routes it
+  fn receive(&mut self) {
+    // A REQ socket sends its identity, an empty frame and the contents
+    let frames = match self.router_socket.recv_multipart(0) {
+      Ok(frames) if frames.len() == 3 => frames,
+      Ok(frames) => {
+        eprintln!("Dropping message: expected 3 frames, got {}", frames.len());
+        return;
+      }
+      Err(e) => {
+        eprintln!("Dropping message: cannot receive message: {}", e);
+        return;
+      }
+    };
+    self
+      .router_socket
+      .send_multipart([&frames[0][..], b"", b"{}"], 0)
+      .expect("cannot send acknowledgement");
+
+    let source = String::from_utf8_lossy(&frames[0]).to_string();
+    let contents = String::from_utf8_lossy(&frames[2]).to_string();
+    let json: serde_json::Value = match serde_json::from_str(&contents) {
+      Ok(json) => json,
+      Err(e) => {
+        eprintln!("Dropping message from {}: {}", source, e);
+        return;
+      }
+    };
+    match json.get("destination").and_then(|d| d.as_str()) {
+      Some(destination) => self.route(&source, destination, contents.to_string()),
+      None if json.get("type").and_then(|t| t.as_str()) == Some("helloResponse") => {
+        if self.greeting.remove(&source).is_some() {
+          println!("{} is up", source);
+        }
+      }
+      // Replies to clients are reported, unless the node that sent them has failed
+      None => {
+        if !self.network.failed.contains(&source) {
+          println!("{}: {}", source, contents);
+        }
+      }
+    }
+  }
+
+  /// Sends a message from one node to another through the faults injected into the network
+  ///
+  /// # Arguments
+  ///
+  /// * `source` - Name of the sending node
+  /// * `destination` - Name of the receiving node
+  /// * `contents` - The message
+  fn route(&mut self, source: &str, destination: &str, contents: String) {
+    if !self.processes.contains_key(destination)
+      || !self.network.delivers(&mut self.rng, source, destination)
+    {
+      return;
+    }
+    if self.max_delay == 0 {
+      self.publish(destination, &contents);
+      return;
+    }
+    let due = Instant::now() + Duration::from_millis(self.rng.gen_range(0, self.max_delay + 1));
+    self.network.delay(due, source, destination, contents);
+  }
+
+  /// Publishes the messages held back that are due,
+  /// unless a failure or partition since they were sent keeps them from their destination
+  fn release_delayed(&mut self) {
+    for (destination, contents) in self.network.release(Instant::now()) {
+      if self.processes.contains_key(&destination) {
+        self.publish(&destination, &contents);
+      }
+    }
+  }
+
+  /// Returns whether a message from a client can travel to a node
+  ///
+  /// # Arguments
+  ///
+  /// * `destination` - Name of the receiving node
+  fn reachable(&self, destination: &str) -> bool {
+    self.processes.contains_key(destination) && self.network.reachable(None, destination)
+  }
+
+  /// Publishes a message to a node
+  ///
+  /// # Arguments
+  ///
+  /// * `destination` - Name of the node
+  /// * `contents` - The message
+  fn publish(&self, destination: &str, contents: &str) {
+    // Nodes subscribe to their name, and expect it followed by an empty frame and the contents
+    self
+      .pub_socket
+      .send_multipart([destination.as_bytes(), b"", contents.as_bytes()], 0)
+      .expect("cannot publish message");
+  }
+
+  /// Stops every node process
+  fn stop(&mut self) {
+    for (_, mut child) in self.processes.drain() {
+      let _ = child.kill();
+      let _ = child.wait();
+    }
+  }
+}
+
+/// Reads commands from a script or standard input on a separate thread,
+/// waiting there on wait commands and sending every other command on
+///
+/// # Arguments
+///
+/// * `script` - Path of the script, or None to read standard input
+fn read_commands(script: Option<PathBuf>) -> Receiver<Command> {
+  let (sender, receiver) = mpsc::channel();
+  // A script is parsed whole up front, so that a mistake in it is reported before anything runs
+  let script = script.map(|path| {
+    let contents = fs::read_to_string(&path).unwrap_or_else(|e| {
+      eprintln!("cannot read {:?}: {}", path, e);
+      process::exit(1);
+    });
+    chi::parse(&contents).unwrap_or_else(|e| {
+      eprintln!("{:?}: {}", path, e);
+      process::exit(1);
+    })
+  });
+  thread::spawn(move || {
+    let commands: Box<dyn Iterator<Item = Command>> =
+      match script {
+        Some(commands) => Box::new(commands.into_iter()),
+        None => Box::new(
+          io::stdin().lock().lines().map_while(Result::ok).filter_map(
+            |line| match chi::parse_line(&line) {
+              Ok(command) => command,
+              Err(e) => {
+                eprintln!("{}", e);
+                None
+              }
+            },
+          ),
+        ),
+      };
+    for command in commands {
+      match command {
+        Command::Wait(millis) => thread::sleep(Duration::from_millis(millis)),
+        command => {
+          if sender.send(command).is_err() {
+            return;
+          }
+        }
+      }
+    }
+  });
+  receiver
+}
+
+/// Main function
+fn main() {
+  let cli_options = CLI::from_args();
+  if !(0.0..=1.0).contains(&cli_options.drop_rate) {
+    eprintln!("--drop-rate must be between 0 and 1");
+    process::exit(1);
+  }
+  let config = Config::read(&cli_options.config).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    process::exit(1);
+  });
+  let commands = read_commands(cli_options.run.clone());
+  let ctx = zmq::Context::new();
+  let mut broker = Broker::new(&ctx, cli_options, config);
+  broker.run(commands);
+  broker.stop();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  /// Returns the names of the nodes node-1 to node-`count`
+  ///
+  /// # Arguments
+  ///
+  /// * `count` - Number of nodes
+  fn nodes(count: usize) -> Vec<String> {
+    (1..=count).map(|i| format!("node-{}", i)).collect()
+  }
+
+  #[test]
+  fn partition_cuts_its_sides_off_from_each_other_only() {
+    let mut network = Network::new(0.0);
+    let names = nodes(5);
+    network.partition(
+      String::from("part"),
+      nodes(2),
+      Some(vec![String::from("node-3"), String::from("node-4")]),
+      &names,
+    );
+    assert!(network.reachable(Some("node-1"), "node-2"));
+    assert!(network.reachable(Some("node-3"), "node-4"));
+    assert!(!network.reachable(Some("node-1"), "node-3"));
+    assert!(!network.reachable(Some("node-4"), "node-2"));
+    // node-5 is on neither side, so it reaches both
+    assert!(network.reachable(Some("node-5"), "node-1"));
+    assert!(network.reachable(Some("node-3"), "node-5"));
+    // Clients are never partitioned
+    assert!(network.reachable(None, "node-3"));
+
+    network.partitions.remove("part");
+    assert!(network.reachable(Some("node-1"), "node-3"));
+  }
+
+  #[test]
+  fn partition_without_another_side_cuts_off_every_other_node() {
+    let mut network = Network::new(0.0);
+    let names = nodes(4);
+    network.partition(
+      String::from("alone"),
+      vec![String::from("node-1")],
+      None,
+      &names,
+    );
+    for other in &names[1..] {
+      assert!(!network.reachable(Some("node-1"), other));
+      assert!(!network.reachable(Some(other), "node-1"));
+    }
+    assert!(network.reachable(Some("node-2"), "node-4"));
+  }
+
+  #[test]
+  fn failed_nodes_neither_send_nor_receive() {
+    let mut network = Network::new(0.0);
+    network.failed.insert(String::from("node-1"));
+    assert!(!network.reachable(Some("node-1"), "node-2"));
+    assert!(!network.reachable(Some("node-2"), "node-1"));
+    assert!(!network.reachable(None, "node-1"));
+    assert!(network.reachable(Some("node-2"), "node-3"));
+
+    network.failed.remove("node-1");
+    assert!(network.reachable(Some("node-1"), "node-2"));
+  }
+
+  #[test]
+  fn messages_are_dropped_at_the_drop_rate() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let delivered = |network: &Network, rng: &mut StdRng| {
+      (0..10000)
+        .filter(|_| network.delivers(rng, "node-1", "node-2"))
+        .count()
+    };
+    assert_eq!(delivered(&Network::new(0.0), &mut rng), 10000);
+    assert_eq!(delivered(&Network::new(1.0), &mut rng), 0);
+    let quarter = delivered(&Network::new(0.25), &mut rng);
+    assert!((7000..8000).contains(&quarter), "{} delivered", quarter);
+
+    // A message that cannot travel is never delivered, whatever the drop rate
+    let mut network = Network::new(0.0);
+    network.failed.insert(String::from("node-2"));
+    assert_eq!(delivered(&network, &mut rng), 0);
+  }
+
+  #[test]
+  fn delayed_messages_are_rechecked_against_partitions_made_since_they_were_sent() {
+    let mut network = Network::new(0.0);
+    let names = nodes(3);
+    let now = Instant::now();
+    let later = now + Duration::from_millis(100);
+    network.delay(later, "node-1", "node-2", String::from("first"));
+    network.delay(later, "node-3", "node-2", String::from("second"));
+    network.delay(now, "node-1", "node-3", String::from("third"));
+    network.partition(
+      String::from("part"),
+      vec![String::from("node-1")],
+      None,
+      &names,
+    );
+
+    // Messages are only released once due, and not across the partition made meanwhile
+    assert!(network.release(now).is_empty());
+    assert_eq!(
+      network.release(later),
+      vec![(String::from("node-2"), String::from("second"))]
+    );
+    assert!(network.delayed.is_empty());
+  }
+}
@@ -0,0 +1,159 @@
+// File: chi.rs
+//
+// The purpose of this file is to parse the chistributed scripting language,
+// in which the scripts in scripts/ drive a cluster of nodes.
+
+/// A command of the chistributed scripting language
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Start(String),
+  Set {
+    node: String,
+    key: String,
+    value: String,
+  },
+  Get {
+    node: String,
+    key: String,
+  },
+  FailNode(String),
+  RecoverNode(String),
+  CreatePartition {
+    name: String,
+    side: Vec<String>,
+    other: Option<Vec<String>>,
+  },
+  RemovePartition(String),
+  /// Milliseconds to wait
+  Wait(u64),
+  Quit,
+}
+
+/// Splits a line into words, keeping double-quoted strings whole
+///
+/// # Arguments
+///
+/// * `line` - The line
+pub fn tokenize(line: &str) -> Result<Vec<String>, String> {
+  let mut words = Vec::new();
+  let mut chars = line.chars().peekable();
+  while let Some(&c) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+    } else if c == '"' {
+      chars.next();
+      let mut word = String::new();
+      loop {
+        match chars.next() {
+          Some('"') => break,
+          Some(c) => word.push(c),
+          None => return Err(String::from("unterminated string")),
+        }
+      }
+      words.push(word);
+    } else {
+      let mut word = String::new();
+      while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+          break;
+        }
+        word.push(c);
+        chars.next();
+      }
+      words.push(word);
+    }
+  }
+  Ok(words)
+}
+
+/// Returns the value of an option of a command
+///
+/// # Arguments
+///
+/// * `args` - The command's words after its name
+/// * `flag` - The option, such as -n
+fn option<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+  args
+    .iter()
+    .position(|a| a == flag)
+    .and_then(|i| args.get(i + 1))
+    .map(|a| a.as_str())
+}
+
+/// Returns the value of an option a command cannot do without
+///
+/// # Arguments
+///
+/// * `args` - The command's words after its name
+/// * `flag` - The option, such as -n
+fn required(args: &[String], flag: &str) -> Result<String, String> {
+  option(args, flag)
+    .map(String::from)
+    .ok_or(format!("missing {}", flag))
+}
+
+/// Returns the nodes of a comma-separated list
+///
+/// # Arguments
+///
+/// * `list` - The list
+fn nodes(list: &str) -> Vec<String> {
+  list.split(',').map(String::from).collect()
+}
+
+/// Parses one line of a script
+/// Returns None for a blank line
+///
+/// # Arguments
+///
+/// * `line` - The line
+pub fn parse_line(line: &str) -> Result<Option<Command>, String> {
+  let words = tokenize(line)?;
+  let (command, args) = match words.split_first() {
+    Some(split) => split,
+    None => return Ok(None),
+  };
+  let command = match command.as_str() {
+    "start" => Command::Start(required(args, "-n")?),
+    "set" => Command::Set {
+      node: required(args, "-n")?,
+      key: required(args, "-k")?,
+      value: required(args, "-v")?,
+    },
+    "get" => Command::Get {
+      node: required(args, "-n")?,
+      key: required(args, "-k")?,
+    },
+    "fail_node" => Command::FailNode(required(args, "-n")?),
+    "recover_node" => Command::RecoverNode(required(args, "-n")?),
+    "create_partition" => Command::CreatePartition {
+      name: required(args, "-n")?,
+      side: nodes(&required(args, "-p")?),
+      other: option(args, "-2").map(nodes),
+    },
+    "remove_partition" => Command::RemovePartition(required(args, "-n")?),
+    "wait" => {
+      let seconds: f64 = required(args, "-t")?
+        .parse()
+        .map_err(|_| String::from("-t must be a number of seconds"))?;
+      Command::Wait((seconds * 1000.0) as u64)
+    }
+    "quit" => Command::Quit,
+    other => return Err(format!("unknown command {}", other)),
+  };
+  Ok(Some(command))
+}
+
+/// Parses a script
+///
+/// # Arguments
+///
+/// * `script` - The script's contents
+pub fn parse(script: &str) -> Result<Vec<Command>, String> {
+  let mut commands = Vec::new();
+  for (i, line) in script.lines().enumerate() {
+    let command = parse_line(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    commands.extend(command);
+  }
+  Ok(commands)
+}
@@ -0,0 +1,325 @@
+// File: clock.rs
+//
+// The purpose of this file is to version writes with a hybrid logical clock
+// so that every node agrees on which of two writes to a key is newer, and
+// optionally with vector clocks so that concurrent writes can be kept side by side.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// How conflicting writes to the same key are resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Versioning {
+  /// Keep the write with the newest hybrid logical clock version
+  Lww,
+  /// Keep every write that is not causally older than another as a sibling
+  Vector,
+}
+
+impl FromStr for Versioning {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Versioning, String> {
+    match s {
+      "lww" => Ok(Versioning::Lww),
+      "vector" => Ok(Versioning::Vector),
+      _ => Err(format!("unknown versioning mode: {}", s)),
+    }
+  }
+}
+
+/// Where a node reads the time from, so that a simulation can control it
+pub trait TimeSource: Send + Sync {
+  /// Returns the current time, which deadlines and failure detectors are measured against
+  fn now(&self) -> Instant;
+
+  /// Returns the current physical time in milliseconds since the Unix epoch
+  fn wall_millis(&self) -> u64;
+}
+
+/// The machine's own clocks
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn wall_millis(&self) -> u64 {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis() as u64)
+      .unwrap_or(0)
+  }
+}
+
+/// The version of a stored value
+/// Versions are ordered by wall time, then logical counter, then writer name,
+/// so concurrent writes from different nodes still have a deterministic winner
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version {
+  pub wall: u64,
+  pub logical: u32,
+  pub writer: String,
+}
+
+/// A hybrid logical clock: physical time in milliseconds, plus a logical counter
+/// that keeps versions increasing when physical time stalls or runs behind another node's
+pub struct HybridClock {
+  node_name: String,
+  time: Arc<dyn TimeSource>,
+  wall: u64,
+  logical: u32,
+}
+
+impl HybridClock {
+  /// Returns a new HybridClock object
+  ///
+  /// # Arguments
+  ///
+  /// * `node_name` - Name of the node whose writes the clock versions
+  /// * `time` - Where physical time is read from
+  pub fn new(node_name: &str, time: Arc<dyn TimeSource>) -> HybridClock {
+    HybridClock {
+      node_name: String::from(node_name),
+      time,
+      wall: 0,
+      logical: 0,
+    }
+  }
+
+  /// Returns a new version for a local write, newer than every version seen so far
+  pub fn tick(&mut self) -> Version {
+    let now = self.time.wall_millis();
+    if now > self.wall {
+      self.wall = now;
+      self.logical = 0;
+    } else {
+      self.advance_past(self.wall, self.logical);
+    }
+    Version {
+      wall: self.wall,
+      logical: self.logical,
+      writer: self.node_name.to_string(),
+    }
+  }
+
+  /// Advances the clock past a version received from another node
+  ///
+  /// # Arguments
+  ///
+  /// * `version` - The received version
+  pub fn observe(&mut self, version: &Version) {
+    let now = self.time.wall_millis();
+    if now > self.wall && now > version.wall {
+      self.wall = now;
+      self.logical = 0;
+    } else if version.wall > self.wall {
+      self.advance_past(version.wall, version.logical);
+    } else if version.wall == self.wall {
+      self.advance_past(self.wall, self.logical.max(version.logical));
+    } else {
+      self.advance_past(self.wall, self.logical);
+    }
+  }
+
+  /// Moves the clock to the time right after a given one, carrying into the next
+  /// millisecond when the logical counter is exhausted
+  ///
+  /// # Arguments
+  ///
+  /// * `wall` - Physical part of the given time
+  /// * `logical` - Logical part of the given time
+  fn advance_past(&mut self, wall: u64, logical: u32) {
+    match logical.checked_add(1) {
+      Some(logical) => {
+        self.wall = wall;
+        self.logical = logical;
+      }
+      None => {
+        self.wall = wall + 1;
+        self.logical = 0;
+      }
+    }
+  }
+}
+
+/// The causal history of a write: for each node, how many writes it has made
+/// to the key that the write has seen
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+  /// Returns the number of writes a node has made that the clock has seen
+  ///
+  /// # Arguments
+  ///
+  /// * `node_name` - Name of the node
+  pub fn get(&self, node_name: &str) -> u64 {
+    self.0.get(node_name).cloned().unwrap_or(0)
+  }
+
+  /// Sets the number of writes a node has made that the clock has seen
+  ///
+  /// # Arguments
+  ///
+  /// * `node_name` - Name of the node
+  /// * `count` - Number of writes
+  pub fn set(&mut self, node_name: &str, count: u64) {
+    self.0.insert(String::from(node_name), count);
+  }
+
+  /// Combines another clock into this one, keeping the larger count for every node
+  ///
+  /// # Arguments
+  ///
+  /// * `other` - The clock being combined
+  pub fn merge(&mut self, other: &VectorClock) {
+    for (name, count) in other.0.iter() {
+      let entry = self.0.entry(name.to_string()).or_insert(0);
+      *entry = (*entry).max(*count);
+    }
+  }
+
+  /// Returns whether this clock has seen a given write
+  ///
+  /// # Arguments
+  ///
+  /// * `dot` - The write
+  pub fn contains(&self, dot: &Dot) -> bool {
+    self.get(&dot.node_name) >= dot.count
+  }
+
+  /// Adds a write to the writes this clock has seen
+  ///
+  /// # Arguments
+  ///
+  /// * `dot` - The write
+  pub fn add(&mut self, dot: &Dot) {
+    let count = self.get(&dot.node_name).max(dot.count);
+    self.set(&dot.node_name, count);
+  }
+}
+
+/// A single write to a key: the node that stored it, and how many writes that node
+/// had stored to the key counting this one
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dot {
+  pub node_name: String,
+  pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicU64, Ordering};
+
+  /// Physical time that a test sets by hand
+  struct ManualTime(AtomicU64);
+
+  impl TimeSource for ManualTime {
+    fn now(&self) -> Instant {
+      Instant::now()
+    }
+
+    fn wall_millis(&self) -> u64 {
+      self.0.load(Ordering::SeqCst)
+    }
+  }
+
+  fn manual_clock(node_name: &str, wall: u64) -> (HybridClock, Arc<ManualTime>) {
+    let time = Arc::new(ManualTime(AtomicU64::new(wall)));
+    (HybridClock::new(node_name, time.clone()), time)
+  }
+
+  fn version(wall: u64, logical: u32, writer: &str) -> Version {
+    Version {
+      wall,
+      logical,
+      writer: String::from(writer),
+    }
+  }
+
+  #[test]
+  fn tick_follows_physical_time() {
+    let (mut clock, time) = manual_clock("node-0", 100);
+    assert_eq!(clock.tick(), version(100, 0, "node-0"));
+    assert_eq!(clock.tick(), version(100, 1, "node-0"));
+    time.0.store(101, Ordering::SeqCst);
+    assert_eq!(clock.tick(), version(101, 0, "node-0"));
+  }
+
+  #[test]
+  fn tick_keeps_increasing_when_physical_time_goes_backwards() {
+    let (mut clock, time) = manual_clock("node-0", 100);
+    let first = clock.tick();
+    time.0.store(50, Ordering::SeqCst);
+    let second = clock.tick();
+    let third = clock.tick();
+    assert_eq!(second, version(100, 1, "node-0"));
+    assert!(first < second && second < third);
+  }
+
+  #[test]
+  fn exhausted_counter_carries_into_the_next_millisecond() {
+    let (mut clock, _) = manual_clock("node-0", 100);
+    clock.observe(&version(100, u32::MAX - 1, "node-1"));
+    assert_eq!(clock.tick(), version(101, 0, "node-0"));
+    assert_eq!(clock.tick(), version(101, 1, "node-0"));
+
+    let (mut clock, _) = manual_clock("node-0", 100);
+    clock.observe(&version(200, u32::MAX, "node-1"));
+    assert_eq!(clock.tick(), version(201, 1, "node-0"));
+  }
+
+  #[test]
+  fn observing_a_future_version_moves_past_it() {
+    let (mut clock, time) = manual_clock("node-0", 100);
+    clock.tick();
+    let future = version(500, 3, "node-1");
+    clock.observe(&future);
+    let next = clock.tick();
+    assert_eq!(next, version(500, 5, "node-0"));
+    assert!(next > future);
+
+    // Physical time catching up resets the counter
+    time.0.store(501, Ordering::SeqCst);
+    assert_eq!(clock.tick(), version(501, 0, "node-0"));
+  }
+
+  fn vector(counts: &[(&str, u64)]) -> VectorClock {
+    let mut clock = VectorClock::default();
+    for (name, count) in counts {
+      clock.set(name, *count);
+    }
+    clock
+  }
+
+  #[test]
+  fn merged_vector_clock_has_seen_the_writes_of_both() {
+    let left = vector(&[("node-0", 2), ("node-1", 1)]);
+    let right = vector(&[("node-0", 1), ("node-1", 2)]);
+    let mut merged = left.clone();
+    merged.merge(&right);
+    assert_eq!(merged, vector(&[("node-0", 2), ("node-1", 2)]));
+
+    let dot = |node_name: &str, count| Dot {
+      node_name: String::from(node_name),
+      count,
+    };
+    assert!(!left.contains(&dot("node-1", 2)));
+    assert!(merged.contains(&dot("node-1", 2)));
+    assert!(!merged.contains(&dot("node-0", 3)));
+  }
+
+  #[test]
+  fn observing_a_past_version_still_advances() {
+    let (mut clock, _) = manual_clock("node-0", 100);
+    clock.tick();
+    clock.observe(&version(50, 9, "node-1"));
+    assert_eq!(clock.tick(), version(100, 2, "node-0"));
+  }
+}
@@ -0,0 +1,311 @@
+// File: detector.rs
+//
+// The purpose of this file is to decide when a node should consider its
+// successor failed, from the pings it sent and the pongs it got back.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::Instant;
+
+// Number of pong intervals the phi-accrual detector keeps to estimate their distribution
+pub const MAX_SAMPLES: usize = 100;
+// Lowest standard deviation of pong intervals, in milliseconds, so that a steady
+// history does not make the slightest delay look like a failure
+pub const MIN_STD_DEV: f64 = 200.0;
+
+/// Decides whether a monitored node has failed
+pub trait FailureDetector: Send + Sync {
+  /// Records that a ping was sent to the monitored node
+  fn ping_sent(&mut self, now: Instant);
+
+  /// Records that a pong was received from the monitored node
+  fn pong_received(&mut self, now: Instant);
+
+  /// Returns whether the monitored node is suspected to have failed
+  fn suspects(&self, now: Instant) -> bool;
+
+  /// Forgets everything about the monitored node, once another node is monitored instead
+  fn reset(&mut self);
+}
+
+/// The available failure detectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetectorKind {
+  /// Suspect the node after a fixed number of pings in a row went unanswered
+  Counter,
+  /// Suspect the node once a pong is late enough given how pongs arrived so far
+  Phi,
+}
+
+impl FromStr for DetectorKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<DetectorKind, String> {
+    match s {
+      "counter" => Ok(DetectorKind::Counter),
+      "phi" => Ok(DetectorKind::Phi),
+      _ => Err(format!("unknown failure detector: {}", s)),
+    }
+  }
+}
+
+/// Which failure detector monitors each successor, and how suspicious it must be
+#[derive(Debug, Clone, Copy)]
+pub struct DetectorSettings {
+  pub kind: DetectorKind,
+  pub missed_pings: u32,
+  pub phi_threshold: f64,
+}
+
+impl DetectorSettings {
+  /// Returns a new failure detector
+  ///
+  /// # Arguments
+  ///
+  /// * `ping_interval` - Milliseconds between two pings to the monitored node
+  pub fn build(&self, ping_interval: u32) -> Box<dyn FailureDetector> {
+    match self.kind {
+      DetectorKind::Counter => Box::new(PingCounter::new(self.missed_pings)),
+      DetectorKind::Phi => Box::new(PhiAccrual::new(self.phi_threshold, ping_interval as f64)),
+    }
+  }
+}
+
+/// Suspects the monitored node once a number of pings in a row went unanswered
+pub struct PingCounter {
+  threshold: u32,
+  missed: u32,
+}
+
+impl PingCounter {
+  /// Returns a new PingCounter object
+  ///
+  /// # Arguments
+  ///
+  /// * `threshold` - Number of unanswered pings before the node is suspected
+  pub fn new(threshold: u32) -> PingCounter {
+    PingCounter {
+      threshold,
+      missed: 0,
+    }
+  }
+}
+
+impl FailureDetector for PingCounter {
+  fn ping_sent(&mut self, _now: Instant) {
+    self.missed += 1;
+  }
+
+  fn pong_received(&mut self, _now: Instant) {
+    self.missed = 0;
+  }
+
+  fn suspects(&self, _now: Instant) -> bool {
+    self.missed >= self.threshold
+  }
+
+  fn reset(&mut self) {
+    self.missed = 0;
+  }
+}
+
+/// The phi-accrual failure detector (Hayashibara et al.)
+/// Keeps the intervals between recent pongs and computes phi, the suspicion that the node
+/// failed given how long it has been since the last pong: phi = -log10 of the probability
+/// that a pong would arrive this late, with intervals assumed normally distributed
+/// Until a pong arrives, the time since the first ping is measured against the ping interval
+pub struct PhiAccrual {
+  threshold: f64,
+  expected_interval: f64,
+  intervals: VecDeque<f64>,
+  last_pong: Option<Instant>,
+  first_ping: Option<Instant>,
+}
+
+impl PhiAccrual {
+  /// Returns a new PhiAccrual object
+  ///
+  /// # Arguments
+  ///
+  /// * `threshold` - Value of phi above which the node is suspected
+  /// * `expected_interval` - Milliseconds expected between pongs before any has arrived
+  pub fn new(threshold: f64, expected_interval: f64) -> PhiAccrual {
+    PhiAccrual {
+      threshold,
+      expected_interval,
+      intervals: VecDeque::new(),
+      last_pong: None,
+      first_ping: None,
+    }
+  }
+
+  /// Returns the mean and standard deviation of the intervals between pongs, in milliseconds
+  fn distribution(&self) -> (f64, f64) {
+    if self.intervals.is_empty() {
+      return (self.expected_interval, self.expected_interval / 4.0);
+    }
+    let n = self.intervals.len() as f64;
+    let mean = self.intervals.iter().sum::<f64>() / n;
+    let variance = self
+      .intervals
+      .iter()
+      .map(|i| (i - mean) * (i - mean))
+      .sum::<f64>()
+      / n;
+    (mean, variance.sqrt().max(MIN_STD_DEV))
+  }
+
+  /// Returns the current suspicion that the node failed
+  /// The normal distribution's tail is computed with a logistic approximation
+  ///
+  /// # Arguments
+  ///
+  /// * `now` - The current time
+  pub fn phi(&self, now: Instant) -> f64 {
+    let since = match self.last_pong.or(self.first_ping) {
+      Some(since) => since,
+      None => return 0.0,
+    };
+    let elapsed = now.duration_since(since).as_millis() as f64;
+    let (mean, std_dev) = self.distribution();
+    let y = (elapsed - mean) / std_dev;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+      -(e / (1.0 + e)).log10()
+    } else {
+      -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+  }
+}
+
+impl FailureDetector for PhiAccrual {
+  fn ping_sent(&mut self, now: Instant) {
+    if self.first_ping.is_none() {
+      self.first_ping = Some(now);
+    }
+  }
+
+  fn pong_received(&mut self, now: Instant) {
+    if let Some(last) = self.last_pong {
+      if self.intervals.len() == MAX_SAMPLES {
+        self.intervals.pop_front();
+      }
+      self
+        .intervals
+        .push_back(now.duration_since(last).as_millis() as f64);
+    }
+    self.last_pong = Some(now);
+  }
+
+  fn suspects(&self, now: Instant) -> bool {
+    self.phi(now) > self.threshold
+  }
+
+  fn reset(&mut self) {
+    self.intervals.clear();
+    self.last_pong = None;
+    self.first_ping = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn at(start: Instant, millis: u64) -> Instant {
+    start + Duration::from_millis(millis)
+  }
+
+  /// Returns a detector that received a pong every second for a while, and when the last one came
+  fn steady(start: Instant) -> (PhiAccrual, Instant) {
+    let mut detector = PhiAccrual::new(8.0, 1000.0);
+    for i in 0..10 {
+      detector.ping_sent(at(start, i * 1000));
+      detector.pong_received(at(start, i * 1000));
+    }
+    (detector, at(start, 9000))
+  }
+
+  #[test]
+  fn counter_suspects_after_missed_pings_in_a_row() {
+    let now = Instant::now();
+    let mut detector = PingCounter::new(3);
+    detector.ping_sent(now);
+    detector.ping_sent(now);
+    assert!(!detector.suspects(now));
+    detector.pong_received(now);
+    detector.ping_sent(now);
+    detector.ping_sent(now);
+    assert!(!detector.suspects(now));
+    detector.ping_sent(now);
+    assert!(detector.suspects(now));
+    detector.reset();
+    assert!(!detector.suspects(now));
+  }
+
+  #[test]
+  fn phi_is_half_likely_at_the_mean_interval() {
+    let start = Instant::now();
+    let (detector, last) = steady(start);
+    assert_eq!(detector.distribution(), (1000.0, MIN_STD_DEV));
+    assert!((detector.phi(at(last, 1000)) - 0.5f64.log10().abs()).abs() < 1e-9);
+    assert!(detector.phi(at(last, 500)) < 0.01);
+  }
+
+  #[test]
+  fn phi_grows_with_the_time_since_the_last_pong() {
+    let start = Instant::now();
+    let (detector, last) = steady(start);
+    let phis: Vec<f64> = (0..20).map(|i| detector.phi(at(last, i * 100))).collect();
+    assert!(phis.windows(2).all(|pair| pair[0] < pair[1]));
+  }
+
+  #[test]
+  fn phi_crosses_the_threshold_between_five_and_six_deviations_late() {
+    let start = Instant::now();
+    let (detector, last) = steady(start);
+    // Pongs come every 1000ms, with the lowest deviation of 200ms
+    assert!(!detector.suspects(at(last, 2000)));
+    assert!(detector.suspects(at(last, 2200)));
+  }
+
+  #[test]
+  fn phi_is_measured_against_the_ping_interval_until_a_pong_arrives() {
+    let start = Instant::now();
+    let mut detector = PhiAccrual::new(8.0, 1000.0);
+    assert_eq!(detector.phi(at(start, 60_000)), 0.0);
+
+    // The expected interval's deviation is a quarter of it, 250ms
+    detector.ping_sent(start);
+    detector.ping_sent(at(start, 1000));
+    assert!((detector.phi(at(start, 1000)) - 0.5f64.log10().abs()).abs() < 1e-9);
+    assert!(!detector.suspects(at(start, 2250)));
+    assert!(detector.suspects(at(start, 2500)));
+
+    // A single pong does not make an interval, so the ping interval is still used
+    detector.pong_received(at(start, 2000));
+    assert!(!detector.suspects(at(start, 3000)));
+    assert!(detector.suspects(at(start, 4500)));
+
+    detector.reset();
+    assert_eq!(detector.phi(at(start, 60_000)), 0.0);
+  }
+
+  #[test]
+  fn only_recent_intervals_are_kept() {
+    let start = Instant::now();
+    let mut detector = PhiAccrual::new(8.0, 1000.0);
+    let mut now = 0;
+    for _ in 0..MAX_SAMPLES {
+      detector.pong_received(at(start, now));
+      now += 5000;
+    }
+    for _ in 0..=MAX_SAMPLES {
+      detector.pong_received(at(start, now));
+      now += 1000;
+    }
+    assert_eq!(detector.intervals.len(), MAX_SAMPLES);
+    assert_eq!(detector.distribution(), (1000.0, MIN_STD_DEV));
+  }
+}
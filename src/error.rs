@@ -0,0 +1,61 @@
+// File: error.rs
+//
+// The purpose of this file is to describe why a message from the network
+// could not be handled, so that the node can log it and keep running.
+
+use std::fmt;
+use std::io;
+
+/// Why a message from the network was dropped
+#[derive(Debug)]
+pub enum MessageError {
+  /// The transport failed to deliver a message, for the reason it gave
+  Receive(String),
+  /// The transport could not pass a message on, for the reason it gave
+  Send(String),
+  /// A message from the broker did not have the address, empty and contents frames
+  Frames(usize),
+  /// A message's contents were not valid UTF-8
+  NotUtf8,
+  /// A message was not valid JSON, or did not have the fields its type needs
+  Malformed(serde_json::Error),
+  /// A message held a position outside the receiving node's tables
+  OutOfRange { field: &'static str, value: i64 },
+  /// The storage could not durably record what a message asked for
+  Storage(io::Error),
+  /// A message of a type nodes do not handle, such as a reply meant for a client
+  Unhandled(String),
+}
+
+impl MessageError {
+  /// Returns a short name for the kind of error, under which it is counted
+  pub fn kind(&self) -> &'static str {
+    match self {
+      MessageError::Receive(_) => "receive",
+      MessageError::Send(_) => "send",
+      MessageError::Frames(_) => "frames",
+      MessageError::NotUtf8 => "utf8",
+      MessageError::Malformed(_) => "malformed",
+      MessageError::OutOfRange { .. } => "range",
+      MessageError::Storage(_) => "storage",
+      MessageError::Unhandled(_) => "unhandled",
+    }
+  }
+}
+
+impl fmt::Display for MessageError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MessageError::Receive(e) => write!(f, "cannot receive message: {}", e),
+      MessageError::Send(e) => write!(f, "cannot send message: {}", e),
+      MessageError::Frames(count) => write!(f, "expected 3 frames, got {}", count),
+      MessageError::NotUtf8 => write!(f, "message is not valid UTF-8"),
+      MessageError::Malformed(e) => write!(f, "malformed message: {}", e),
+      MessageError::OutOfRange { field, value } => write!(f, "{} {} is out of range", field, value),
+      MessageError::Storage(e) => write!(f, "cannot store: {}", e),
+      MessageError::Unhandled(kind) => write!(f, "{} messages cannot be handled", kind),
+    }
+  }
+}
+
+impl std::error::Error for MessageError {}
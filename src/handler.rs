@@ -4,40 +4,162 @@
 // to the external network.
 
 extern crate chan;
-extern crate zmq;
-use crate::msg;
+use crate::clock::{HybridClock, TimeSource, VectorClock, Version, Versioning};
+use crate::detector::{DetectorSettings, FailureDetector};
+use crate::error::MessageError;
+use crate::hint::{Hint, HintQueue};
+use crate::msg::{self, Message, RequestId};
 use crate::node;
+use crate::storage::{Record, Storage};
+use crate::transport::Transport;
 use node::{NodeEntry, QueryType, TransferType};
-use serde::Serialize;
 extern crate parking_lot;
 use crate::hash;
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use hash::Id;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use rand::rngs::StdRng;
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-// M defines the number of keys in the ring topology (2^M)
-pub const M: i32 = 8;
-// The number of pongs a node can miss before it's predecessor declares it failed
-pub const FAILURE_THRESHOLD: i32 = 2;
 // Period to stabilize ring (1s)
 pub const STABILIZE_TIME: u32 = 1000;
+// Period to compare Merkle trees with replica holders (10s)
+pub const ANTI_ENTROPY_TIME: u32 = 10000;
+// Time a client waits for a Set or Delete to be confirmed before it gets an error (5s)
+pub const WRITE_TIMEOUT: u64 = 5000;
+// Number of nodes asked to ping a suspected successor before it is declared failed
+pub const INDIRECT_PROBES: usize = 3;
+// Time those nodes have to reach the suspected successor (2s)
+pub const PROBE_TIMEOUT: u64 = 2000;
 
 /// Automatic reference counted wrapper for a RWLock contained the handler data
 pub struct Handler {
     inner: Arc<RwLock<HandlerInner>>,
 }
 
-/// Contains the node data, sockets to communicate with the network, and number of pongs it has missed from the next node in the ring
+/// Contains the virtual nodes, the clock versioning the writes this process coordinates
+/// and how conflicting writes are resolved, the replication factor and default quorums,
+/// the writes and reads waiting on other nodes, the sequence number of the last replication
+/// update applied from each replicated node, the writes waiting for unreachable owners,
+/// the leave the process is going through, if any, the nodes this process is pinging on
+/// behalf of other nodes, how many messages were dropped for each kind of error,
+/// the transport carrying messages to and from the network, where the time is read from
+/// and the random number generator picking the fingers and successors to refresh
 pub struct HandlerInner {
+    debug: bool,
     connected: bool,
     node_name: String,
     peer_names: Vec<String>,
-    sub_socket: zmq::Socket,
-    req_socket: zmq::Socket,
+    transport: Box<dyn Transport>,
+    time: Arc<dyn TimeSource>,
+    rng: StdRng,
+    vnodes: Vec<VirtualNode>,
+    clock: HybridClock,
+    versioning: Versioning,
+    replication_factor: usize,
+    write_quorum: usize,
+    read_quorum: usize,
+    pending_writes: HashMap<i32, PendingWrite>,
+    pending_gets: HashMap<i32, PendingGet>,
+    pending_replications: HashMap<RequestId, PendingReplication>,
+    pending_reads: HashMap<RequestId, PendingRead>,
+    replica_seqs: HashMap<Id, u64>,
+    hints: HintQueue,
+    leave: Option<Leave>,
+    left: bool,
+    indirect_probes: HashMap<String, IndirectProbe>,
+    error_counts: Mutex<HashMap<&'static str, u64>>,
+}
+
+/// A node this process pings because other nodes suspect it failed,
+/// with the virtual nodes to tell once it answers
+struct IndirectProbe {
+    requesters: HashSet<String>,
+    deadline: Instant,
+}
+
+/// A leave this process is going through: the client request to answer once it is done,
+/// and the hand-off of each virtual node's keys, or None for virtual nodes with no node
+/// on another process to hand off to
+struct Leave {
+    id: Option<i32>,
+    handoffs: Vec<Option<Handoff>>,
+}
+
+/// The keys a leaving virtual node owns, the successor on another process adopting them,
+/// the predecessor on another process that now comes right before that successor,
+/// and whether the successor confirmed it adopted the keys
+struct Handoff {
+    successor: NodeEntry,
+    predecessor: Option<NodeEntry>,
+    keys: Vec<String>,
+    values: Vec<Record>,
+    acked: bool,
+}
+
+/// A client Set or Delete this process coordinates, waiting for the key's owner to confirm
+/// that the write quorum stored it, and the write as sent to the owner once it is found
+/// The value is None for a Delete
+struct PendingWrite {
+    key: String,
+    value: Option<String>,
+    quorum: usize,
+    deadline: Instant,
+    sent: Option<Hint>,
+}
+
+/// A client Get this process coordinates, waiting for the key's owner to send back
+/// the newest copy its read quorum found
+struct PendingGet {
+    key: String,
+    deadline: Instant,
+}
+
+/// A Set or Delete stored by one of this process's virtual nodes, waiting for
+/// enough replica holders to confirm it before the coordinator is told
+struct PendingReplication {
+    owner: String,
+    key: String,
+    version: Version,
+    holders: HashSet<String>,
+    needed: usize,
+    deadline: Instant,
+}
+
+/// A client Get one of this process's virtual nodes owns, waiting for enough replica
+/// holders to send their copy of the key, with the virtual node's own copy and each holder's
+struct PendingRead {
+    key: String,
+    vnode: usize,
+    record: Option<Record>,
+    replies: HashMap<String, Option<Record>>,
+    needed: usize,
+    deadline: Instant,
+}
+
+/// One of the process's positions on the ring, with its own finger table and successor list,
+/// the failure detector monitoring the next node in the ring, the other nodes' attempt
+/// to reach that node once it is suspected, the failure detector monitoring the previous
+/// node in the ring along with that node's name, and the salt of its id
+struct VirtualNode {
     node: node::Node,
-    pings: i32,
+    detector: Box<dyn FailureDetector>,
+    probe: Option<Probe>,
+    pred_detector: Box<dyn FailureDetector>,
+    watched_pred: Option<String>,
+    pred_probe: Option<Probe>,
+    salt: u32,
+}
+
+/// A suspected successor or predecessor that other nodes were asked to ping,
+/// and when it is declared failed if none of them reached it
+struct Probe {
+    suspect: String,
+    deadline: Instant,
 }
 
 /// Interface for Handler
@@ -46,53 +168,100 @@ impl Handler {
     ///
     /// # Arguments
     ///
-    /// * `ctx` - A ZeroMQ Context for creating sockets
+    /// * `transport` - Carries messages to and from the network
     /// * `node_name` - Name of the node
-    /// * `pub_endpoint` - Endpoint for receiving messages
-    /// * `router_endpoint` - Endpoint for sending messages
     /// * `peer` - List of other peers in the network
+    /// * `ring_bits` - Width of the identifier space, the ring holds 2^ring_bits keys
+    /// * `vnodes` - Number of positions on the ring owned by this process
+    /// * `storage` - Backend holding the process's key/value data
+    /// * `versioning` - How conflicting writes to a key are resolved
+    /// * `replication_factor` - Number of copies of each key, counting the owner's
+    /// * `write_quorum` - Number of copies that must store a write before it is confirmed, unless the request says otherwise
+    /// * `read_quorum` - Number of copies a read consults, unless the request says otherwise
+    /// * `hints` - Queue of writes waiting for unreachable owners
+    /// * `detector` - Failure detector each virtual node monitors its successor with
+    /// * `time` - Where the time is read from
+    /// * `rng` - Random number generator picking the fingers and successors to refresh
+    /// * `debug` - Whether to print every received message
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        ctx: zmq::Context,
+        transport: Box<dyn Transport>,
         node_name: &str,
-        pub_endpoint: &str,
-        router_endpoint: &str,
         peer: Option<Vec<String>>,
+        ring_bits: u32,
+        vnodes: usize,
+        storage: Box<dyn Storage>,
+        versioning: Versioning,
+        replication_factor: usize,
+        write_quorum: usize,
+        read_quorum: usize,
+        hints: HintQueue,
+        detector: DetectorSettings,
+        time: Arc<dyn TimeSource>,
+        rng: StdRng,
+        debug: bool,
     ) -> Handler {
-        let sub_socket = ctx.socket(zmq::SUB).unwrap();
-        sub_socket
-            .connect(pub_endpoint)
-            .expect("failed connecting subscriber");
-
-        sub_socket
-            .set_subscribe(node_name.as_bytes())
-            .expect("failed subscribing");
-
-        let req_socket = ctx.socket(zmq::REQ).unwrap();
-        req_socket
-            .connect(router_endpoint)
-            .expect("failed connecting requester");
-        req_socket
-            .set_identity(node_name.as_bytes())
-            .expect("failed requesting");
-
         let mut peer_names = Vec::new();
         if let Some(peers) = peer {
             peer_names.extend_from_slice(&peers)
         }
 
-        // Length of successor list, defined in Chord as log(N)
-        let tau: i32 = f64::ceil(f64::log2((peer_names.len() + 1) as f64)) as i32;
-        let id = hash::hash(node_name);
+        // Length of successor list, defined in Chord as log(N) where N counts every virtual node
+        // It is long enough to hold every replica holder
+        let ring_size = (peer_names.len() + 1) * vnodes;
+        let tau: i32 =
+            (f64::ceil(f64::log2(ring_size as f64)) as i32).max(replication_factor as i32 - 1);
+
+        // Every virtual node shares the process's storage
+        let storage = Arc::new(RwLock::new(storage));
+        let vnodes = (0..vnodes)
+            .map(|i| {
+                let name = node::vnode_name(node_name, i);
+                let id = hash::hash(&name, ring_bits);
+                VirtualNode {
+                    node: node::Node::new(
+                        ring_bits,
+                        &name,
+                        id,
+                        tau,
+                        replication_factor - 1,
+                        storage.clone(),
+                    ),
+                    detector: detector.build(STABILIZE_TIME),
+                    probe: None,
+                    pred_detector: detector.build(STABILIZE_TIME),
+                    watched_pred: None,
+                    pred_probe: None,
+                    salt: 0,
+                }
+            })
+            .collect();
 
         Handler {
             inner: Arc::new(RwLock::new(HandlerInner {
+                debug,
                 connected: false,
                 node_name: String::from(node_name),
-                sub_socket,
-                req_socket,
+                transport,
                 peer_names,
-                node: node::Node::new(M, node_name, id, tau),
-                pings: 0,
+                vnodes,
+                clock: HybridClock::new(node_name, time.clone()),
+                time,
+                rng,
+                versioning,
+                replication_factor,
+                write_quorum,
+                read_quorum,
+                pending_writes: HashMap::new(),
+                pending_gets: HashMap::new(),
+                pending_replications: HashMap::new(),
+                pending_reads: HashMap::new(),
+                replica_seqs: HashMap::new(),
+                hints,
+                leave: None,
+                left: false,
+                indirect_probes: HashMap::new(),
+                error_counts: Mutex::new(HashMap::new()),
             })),
         }
     }
@@ -103,152 +272,850 @@ impl Handler {
         loop {
             // Read new message
             let read_inner_self = lock.read();
-            let _address = read_inner_self.sub_socket.recv_string(0).unwrap().unwrap();
-            let _skip = read_inner_self.sub_socket.recv_string(0).unwrap().unwrap();
-            let contents = read_inner_self.sub_socket.recv_string(0).unwrap().unwrap();
+            let received = read_inner_self.receive();
+            let connected = read_inner_self.connected;
             RwLockReadGuard::unlock_fair(read_inner_self);
-            let m: msg::RcvdMessage = serde_json::from_str(&contents).unwrap();
+            match received {
+                Ok(contents) => self.deliver(&contents),
+                Err(e) => lock.write().record_error(&e),
+            }
+            // Stop once the node has handed its keys over and left the ring
+            if self.has_left() {
+                return;
+            }
             // Begin periodic stabilization after network detects node's existence
-            if m.r#type == "hello" {
+            if !connected && lock.read().connected {
                 self.periodic_stabilize();
             }
-            // Handle new message
-            let mut write_inner_self = lock.write();
-            write_inner_self.handle_messages(m);
-            RwLockWriteGuard::unlock_fair(write_inner_self);
         }
     }
 
+    /// Returns whether the node has handed its keys over and left the ring
+    pub fn has_left(&self) -> bool {
+        self.inner.read().left
+    }
+
+    /// Parses and handles a message received from the network
+    /// Messages that cannot be parsed or handled are logged, counted and dropped,
+    /// and a malformed client request is answered with an error when it has an id
+    ///
+    /// # Arguments
+    ///
+    /// * `contents` - The message as received
+    pub fn deliver(&self, contents: &str) {
+        let mut write_inner_self = self.inner.write();
+        match msg::parse(contents) {
+            Ok((destination, m)) => {
+                if let Err(e) = write_inner_self.handle_messages(destination.as_deref(), m) {
+                    write_inner_self.record_error(&e);
+                }
+            }
+            Err(e) => {
+                if let Some(reply) = msg::error_reply(contents, &e.to_string()) {
+                    write_inner_self.send_message(reply);
+                }
+                write_inner_self.record_error(&MessageError::Malformed(e));
+            }
+        }
+        RwLockWriteGuard::unlock_fair(write_inner_self);
+    }
+
     /// Periodically runs a set of tasks to return the ring of nodes to a stable state
     fn periodic_stabilize(&self) {
         let tick = chan::tick_ms(STABILIZE_TIME);
+        let anti_entropy = chan::tick_ms(ANTI_ENTROPY_TIME);
 
-        let lock = self.inner.clone();
+        let handler = Handler {
+            inner: self.inner.clone(),
+        };
         thread::spawn(move || loop {
             chan_select! {
                 // Every 1 second
-                tick.recv() => {
-                    let read_inner_self = lock.read();
-                    // For debugging topology: read_inner_self.node.display_ring();
-                    read_inner_self.stabilize_ring();
-                    read_inner_self.ping_self();
-                    read_inner_self.heal_partition();
-                    RwLockReadGuard::unlock_fair(read_inner_self);
-                    let mut write_inner_self = lock.write();
-                    write_inner_self.fix_fingers();
-                    write_inner_self.fix_successors();
-                    write_inner_self.ping_successor();
-                    RwLockWriteGuard::unlock_fair(write_inner_self);
-                }
+                tick.recv() => handler.stabilize(),
+                // Every 10 seconds
+                anti_entropy.recv() => handler.anti_entropy(),
             }
         });
     }
+
+    /// Runs the tasks that return the ring of nodes to a stable state once
+    pub fn stabilize(&self) {
+        let read_inner_self = self.inner.read();
+        // For debugging topology: read_inner_self.vnodes[0].node.display_ring();
+        for v in 0..read_inner_self.vnodes.len() {
+            read_inner_self.stabilize_ring(v);
+            read_inner_self.heal_partition(v);
+        }
+        read_inner_self.ping_self();
+        RwLockReadGuard::unlock_fair(read_inner_self);
+        let mut write_inner_self = self.inner.write();
+        for v in 0..write_inner_self.vnodes.len() {
+            write_inner_self.fix_fingers(v);
+            write_inner_self.fix_successors(v);
+            write_inner_self.ping_successor(v);
+            write_inner_self.ping_predecessor(v);
+        }
+        write_inner_self.ping_hinted_owners();
+        write_inner_self.route_hints();
+        write_inner_self.send_handoffs();
+        write_inner_self.expire_requests();
+        RwLockWriteGuard::unlock_fair(write_inner_self);
+    }
+
+    /// Returns the name of each virtual node along with the name of its successor
+    #[cfg(test)]
+    pub fn successors(&self) -> Vec<(String, String)> {
+        self.inner
+            .read()
+            .vnodes
+            .iter()
+            .map(|v| {
+                (
+                    v.node.get_name().to_string(),
+                    v.node.get_successor().node_name,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the id and name of each virtual node
+    #[cfg(test)]
+    pub fn entries(&self) -> Vec<NodeEntry> {
+        self.inner
+            .read()
+            .vnodes
+            .iter()
+            .map(|v| NodeEntry::new(v.node.get_id(), v.node.get_name()))
+            .collect()
+    }
+
+    /// Takes one step of a lookup at a virtual node, as FindSucc messages are routed
+    /// Returns true and the virtual node's successor if it owns the key,
+    /// or false and the closest node preceding the key in its finger table
+    ///
+    /// # Arguments
+    ///
+    /// * `vnode` - Name of the virtual node
+    /// * `key` - The key being looked up
+    #[cfg(test)]
+    pub fn route(&self, vnode: &str, key: Id) -> (bool, NodeEntry) {
+        let inner = self.inner.read();
+        let node = &inner
+            .vnodes
+            .iter()
+            .find(|v| v.node.get_name() == vnode)
+            .unwrap_or_else(|| panic!("unknown virtual node {}", vnode))
+            .node;
+        match node.find_predecessor(key) {
+            (true, _) => (true, node.get_successor()),
+            step => step,
+        }
+    }
+
+    /// Returns the name of each virtual node along with the name of its predecessor, if any
+    #[cfg(test)]
+    pub fn predecessors(&self) -> Vec<(String, Option<String>)> {
+        self.inner
+            .read()
+            .vnodes
+            .iter()
+            .map(|v| {
+                (
+                    v.node.get_name().to_string(),
+                    v.node.get_predecessor().map(|pred| pred.node_name),
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the number of writes waiting to be handed off to their owners
+    #[cfg(test)]
+    pub fn hint_count(&self) -> usize {
+        self.inner.read().hints.len()
+    }
+
+    /// Returns how many messages were dropped for a kind of error
+    #[cfg(test)]
+    pub fn error_count(&self, kind: &str) -> u64 {
+        self.inner
+            .read()
+            .error_counts
+            .lock()
+            .get(kind)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Returns the record of a key in the process's own store, tombstones included
+    #[cfg(test)]
+    pub fn record(&self, key: &str) -> Option<Record> {
+        self.inner.read().vnodes[0].node.get(key)
+    }
+
+    /// Returns the records of a key in the replicas the process holds, tombstones included
+    #[cfg(test)]
+    pub fn replica_records(&self, key: &str) -> Vec<Record> {
+        self.inner.read().vnodes[0].node.replica_records(key)
+    }
+
+    /// Returns whether a virtual node is still waiting for its replica holders
+    /// to see the deletion of a key
+    #[cfg(test)]
+    pub fn tracks_tombstone(&self, key: &str) -> bool {
+        self.inner
+            .read()
+            .vnodes
+            .iter()
+            .any(|v| v.node.tracks_tombstone(key))
+    }
+
+    /// Compares every virtual node's Merkle tree with its replica holders once
+    pub fn anti_entropy(&self) {
+        let read_inner_self = self.inner.read();
+        for v in 0..read_inner_self.vnodes.len() {
+            read_inner_self.start_anti_entropy(v);
+        }
+        RwLockReadGuard::unlock_fair(read_inner_self);
+    }
 }
 
 /// Interface for HandlerInner
 impl HandlerInner {
-    /// A new node tries to join other nodes to form a complete ring
-    fn join(&self) {
+    /// A new virtual node tries to join other nodes to form a complete ring
+    /// Virtual nodes other than the first also join the first, so a lone process still forms one ring
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn join(&self, v: usize) {
+        let node = &self.vnodes[v].node;
         for peer in self.peer_names.iter() {
-            self.send_to_broker(&msg::Join::new(&self.node_name, &peer));
+            self.send_message(msg::Join::new(node.get_name(), peer, node.get_id()));
+        }
+        if v != 0 {
+            self.send_message(msg::Join::new(
+                node.get_name(),
+                &self.node_name,
+                node.get_id(),
+            ));
         }
     }
 
-    /// Routes a new message through the broker
+    /// Moves a virtual node to a new id after another node was found owning its current id
+    /// The name is re-hashed with an increasing salt until it lands somewhere free
     ///
     /// # Arguments
     ///
-    /// * `msg` - The new message
-    fn send_to_broker<T: Serialize>(&self, msg: T) {
-        let json = serde_json::json!(msg);
-        let j = serde_json::to_string(&json).expect("cannot convert json to string");
-        self.req_socket.send(&j, 0).expect("cannot send message");
+    /// * `v` - Index of the virtual node
+    fn resalt(&mut self, v: usize) {
+        let vnode = &mut self.vnodes[v];
+        vnode.salt += 1;
+        let salted = format!("{}#{}", vnode.node.get_name(), vnode.salt);
+        let id = hash::hash(&salted, vnode.node.ring_bits());
+        vnode.node.rekey(id);
+        vnode.detector.reset();
+    }
 
-        // Must receive acknowledgement from broker before next message can be read
-        let _ = self.req_socket.recv_string(0).unwrap().unwrap();
+    /// Returns the index of the virtual node with the given name
+    /// Messages without a known virtual node, such as client requests, go to the first one
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the virtual node
+    fn vnode_index(&self, name: Option<&str>) -> usize {
+        name.and_then(|name| self.vnodes.iter().position(|v| v.node.get_name() == name))
+            .unwrap_or(0)
+    }
+
+    /// Returns the causal context a write coordinated by this process is sent with:
+    /// the client's context, or an empty one, under vector-clock versioning
+    /// and none under last-writer-wins
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - The context the client passed with the write, if any
+    fn write_context(&self, context: Option<VectorClock>) -> Option<VectorClock> {
+        match self.versioning {
+            Versioning::Vector => Some(context.unwrap_or_default()),
+            Versioning::Lww => None,
+        }
     }
 
-    /// Periodically verifies the current node's immediate successor
-    pub fn stabilize_ring(&self) {
-        let successor = self.node.get_successor();
-        self.send_to_broker(&msg::GetPred::new(&self.node_name, &successor.node_name));
+    /// Returns the current time
+    fn now(&self) -> Instant {
+        self.time.now()
     }
 
-    /// Periodically refreshes a random finger table entry
-    pub fn fix_fingers(&mut self) {
-        let mut rng = rand::thread_rng();
-        let i = rng.gen_range(1, M);
-        let query_id = self.node.get_finger_start(i);
-        self.node.push_query(query_id, QueryType::FixFinger);
-        self.find_successor(query_id, &self.node_name, Some(i));
+    /// Receives the next message from the network
+    /// Returns its contents, or why it could not be received
+    fn receive(&self) -> Result<String, MessageError> {
+        let contents = self.transport.receive()?;
+        self.debug_log(&contents);
+        Ok(contents)
     }
 
-    /// Periodically refreshes a random successor on the successor list
-    pub fn fix_successors(&mut self) {
-        let mut rng = rand::thread_rng();
+    /// Prints a line about what the node is doing when debugging
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line
+    fn debug_log(&self, line: &str) {
+        if self.debug {
+            println!("{}", line);
+        }
+    }
+
+    /// Logs a message that could not be handled and counts it by kind of error
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - Why the message could not be handled
+    fn record_error(&self, error: &MessageError) {
+        let mut error_counts = self.error_counts.lock();
+        let count = error_counts.entry(error.kind()).or_insert(0);
+        *count += 1;
+        eprintln!(
+            "Dropping message: {} ({} {} errors so far)",
+            error,
+            count,
+            error.kind()
+        );
+    }
+
+    /// Sends a new message to the process hosting its destination
+    /// A message the transport cannot send is counted and dropped like any other lost message
+    ///
+    /// # Arguments
+    ///
+    /// * `msg` - The new message
+    fn send_message<T: Into<Message>>(&self, msg: T) {
+        let mut json = serde_json::json!(msg.into());
+        msg::route_to_processes(&mut json);
+        if let Err(e) = self.transport.send(&json) {
+            self.record_error(&e);
+        }
+    }
+
+    /// Periodically verifies a virtual node's immediate successor
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn stabilize_ring(&self, v: usize) {
+        let node = &self.vnodes[v].node;
+        let successor = node.get_successor();
+        self.send_message(msg::GetPred::new(node.get_name(), &successor.node_name));
+    }
+
+    /// Periodically refreshes a random finger table entry of a virtual node
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn fix_fingers(&mut self, v: usize) {
+        let node = &mut self.vnodes[v].node;
+        let m = node.ring_bits();
+        if m < 2 {
+            return;
+        }
+        let i = self.rng.gen_range(1, m);
+        let query_id = node.get_finger_start(i);
+        node.push_query(query_id, Some(i as i32), QueryType::FixFinger);
+        self.find_successor(v, query_id, self.vnodes[v].node.get_name(), Some(i as i32));
+    }
+
+    /// Periodically refreshes a random successor on a virtual node's successor list
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn fix_successors(&mut self, v: usize) {
+        let node = &mut self.vnodes[v].node;
         // indexes of live successors in array
-        let indexes = self.node.live_successor_indexes();
+        let indexes = node.live_successor_indexes();
         // get i in range of num live successors
         if !indexes.is_empty() {
-            let i = rng.gen_range(0, indexes.len());
+            let i = self.rng.gen_range(0, indexes.len());
             let random_succ = indexes[i];
-            let query_id = self.node.successor_at_index(random_succ) + 1;
+            let query_id = node
+                .successor_at_index(random_succ)
+                .add_pow2(0, node.ring_bits());
             // find the successor of that one and put it in index + 1
-            self.node.push_query(query_id, QueryType::FixSuccessor);
-            self.find_successor(query_id, &self.node_name, Some((random_succ + 1) as i32));
+            node.push_query(
+                query_id,
+                Some((random_succ + 1) as i32),
+                QueryType::FixSuccessor,
+            );
+            self.find_successor(
+                v,
+                query_id,
+                self.vnodes[v].node.get_name(),
+                Some((random_succ + 1) as i32),
+            );
         }
     }
 
-    /// Periodically tries to rejoin the last failed successor to heal a partition
-    pub fn heal_partition(&self) {
-        if let Some(last_fail) = self.node.get_failed_successor() {
-            self.send_to_broker(&msg::Rejoin::new(&self.node_name, &last_fail.node_name));
+    /// Periodically tries to rejoin a virtual node's last failed successor to heal a partition
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn heal_partition(&self, v: usize) {
+        let node = &self.vnodes[v].node;
+        if let Some(last_fail) = node.get_failed_successor() {
+            self.send_message(msg::Rejoin::new(node.get_name(), &last_fail.node_name));
         }
     }
 
     /// Periodically sends message to self to switch the lock between period thread and ZeroMQ loop
     pub fn ping_self(&self) {
-        self.send_to_broker(&msg::PingSelf::new(&self.node_name));
+        self.send_message(msg::PingSelf::new(&self.node_name));
+    }
+
+    /// Periodically pings a virtual node's successor to make sure it is alive
+    /// If its failure detector suspects it, other nodes are asked to ping it as well,
+    /// and it is declared failed only if none of them reached it in time
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn ping_successor(&mut self, v: usize) {
+        let now = self.now();
+        let vnode = &mut self.vnodes[v];
+        let successor = vnode.node.get_successor();
+        if successor.node_name == vnode.node.get_name() {
+            return;
+        }
+        // A probe of a node that is no longer the successor is dropped
+        if vnode
+            .probe
+            .as_ref()
+            .is_some_and(|p| p.suspect != successor.node_name)
+        {
+            vnode.probe = None;
+        }
+
+        if vnode.detector.suspects(now) {
+            match vnode.probe.as_ref().map(|p| p.deadline <= now) {
+                Some(true) => {
+                    self.fail_successor(v);
+                    return;
+                }
+                Some(false) => (),
+                None => {
+                    let helpers = vnode
+                        .node
+                        .probe_helpers(&successor.node_name, INDIRECT_PROBES);
+                    if helpers.is_empty() {
+                        self.fail_successor(v);
+                        return;
+                    }
+                    vnode.probe = Some(Probe {
+                        suspect: successor.node_name.to_string(),
+                        deadline: now + Duration::from_millis(PROBE_TIMEOUT),
+                    });
+                    for helper in helpers {
+                        self.send_message(msg::PingReq::new(
+                            self.vnodes[v].node.get_name(),
+                            &helper.node_name,
+                            &successor.node_name,
+                        ));
+                    }
+                }
+            }
+        }
+        self.vnodes[v].detector.ping_sent(now);
+        self.send_message(msg::Ping::new(
+            self.vnodes[v].node.get_name(),
+            &successor.node_name,
+        ));
+    }
+
+    /// Periodically pings a virtual node's predecessor to make sure it is alive
+    /// If its failure detector suspects it, other nodes are asked to ping it as well, and
+    /// only if none of them reached it in time is the predecessor cleared and the keys of its
+    /// replica taken over, instead of waiting for another node to notify
+    /// Predecessors on the same process are not pinged since they cannot fail on their own
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    pub fn ping_predecessor(&mut self, v: usize) {
+        let now = self.now();
+        let vnode = &mut self.vnodes[v];
+        let pred = match vnode.node.get_predecessor() {
+            Some(pred) if node::physical_name(&pred.node_name) != self.node_name => pred,
+            _ => {
+                vnode.watched_pred = None;
+                vnode.pred_probe = None;
+                return;
+            }
+        };
+        // A new predecessor is monitored from scratch
+        if vnode.watched_pred.as_deref() != Some(&pred.node_name) {
+            vnode.pred_detector.reset();
+            vnode.watched_pred = Some(pred.node_name.to_string());
+            vnode.pred_probe = None;
+        }
+
+        if vnode.pred_detector.suspects(now) {
+            match vnode.pred_probe.as_ref().map(|p| p.deadline <= now) {
+                Some(true) => {
+                    self.fail_predecessor(v);
+                    return;
+                }
+                Some(false) => (),
+                None => {
+                    let helpers = vnode.node.probe_helpers(&pred.node_name, INDIRECT_PROBES);
+                    if helpers.is_empty() {
+                        self.fail_predecessor(v);
+                        return;
+                    }
+                    vnode.pred_probe = Some(Probe {
+                        suspect: pred.node_name.to_string(),
+                        deadline: now + Duration::from_millis(PROBE_TIMEOUT),
+                    });
+                    for helper in helpers {
+                        self.send_message(msg::PingReq::new(
+                            self.vnodes[v].node.get_name(),
+                            &helper.node_name,
+                            &pred.node_name,
+                        ));
+                    }
+                }
+            }
+        }
+        self.vnodes[v].pred_detector.ping_sent(now);
+        self.send_message(msg::Ping::new(
+            self.vnodes[v].node.get_name(),
+            &pred.node_name,
+        ));
     }
 
-    /// Periodically pings successor to make sure it is alive
-    /// If confirmed dead (does not receive 2 pongs in a row):
+    /// Declares a virtual node's predecessor failed:
+    /// Clears it and takes over the keys of its replica
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn fail_predecessor(&mut self, v: usize) {
+        let vnode = &mut self.vnodes[v];
+        let promoted = vnode.node.predecessor_failure();
+        vnode.pred_detector.reset();
+        vnode.watched_pred = None;
+        vnode.pred_probe = None;
+        match promoted {
+            Ok(keys) => self.replicate_keys(v, keys, None),
+            Err(e) => self.record_error(&MessageError::Storage(e)),
+        }
+    }
+
+    /// Declares a virtual node's successor failed:
     /// Takes the next successor in the successor list as its own and
     /// notifies the node that it is its new predecessor
-    pub fn ping_successor(&mut self) {
-        if self.pings < FAILURE_THRESHOLD {
-            let successor = self.node.get_successor();
-            if successor.node_name != self.node_name {
-                self.pings += 1;
-                self.send_to_broker(&msg::Ping::new(&self.node_name, &successor.node_name));
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn fail_successor(&mut self, v: usize) {
+        let vnode = &mut self.vnodes[v];
+        vnode.node.successor_failure();
+        vnode.detector.reset();
+        vnode.probe = None;
+        let node = &self.vnodes[v].node;
+        let new_successor = node.get_successor();
+        self.send_message(msg::Notify::new(
+            node.get_name(),
+            &new_successor.node_name,
+            node.get_id(),
+            true,
+        ));
+    }
+
+    /// Periodically pings the owners that have hints waiting for them,
+    /// so that the hints are handed off once an owner answers
+    pub fn ping_hinted_owners(&self) {
+        let name = self.vnodes[0].node.get_name();
+        for owner in self.hints.owners() {
+            self.send_message(msg::Ping::new(name, &owner));
+        }
+    }
+
+    /// Periodically looks up the node that currently owns the key of every hint and sends
+    /// the hint there, so that it is stored even if its original owner never comes back
+    pub fn route_hints(&mut self) {
+        let name = String::from(self.vnodes[0].node.get_name());
+        let ring_bits = self.vnodes[0].node.ring_bits();
+        let queries: Vec<(Id, i32, String, Version)> = self
+            .hints
+            .iter()
+            .map(|hint| {
+                let query_id = hash::hash(&hint.key, ring_bits);
+                (query_id, hint.id, hint.key.to_string(), hint.version.clone())
+            })
+            .collect();
+        for (query_id, id, key, version) in queries {
+            self.vnodes[0]
+                .node
+                .push_query(query_id, Some(id), QueryType::Hint(key, version));
+            self.find_successor(0, query_id, &name, Some(id));
+        }
+    }
+
+    /// Sends the hints waiting for an owner that is reachable again
+    /// Each hint is dropped once the owner confirms it
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Name of the virtual node the owner confirms the hints to
+    /// * `owner` - Name of the owner
+    fn hand_off(&self, source: &str, owner: &str) {
+        for hint in self.hints.for_owner(owner) {
+            self.send_write(source, &hint, 1);
+        }
+    }
+
+    /// Sends a Set or Delete to the owner of its key
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Name of the virtual node the owner confirms the write to
+    /// * `write` - The write and its owner
+    /// * `w` - Write quorum of the write
+    fn send_write(&self, source: &str, write: &Hint, w: usize) {
+        match &write.value {
+            Some(value) => self.send_message(msg::Store::new(
+                source,
+                &write.owner,
+                &write.key,
+                value,
+                write.id,
+                w,
+                write.version.clone(),
+                write.context.clone(),
+            )),
+            None => self.send_message(msg::Remove::new(
+                source,
+                &write.owner,
+                &write.key,
+                write.id,
+                w,
+                write.version.clone(),
+                write.context.clone(),
+            )),
+        }
+    }
+
+    /// Sends a client Set or Delete to the owner found for its key and remembers it,
+    /// so that it becomes a hint if the owner does not confirm it in time
+    /// Writes that already timed out are dropped
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Name of the virtual node that received the request from the client
+    /// * `write` - The write and its owner
+    fn route_write(&mut self, source: &str, write: Hint) {
+        if let Some(quorum) = self.pending_writes.get(&write.id).map(|p| p.quorum) {
+            self.send_write(source, &write, quorum);
+            if let Some(pending) = self.pending_writes.get_mut(&write.id) {
+                pending.sent = Some(write);
             }
-        } else {
-            self.node.successor_failure();
-            let new_successor = self.node.get_successor();
-            self.send_to_broker(&msg::Notify::new(
-                &self.node_name,
-                &new_successor.node_name,
-                self.node.get_id(),
-                true,
+        }
+    }
+
+    /// Starts leaving the ring: every virtual node gives up the keys it owns to the first
+    /// node after it on another process, which is sent them until it confirms it adopted them
+    /// The leave is refused when a virtual node holds keys but knows no node on another
+    /// process, so that the process never stops while holding keys no other node has
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the client request, if any
+    fn start_leave(&mut self, id: Option<i32>) {
+        let mut handoffs = Vec::new();
+        for v in 0..self.vnodes.len() {
+            let node = &self.vnodes[v].node;
+            let (keys, values) = node.transfer_kvs_range(self.range_start(v), node.get_id());
+            match self.outside_successor(v) {
+                Some(successor) => handoffs.push(Some(Handoff {
+                    successor,
+                    predecessor: self.outside_predecessor(v),
+                    keys,
+                    values,
+                    acked: false,
+                })),
+                None if keys.is_empty() => handoffs.push(None),
+                None => {
+                    if let Some(id) = id {
+                        self.send_message(msg::LeaveFailResponse::no_successor(id));
+                    }
+                    return;
+                }
+            }
+        }
+        self.leave = Some(Leave { id, handoffs });
+        self.send_handoffs();
+        self.finish_leave();
+    }
+
+    /// Returns every node the process's virtual nodes know of, along with the virtual nodes themselves
+    fn known_nodes(&self) -> Vec<NodeEntry> {
+        self.vnodes
+            .iter()
+            .flat_map(|vnode| {
+                let node = &vnode.node;
+                let own = NodeEntry::new(node.get_id(), node.get_name());
+                node.known_nodes().into_iter().chain(std::iter::once(own))
+            })
+            .collect()
+    }
+
+    /// Returns the id right before the range of keys a virtual node owns: its predecessor's id,
+    /// or without a predecessor the closest id before its own among the nodes the process knows,
+    /// or its own id, meaning the whole ring, when the process knows no other node
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn range_start(&self, v: usize) -> Id {
+        let node = &self.vnodes[v].node;
+        if let Some(pred) = node.get_predecessor() {
+            return pred.id;
+        }
+        let id = node.get_id();
+        self.known_nodes()
+            .into_iter()
+            .map(|n| n.id)
+            .filter(|candidate| *candidate != id)
+            .fold(id, |start, candidate| {
+                if hash::in_range(candidate, start, id, false) {
+                    candidate
+                } else {
+                    start
+                }
+            })
+    }
+
+    /// Returns the node a leaving virtual node hands its keys to: its first live successor on
+    /// another process, or else the closest node after it on another process among the nodes
+    /// the process knows, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn outside_successor(&self, v: usize) -> Option<NodeEntry> {
+        let node = &self.vnodes[v].node;
+        let outside = |n: &NodeEntry| node::physical_name(&n.node_name) != self.node_name;
+        if let Some(successor) = node.live_successors().into_iter().find(outside) {
+            return Some(successor);
+        }
+        let id = node.get_id();
+        self.known_nodes().into_iter().filter(outside).fold(
+            None,
+            |closest, candidate| match closest {
+                Some(closest) if !hash::in_range(candidate.id, id, closest.id, false) => {
+                    Some(closest)
+                }
+                _ => Some(candidate),
+            },
+        )
+    }
+
+    /// Returns the closest predecessor of a virtual node that is on another process, if it is known
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn outside_predecessor(&self, v: usize) -> Option<NodeEntry> {
+        let mut pred = self.vnodes[v].node.get_predecessor()?;
+        for _ in 0..self.vnodes.len() {
+            if node::physical_name(&pred.node_name) != self.node_name {
+                return Some(pred);
+            }
+            let u = self.vnode_index(Some(&pred.node_name));
+            pred = self.vnodes[u].node.get_predecessor()?;
+        }
+        None
+    }
+
+    /// Periodically sends the hand-offs of a leaving process that are not confirmed yet
+    /// A virtual node right after a node on another process also tells that node its new successor
+    pub fn send_handoffs(&self) {
+        let leave = match &self.leave {
+            Some(leave) => leave,
+            None => return,
+        };
+        for (v, handoff) in leave.handoffs.iter().enumerate() {
+            let handoff = match handoff {
+                Some(handoff) if !handoff.acked => handoff,
+                _ => continue,
+            };
+            let node = &self.vnodes[v].node;
+            self.send_message(msg::LeaveKeys::new(
+                node.get_name(),
+                &handoff.successor.node_name,
+                handoff.keys.clone(),
+                handoff.values.clone(),
+                handoff.predecessor.as_ref().map(|p| p.id),
+                handoff
+                    .predecessor
+                    .as_ref()
+                    .map(|p| p.node_name.to_string()),
             ));
-            self.pings = 0;
+            if let (Some(pred), Some(direct)) = (&handoff.predecessor, node.get_predecessor()) {
+                if pred.node_name == direct.node_name {
+                    self.send_message(msg::LeaveNotice::new(
+                        node.get_name(),
+                        &pred.node_name,
+                        handoff.successor.id,
+                        &handoff.successor.node_name,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Marks the process as left once every hand-off of its leave is confirmed, answering the client
+    /// The process stops handling messages once it has left
+    fn finish_leave(&mut self) {
+        if let Some(leave) = &self.leave {
+            if !self.left && leave.handoffs.iter().flatten().all(|h| h.acked) {
+                if let Some(id) = leave.id {
+                    self.send_message(msg::LeaveResponse::new(id, &self.node_name));
+                }
+                self.left = true;
+            }
         }
     }
 
+    /// Returns the node a leaving virtual node handed its keys to, if it has
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn handoff_target(&self, v: usize) -> Option<String> {
+        self.leave
+            .as_ref()
+            .and_then(|leave| leave.handoffs[v].as_ref())
+            .map(|handoff| handoff.successor.node_name.to_string())
+    }
+
     /// Finds the successor for a given key query
     ///
     /// # Arguments
     ///
+    /// * `v` - Index of the virtual node handling the query
     /// * `query_id` - The key being searched
     /// * `src` - Name of the node that is searching for the key
     /// * `id` - Some queries have an id they need passed with the response
-    fn find_successor(&self, query_id: i32, src: &str, id: Option<i32>) {
-        match self.node.find_predecessor(query_id) {
+    fn find_successor(&self, v: usize, query_id: Id, src: &str, id: Option<i32>) {
+        let node = &self.vnodes[v].node;
+        match node.find_predecessor(query_id) {
             (true, _) => {
-                let successor = self.node.get_successor();
-                self.send_to_broker(&msg::FindSuccResponse::new(
-                    &self.node_name,
-                    &src,
+                let successor = node.get_successor();
+                self.send_message(msg::FindSuccResponse::new(
+                    node.get_name(),
+                    src,
                     &successor.node_name,
                     successor.id,
                     query_id,
@@ -256,23 +1123,446 @@ impl HandlerInner {
                 ))
             }
             (false, next) => {
-                self.send_to_broker(&msg::FindSucc::new(&src, &next.node_name, query_id, id))
+                self.send_message(msg::FindSucc::new(src, &next.node_name, query_id, id))
             }
         }
     }
 
-    /// Duplicates node's data to its successors
-    fn duplicate_to_successors(&self) {
-        for successor in self.node.live_successors() {
-            if successor.node_name != self.node_name {
-                let (keys, values) = self.node.duplicate_store();
-                self.send_to_broker(&msg::Duplicate::new(
-                    &self.node_name,
-                    &successor.node_name,
-                    self.node.get_id(),
-                    keys,
-                    values,
-                ))
+    /// Duplicates a virtual node's whole data to a replica holder that is new, recovered or
+    /// missed a replication update
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    /// * `holder` - Name of the replica holder
+    fn duplicate_to(&self, v: usize, holder: &str) {
+        let node = &self.vnodes[v].node;
+        let (keys, values) = node.duplicate_store();
+        self.send_message(msg::Duplicate::new(
+            node.get_name(),
+            holder,
+            node.get_id(),
+            keys,
+            values,
+            node.replication_seq(),
+        ))
+    }
+
+    /// Sends the root of the Merkle tree over the range of keys a virtual node owns to its
+    /// replica holders, which walk down the tree to find the parts their replica is stale in
+    /// Returns None, skipping the round, when the tree has no root
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    fn start_anti_entropy(&self, v: usize) -> Option<()> {
+        let node = &self.vnodes[v].node;
+        let holders = node.replica_holders();
+        if holders.is_empty() {
+            return Some(());
+        }
+        let min = self.range_start(v);
+        let root = node.merkle_tree(min).hash(0, 0)?;
+        for holder in holders {
+            self.send_message(msg::MerkleHashes::new(
+                node.get_name(),
+                &holder.node_name,
+                node.get_id(),
+                min,
+                0,
+                vec![0],
+                vec![root],
+            ));
+        }
+        Some(())
+    }
+
+    /// Sends the keys a virtual node changed to its replica holders
+    /// Successors on the same process are skipped since they already share its storage
+    /// Tombstones already seen by every holder are dropped afterwards, and so are their replicas
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    /// * `keys` - Keys that changed
+    /// * `request` - Client request replica holders must confirm, if any
+    fn replicate_keys(&mut self, v: usize, keys: Vec<String>, request: Option<RequestId>) {
+        let (records, seq) = self.vnodes[v].node.replication_update(&keys);
+        let node = &self.vnodes[v].node;
+        for holder in node.replica_holders() {
+            self.send_message(msg::Replicate::new(
+                node.get_name(),
+                &holder.node_name,
+                node.get_id(),
+                seq,
+                keys.clone(),
+                records.clone(),
+                request.clone(),
+            ))
+        }
+
+        match self.vnodes[v].node.collect_tombstones() {
+            Ok(collected) if !collected.is_empty() => self.replicate_keys(v, collected, None),
+            Ok(_) => (),
+            Err(e) => self.record_error(&MessageError::Storage(e)),
+        }
+    }
+
+    /// Replicates a Set or Delete a virtual node stored, or tells the coordinator
+    /// that it could not be stored so that the client is answered with the error
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    /// * `coordinator` - Node that received the request from the client
+    /// * `id` - Id of the client request
+    /// * `w` - Write quorum of the request
+    /// * `key` - Key that was written
+    /// * `version` - Version of the write
+    /// * `stored` - Whether the write was stored, or why not
+    #[allow(clippy::too_many_arguments)]
+    fn store_or_report(
+        &mut self,
+        v: usize,
+        coordinator: &str,
+        id: i32,
+        w: usize,
+        key: String,
+        version: Version,
+        stored: io::Result<()>,
+    ) -> Result<(), MessageError> {
+        match stored {
+            Ok(()) => {
+                self.replicate_write(v, coordinator, id, w, key, version);
+                Ok(())
+            }
+            Err(e) => {
+                self.send_message(msg::StoreFailed::new(
+                    self.vnodes[v].node.get_name(),
+                    coordinator,
+                    id,
+                    &key,
+                    version,
+                    &e.to_string(),
+                ));
+                Err(MessageError::Storage(e))
+            }
+        }
+    }
+
+    /// Replicates a Set or Delete a virtual node just stored, and confirms it to the
+    /// coordinator once the write quorum, counting the virtual node's own copy, has stored it
+    /// Only as many replica holders as the virtual node has are waited for, as a quorum is
+    /// never more than the replication factor
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    /// * `coordinator` - Node that received the request from the client
+    /// * `id` - Id of the client request
+    /// * `w` - Write quorum of the request
+    /// * `key` - Key that was written
+    /// * `version` - Version of the write
+    fn replicate_write(
+        &mut self,
+        v: usize,
+        coordinator: &str,
+        id: i32,
+        w: usize,
+        key: String,
+        version: Version,
+    ) {
+        let node = &self.vnodes[v].node;
+        let owner = String::from(node.get_name());
+        let needed = w.saturating_sub(1).min(node.replica_holders().len());
+        if needed == 0 {
+            self.send_message(msg::StoreAck::new(&owner, coordinator, id, &key, version));
+            self.replicate_keys(v, vec![key], None);
+        } else {
+            let request = RequestId::new(coordinator, id);
+            self.pending_replications.insert(
+                request.clone(),
+                PendingReplication {
+                    owner,
+                    key: key.clone(),
+                    version,
+                    holders: HashSet::new(),
+                    needed,
+                    deadline: self.now() + Duration::from_millis(WRITE_TIMEOUT),
+                },
+            );
+            self.replicate_keys(v, vec![key], Some(request));
+        }
+    }
+
+    /// Reads a key a virtual node owns, answering the coordinator right away or once enough
+    /// replica holders have sent their copy to make up the read quorum
+    /// Only as many replica holders as the virtual node has are read from, as a quorum is
+    /// never more than the replication factor
+    ///
+    /// # Arguments
+    ///
+    /// * `v` - Index of the virtual node
+    /// * `coordinator` - Node that received the request from the client
+    /// * `id` - Id of the client request
+    /// * `key` - Key being read
+    /// * `r` - Read quorum of the request
+    fn read_from_quorum(&mut self, v: usize, coordinator: &str, id: i32, key: &str, r: usize) {
+        let node = &self.vnodes[v].node;
+        let record = node.get(key);
+        let holders: Vec<NodeEntry> = node
+            .replica_holders()
+            .into_iter()
+            .take(r.saturating_sub(1))
+            .collect();
+        if holders.is_empty() {
+            self.send_message(msg::RetrieveResponse::new(
+                node.get_name(),
+                coordinator,
+                key,
+                id,
+                record,
+            ));
+            return;
+        }
+
+        let request = RequestId::new(coordinator, id);
+        for holder in holders.iter() {
+            self.send_message(msg::ReadReplica::new(
+                node.get_name(),
+                &holder.node_name,
+                node.get_id(),
+                key,
+                request.clone(),
+            ));
+        }
+        self.pending_reads.insert(
+            request,
+            PendingRead {
+                key: String::from(key),
+                vnode: v,
+                record,
+                replies: HashMap::new(),
+                needed: holders.len(),
+                deadline: self.now() + Duration::from_millis(WRITE_TIMEOUT),
+            },
+        );
+    }
+
+    /// Answers the coordinator of a Get whose read quorum has answered with the newest copy
+    /// of the key, then pushes that copy to every node whose copy was found stale
+    /// A stale copy on the owning virtual node is replaced and replicated to all replica holders,
+    /// otherwise only the stale replica holders are sent the newest copy
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The client request
+    /// * `read` - The completed read
+    fn finish_read(&mut self, request: RequestId, read: PendingRead) {
+        let mut newest = read.record.clone();
+        for record in read.replies.values().flatten() {
+            newest = match newest {
+                Some(existing) => Some(existing.merge(record).unwrap_or(existing)),
+                None => Some(record.clone()),
+            };
+        }
+        self.send_message(msg::RetrieveResponse::new(
+            self.vnodes[read.vnode].node.get_name(),
+            &request.coordinator,
+            &read.key,
+            request.id,
+            newest.clone(),
+        ));
+
+        let newest = match newest {
+            Some(newest) => newest,
+            None => return,
+        };
+        let v = read.vnode;
+        if read.record.as_ref() != Some(&newest) {
+            match self.vnodes[v].node.merge(read.key.to_string(), newest) {
+                Ok(true) => self.replicate_keys(v, vec![read.key], None),
+                Ok(false) => (),
+                Err(e) => self.record_error(&MessageError::Storage(e)),
+            }
+            return;
+        }
+        let node = &self.vnodes[v].node;
+        for (holder, record) in read.replies.iter() {
+            if record.as_ref() != Some(&newest) {
+                self.send_message(msg::ReadRepair::new(
+                    node.get_name(),
+                    holder,
+                    node.get_id(),
+                    &read.key,
+                    newest.clone(),
+                ));
+            }
+        }
+    }
+
+    /// Answers a client Get with the record found for the key
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the client request
+    /// * `key` - Key being read
+    /// * `record` - Newest copy of the key found, if any
+    fn respond_to_get(&self, id: i32, key: &str, record: Option<Record>) {
+        match record {
+            // Concurrent values are returned with their causal context
+            Some(record) if !record.is_tombstone() && !record.siblings.is_empty() => self
+                .send_message(msg::GetSiblingsResponse::new(
+                    id,
+                    key,
+                    record.values(),
+                    record.context(),
+                )),
+            Some(Record {
+                value: Some(value), ..
+            }) => self.send_message(msg::GetSuccessResponse::new(id, key, &value)),
+            _ => self.send_message(msg::GetFailResponse::new(id, key)),
+        }
+    }
+
+    /// Returns a quorum the client asked for, or the default one
+    /// Returns why the quorum cannot be used if it is not between one copy and the
+    /// replication factor, as the command line quorums are checked
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - Name of the quorum in the request
+    /// * `requested` - Quorum in the client request, if any
+    /// * `default` - Quorum used when the request has none
+    fn quorum(
+        &self,
+        field: &str,
+        requested: Option<usize>,
+        default: usize,
+    ) -> Result<usize, String> {
+        match requested {
+            Some(quorum) if quorum == 0 || quorum > self.replication_factor => Err(format!(
+                "{} must be between 1 and the replication factor {}, got {}",
+                field, self.replication_factor, quorum
+            )),
+            Some(quorum) => Ok(quorum),
+            None => Ok(default),
+        }
+    }
+
+    /// Starts waiting for the owner of a key to answer a client Get
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the client request
+    /// * `key` - Key being read
+    fn await_get(&mut self, id: i32, key: &str) {
+        self.pending_gets.insert(
+            id,
+            PendingGet {
+                key: String::from(key),
+                deadline: self.now() + Duration::from_millis(WRITE_TIMEOUT),
+            },
+        );
+    }
+
+    /// Starts waiting for the owner of a key to confirm a client Set or Delete
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the client request
+    /// * `key` - Key being written
+    /// * `value` - Value being set, or None for a Delete
+    /// * `quorum` - Number of copies that must store the write
+    fn await_write(&mut self, id: i32, key: &str, value: Option<String>, quorum: usize) {
+        self.pending_writes.insert(
+            id,
+            PendingWrite {
+                key: String::from(key),
+                value,
+                quorum,
+                deadline: self.now() + Duration::from_millis(WRITE_TIMEOUT),
+                sent: None,
+            },
+        );
+    }
+
+    /// Stops waiting for a client Set or Delete that its owner answered, if the answer is
+    /// for the write as it was sent rather than for another write the client gave the same id
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the client request
+    /// * `key` - Key of the answered write
+    /// * `version` - Version of the answered write
+    fn take_pending_write(
+        &mut self,
+        id: i32,
+        key: &str,
+        version: &Version,
+    ) -> Option<PendingWrite> {
+        let sent = self.pending_writes.get(&id)?.sent.as_ref()?;
+        if sent.key != key || sent.version != *version {
+            return None;
+        }
+        self.pending_writes.remove(&id)
+    }
+
+    /// Answers the client of every write or read that was not answered in time,
+    /// and stops waiting on replications, quorum reads and indirect pings that did not complete in time
+    /// A write that was sent to an owner that never confirmed it is kept as a hint, handed off once
+    /// its owner answers again or another node takes over its key, and the client is told it was
+    /// accepted but not yet confirmed. Any other write that timed out is answered with an error
+    fn expire_requests(&mut self) {
+        let now = self.now();
+        let expired: Vec<i32> = self
+            .pending_writes
+            .iter()
+            .filter(|(_, write)| write.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(write) = self.pending_writes.remove(&id) {
+                let hinted = match write.sent {
+                    Some(hint) => match self.hints.push(hint) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            self.record_error(&MessageError::Storage(e));
+                            false
+                        }
+                    },
+                    None => false,
+                };
+                match (write.value, hinted) {
+                    (Some(value), true) => {
+                        self.send_message(msg::SetHintedResponse::new(id, &write.key, &value))
+                    }
+                    (None, true) => {
+                        self.send_message(msg::DeleteHintedResponse::new(id, &write.key))
+                    }
+                    (Some(_), false) => {
+                        self.send_message(msg::SetFailResponse::new(id, &write.key))
+                    }
+                    (None, false) => {
+                        self.send_message(msg::DeleteFailResponse::new(id, &write.key))
+                    }
+                }
+            }
+        }
+        self.pending_replications
+            .retain(|_, replication| replication.deadline > now);
+        self.pending_reads.retain(|_, read| read.deadline > now);
+        self.indirect_probes.retain(|_, probe| probe.deadline > now);
+
+        let expired: Vec<i32> = self
+            .pending_gets
+            .iter()
+            .filter(|(_, get)| get.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(get) = self.pending_gets.remove(&id) {
+                self.send_message(msg::GetFailResponse::timed_out(id, &get.key));
             }
         }
     }
@@ -281,123 +1571,288 @@ impl HandlerInner {
     ///
     /// # Arguments
     ///
+    /// * `destination` - Name of the node the message is addressed to, if any
     /// * `msg` - The received message
-    fn handle_messages(&mut self, msg: msg::RcvdMessage) {
-        match &msg.r#type[..] {
+    fn handle_messages(
+        &mut self,
+        destination: Option<&str>,
+        msg: Message,
+    ) -> Result<(), MessageError> {
+        // The virtual node the message is addressed to
+        let v = self.vnode_index(destination);
+        let name = String::from(self.vnodes[v].node.get_name());
+
+        // A leaving virtual node passes requests for the keys it gave up to their new owner
+        let msg = match (self.handoff_target(v), msg) {
+            (Some(successor), Message::Store(mut store)) => {
+                store.destination = successor;
+                self.send_message(store);
+                return Ok(());
+            }
+            (Some(successor), Message::Remove(mut remove)) => {
+                remove.destination = successor;
+                self.send_message(remove);
+                return Ok(());
+            }
+            (Some(successor), Message::Retrieve(mut retrieve)) => {
+                retrieve.destination = successor;
+                self.send_message(retrieve);
+                return Ok(());
+            }
+            (_, msg) => msg,
+        };
+
+        match msg {
             // Sends back a hello response
-            "hello" => {
+            Message::Hello(_) => {
                 if !self.connected {
-                    self.send_to_broker(&msg::HelloResponse::new(&self.node_name));
+                    self.send_message(msg::HelloResponse::new(&self.node_name));
                 }
                 self.connected = true;
-                self.join();
+                for v in 0..self.vnodes.len() {
+                    self.join(v);
+                }
             }
 
-            // Sends back a set response and finds the successor of the key that will store the value
-            "set" => {
-                let id = msg.id.expect("set: needs id");
-                let k = msg.key.expect("set: needs key");
-                let v = msg.value.expect("set: needs value");
-
-                self.send_to_broker(&msg::SetResponse::new(id, &k, &v));
+            // Finds the successor of the key that will store the value
+            // The set response is sent once the successor confirms it stored the value
+            // A quorum out of range is answered with an error right away
+            Message::Set(msg::Set {
+                id,
+                key: k,
+                value,
+                w: requested,
+                context,
+            }) => {
+                let w = match self.quorum("w", requested, self.write_quorum) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        self.send_message(msg::SetFailResponse::not_stored(id, &k, &e));
+                        return Err(MessageError::OutOfRange {
+                            field: "w",
+                            value: requested.unwrap_or_default() as i64,
+                        });
+                    }
+                };
+                self.await_write(id, &k, Some(value.to_string()), w);
+
+                let version = self.clock.tick();
+                let context = self.write_context(context);
+                let node = &mut self.vnodes[v].node;
+                let query_id = hash::hash(&k, node.ring_bits());
+                node.push_query(
+                    query_id,
+                    Some(id),
+                    QueryType::Set(k, value, version, context),
+                );
+                self.find_successor(v, query_id, &name, Some(id));
+            }
 
-                let query_id = hash::hash(&k);
-                self.node.push_query(query_id, QueryType::Set(k, v));
-                self.find_successor(query_id, &self.node_name, None);
+            // Finds the successor of the key that will delete the value
+            // The delete response is sent once the successor confirms it deleted the value
+            Message::Delete(msg::Delete {
+                id,
+                key: k,
+                w: requested,
+                context,
+            }) => {
+                let w = match self.quorum("w", requested, self.write_quorum) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        self.send_message(msg::DeleteFailResponse::not_stored(id, &k, &e));
+                        return Err(MessageError::OutOfRange {
+                            field: "w",
+                            value: requested.unwrap_or_default() as i64,
+                        });
+                    }
+                };
+                self.await_write(id, &k, None, w);
+
+                let version = self.clock.tick();
+                let context = self.write_context(context);
+                let node = &mut self.vnodes[v].node;
+                let query_id = hash::hash(&k, node.ring_bits());
+                node.push_query(query_id, Some(id), QueryType::Delete(k, version, context));
+                self.find_successor(v, query_id, &name, Some(id));
             }
 
             // Finds the successor of the key that will retrieve the data if it exists
-            "get" => {
-                let id = msg.id.expect("get: needs id");
-                let k = msg.key.expect("get: needs key");
-
-                let query_id = hash::hash(&k);
+            // The get response is sent once the successor sends back what its read quorum found
+            Message::Get(msg::Get {
+                id,
+                key: k,
+                r: requested,
+            }) => {
+                let r = match self.quorum("r", requested, self.read_quorum) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        self.send_message(msg::GetFailResponse::not_read(id, &k, &e));
+                        return Err(MessageError::OutOfRange {
+                            field: "r",
+                            value: requested.unwrap_or_default() as i64,
+                        });
+                    }
+                };
+                self.await_get(id, &k);
+                let node = &mut self.vnodes[v].node;
+                let query_id = hash::hash(&k, node.ring_bits());
 
-                self.node.push_query(query_id, QueryType::Get(k));
-                self.find_successor(query_id, &self.node_name, Some(id));
+                node.push_query(query_id, Some(id), QueryType::Get(k, r));
+                self.find_successor(v, query_id, &name, Some(id));
             }
 
             // Acknowledge a new node trying to join the ring
-            "join" => {
-                let src = msg.destination.expect("join: needs destination");
-                let dest = msg.source.expect("join: needs source");
+            // Or reject it if another known node already owns its id
+            Message::Join(msg::Join {
+                source: dest,
+                destination: src,
+                node_id,
+            }) => {
+                // A process's own virtual nodes count as known nodes too
+                let owner = self
+                    .vnodes
+                    .iter()
+                    .find_map(|vnode| vnode.node.find_id_owner(node_id, &dest));
+                match owner {
+                    Some(owner) => self.send_message(msg::JoinError::new(
+                        &src,
+                        &dest,
+                        node_id,
+                        &owner.node_name,
+                    )),
+                    None => self.send_message(msg::JoinAck::new(&src, &dest)),
+                }
+            }
 
-                self.send_to_broker(&msg::JoinAck::new(&src, &dest));
+            // Another node already owns this node's id, so move to a new id and try again
+            Message::JoinError(msg::JoinError { node_id, error, .. }) => {
+                // Ignore rejections of an id that has already been replaced
+                if node_id == self.vnodes[v].node.get_id() {
+                    self.debug_log(&error);
+                    self.resalt(v);
+                    self.join(v);
+                }
             }
 
             // Begin joining the ring by asking the acknowledger for its new successor
-            "joinAck" => {
-                let src = msg.source.expect("join: needs source");
-
-                self.node.set_predecessor(None);
-                self.node.push_query(self.node.get_id(), QueryType::JoinAck);
-                self.send_to_broker(&msg::FindSucc::new(
-                    &self.node_name,
-                    &src,
-                    self.node.get_id(),
-                    None,
-                ))
+            Message::JoinAck(msg::JoinAck { source: src, .. }) => {
+                let node = &mut self.vnodes[v].node;
+                node.set_predecessor(None);
+                let id = node.get_id();
+                node.push_query(id, None, QueryType::JoinAck);
+                self.send_message(msg::FindSucc::new(&name, &src, id, None))
             }
 
             // Helps a node find the successor for a key query in the ring
-            "findSucc" => {
-                let query_id = msg.query_id.expect("findSucc: needs query_id");
-                let id = msg.id;
-                let src = msg.source.expect("findSucc: needs source");
-
-                self.find_successor(query_id, &src, id);
+            Message::FindSucc(msg::FindSucc {
+                source: src,
+                query_id,
+                id,
+                ..
+            }) => {
+                self.find_successor(v, query_id, &src, id);
             }
 
             // Handles the query response for a key in the ring
-            "findSuccResponse" => {
-                let node_name = msg.node_name.expect("findSuccResponse: needs node_name");
-                let node_id = msg.node_id.expect("findSuccResponse: needs node_id");
-                let query_id = msg.query_id.expect("findSuccResponse: needs query_id");
-
-                match self.node.pop_query(query_id) {
+            Message::FindSuccResponse(msg::FindSuccResponse {
+                node_name,
+                node_id,
+                query_id,
+                id,
+                ..
+            }) => {
+                let node = &mut self.vnodes[v].node;
+                match node.pop_query(query_id, id) {
                     // Sets the responder as its new successor; finished joining ring
+                    // If the responder already owns this node's id, move to a new id and try again
                     Some(QueryType::JoinAck) => {
-                        self.node.set_successor(NodeEntry::new(node_id, &node_name));
+                        if node_id == node.get_id() && node_name != name {
+                            let owned = format!("Id {} is already owned by {}", node_id, node_name);
+                            self.debug_log(&owned);
+                            self.resalt(v);
+                            self.join(v);
+                        } else {
+                            node.set_successor(NodeEntry::new(node_id, &node_name));
+                        }
                     }
                     // Sets the responder as a successor in a finger table entry
                     Some(QueryType::FixFinger) => {
-                        if let Some(id) = msg.id {
-                            self.node
-                                .set_finger(id, NodeEntry::new(node_id, &node_name));
+                        if let Some(id) = id {
+                            if id < 1 || id as u32 >= node.ring_bits() {
+                                return Err(MessageError::OutOfRange {
+                                    field: "id",
+                                    value: id as i64,
+                                });
+                            }
+                            node.set_finger(id as u32, NodeEntry::new(node_id, &node_name));
                         }
                     }
                     // Tells the responder to respond to the Get
-                    Some(QueryType::Get(k)) => {
-                        if let Some(id) = msg.id {
-                            self.send_to_broker(&msg::Retrieve::new(
-                                &self.node_name,
-                                &node_name,
-                                &k,
-                                id,
-                            ))
+                    Some(QueryType::Get(k, r)) => {
+                        if let Some(id) = id {
+                            self.send_message(msg::Retrieve::new(&name, &node_name, &k, id, r))
                         }
                     }
                     // Tells the responder to store new data from a Set
-                    Some(QueryType::Set(k, v)) => {
-                        self.send_to_broker(&msg::Store::new(&self.node_name, &node_name, &k, &v))
+                    Some(QueryType::Set(key, value, version, context)) => {
+                        if let Some(id) = id {
+                            self.route_write(
+                                &name,
+                                Hint {
+                                    owner: node_name,
+                                    id,
+                                    key,
+                                    value: Some(value),
+                                    version,
+                                    context,
+                                },
+                            );
+                        }
+                    }
+                    // Tells the responder to delete data from a Delete
+                    Some(QueryType::Delete(key, version, context)) => {
+                        if let Some(id) = id {
+                            self.route_write(
+                                &name,
+                                Hint {
+                                    owner: node_name,
+                                    id,
+                                    key,
+                                    value: None,
+                                    version,
+                                    context,
+                                },
+                            );
+                        }
+                    }
+                    // Sends a hinted write to the node that now owns its key
+                    Some(QueryType::Hint(key, version)) => {
+                        if let Some(hint) = self.hints.get(&key, &version) {
+                            let hint = Hint {
+                                owner: node_name.to_string(),
+                                ..hint
+                            };
+                            self.send_write(&name, &hint, 1);
+                        }
                     }
                     // Sets the responder as a new entry in the successor list
                     Some(QueryType::FixSuccessor) => {
-                        if let Some(i) = msg.id {
-                            if self
-                                .node
-                                .fix_successor(i, NodeEntry::new(node_id, &node_name))
+                        if let Some(i) = id {
+                            if i < 0 || i as usize >= node.successor_count() {
+                                return Err(MessageError::OutOfRange {
+                                    field: "id",
+                                    value: i as i64,
+                                });
+                            }
+                            // if the successor is different or goes from dead to live and holds a replica, duplicate data to that successor
+                            if node.fix_successor(i, NodeEntry::new(node_id, &node_name))
+                                && node
+                                    .replica_holders()
+                                    .iter()
+                                    .any(|holder| holder.node_name == node_name)
                             {
-                                let (keys, values) = self.node.duplicate_store();
-                                self.send_to_broker(&msg::Duplicate::new(
-                                    &self.node_name,
-                                    &node_name,
-                                    self.node.get_id(),
-                                    keys,
-                                    values,
-                                ))
+                                self.duplicate_to(v, &node_name);
                             }
-                            // if the successor is different or goes from dead to live and is not self, duplicate data to that successor
                         }
                     }
                     None => (),
@@ -405,178 +1860,644 @@ impl HandlerInner {
             }
 
             // Returns its predecessor to a node trying to stabilize the ring
-            "getPred" => {
-                let src = msg.source.expect("getPred: needs source");
-
-                match self.node.get_predecessor() {
-                    Some(predecessor) => self.send_to_broker(&msg::GetPredResponse::new(
-                        &self.node_name,
+            Message::GetPred(msg::GetPred { source: src, .. }) => {
+                match self.vnodes[v].node.get_predecessor() {
+                    Some(predecessor) => self.send_message(msg::GetPredResponse::new(
+                        &name,
                         &src,
                         Some(predecessor.id),
                         Some(predecessor.node_name),
                     )),
-                    None => self.send_to_broker(&msg::GetPredResponse::new(
-                        &self.node_name,
-                        &src,
-                        None,
-                        None,
-                    )),
+                    None => self.send_message(msg::GetPredResponse::new(&name, &src, None, None)),
                 }
             }
 
             // Uses the predecessor response to determine who its current successor is, then informs that successor
-            "getPredResponse" => {
-                if let Some(pred_id) = msg.pred_id {
-                    if let Some(pred_name) = msg.pred_name {
-                        self.node.stabilize_successor(pred_id, &pred_name);
-                    }
+            Message::GetPredResponse(msg::GetPredResponse {
+                pred_id, pred_name, ..
+            }) => {
+                let node = &mut self.vnodes[v].node;
+                if let (Some(pred_id), Some(pred_name)) = (pred_id, pred_name) {
+                    node.stabilize_successor(pred_id, &pred_name);
                 }
-                let successor = self.node.get_successor();
+                let successor = node.get_successor();
+                let id = node.get_id();
 
-                self.send_to_broker(&msg::Notify::new(
-                    &self.node_name,
-                    &successor.node_name,
-                    self.node.get_id(),
-                    false,
-                ))
+                self.send_message(msg::Notify::new(&name, &successor.node_name, id, false))
             }
 
             // A node thinks it is the current node's new predecessor
             // Transfer keys to the predecessor if it is a new predecessor
             // Or if the current node does not have a predecessor yet (because it is just joining the ring), get keys from its successor
-            "notify" => {
-                let node_name = msg.source.expect("notify: needs source");
-                let node_id = msg.node_id.expect("notify: needs node_id");
-                let failed = msg.failed.expect("notify: needs failed");
-
-                let transfer = self.node.stabilize_predecessor(node_id, &node_name, failed);
+            Message::Notify(msg::Notify {
+                source: node_name,
+                node_id,
+                failed,
+                ..
+            }) => {
+                let node = &mut self.vnodes[v].node;
+                let transfer = node
+                    .stabilize_predecessor(node_id, &node_name, failed)
+                    .map_err(MessageError::Storage)?;
 
                 match transfer {
                     TransferType::Get(min, max) => {
-                        let successor = self.node.get_successor();
-                        self.send_to_broker(&msg::TransferRequest::new(
-                            &self.node_name,
+                        let successor = node.get_successor();
+                        self.send_message(msg::TransferRequest::new(
+                            &name,
                             &successor.node_name,
                             min,
                             max,
                         ));
                     }
                     TransferType::Send(min, max, pred) => {
-                        let (keys, values) = self.node.transfer_kvs_range(min, max);
-                        self.send_to_broker(&msg::TransferKeys::new(
-                            &self.node_name,
+                        let (keys, values) = node.transfer_kvs_range(min, max);
+                        self.send_message(msg::TransferKeys::new(
+                            &name,
                             &pred,
-                            keys,
+                            keys.clone(),
                             values,
                         ));
+                        self.vnodes[v]
+                            .node
+                            .remove_transferred(&keys)
+                            .map_err(MessageError::Storage)?;
                     }
-                    TransferType::Duplicate => {
-                        self.duplicate_to_successors();
+                    TransferType::Replicate(keys) => {
+                        self.replicate_keys(v, keys, None);
                     }
                     TransferType::Nothing => (),
                 }
             }
 
-            // Retrieve data from node to send a GetResponse to the client
-            "retrieve" => {
-                let id = msg.id.expect("retrieve: needs id");
-                let k = msg.key.expect("retieve: needs key");
+            // Reads a key this node owns for the node coordinating a Get
+            Message::Retrieve(msg::Retrieve {
+                source: src,
+                key: k,
+                id,
+                r,
+                ..
+            }) => {
+                self.read_from_quorum(v, &src, id, &k, r);
+            }
 
-                match self.node.get(&k) {
-                    Some(v) => self.send_to_broker(&msg::GetSuccessResponse::new(id, &k, &v)),
-                    None => self.send_to_broker(&msg::GetFailResponse::new(id, &k)),
+            // The newest copy of a key the successor of the key found, which answers the client
+            Message::RetrieveResponse(msg::RetrieveResponse { id, record, .. }) => {
+                if let Some(get) = self.pending_gets.remove(&id) {
+                    self.respond_to_get(id, &get.key, record);
                 }
             }
 
-            // Store data in node from a Set
-            "store" => {
-                let k = msg.key.expect("store: needs key");
-                let v = msg.value.expect("store: needs value");
+            // A request from the successor of a key for this replica holder's copy of it
+            Message::ReadReplica(msg::ReadReplica {
+                source: src,
+                node_id: owner,
+                key: k,
+                request,
+                ..
+            }) => {
+                let record = self.vnodes[v].node.get_replica(owner, &k);
+                self.send_message(msg::ReadReplicaResponse::new(
+                    &name, &src, &k, request, record,
+                ));
+            }
 
-                self.node.set(k, v);
+            // A replica holder's copy of a key being read, the coordinator is answered
+            // with the newest copy once the read quorum has answered, and stale copies are repaired
+            Message::ReadReplicaResponse(msg::ReadReplicaResponse {
+                source: src,
+                request,
+                record,
+                ..
+            }) => {
+                if let Some(read) = self.pending_reads.get_mut(&request) {
+                    read.replies.insert(src, record);
+                    if read.replies.len() >= read.needed {
+                        let read = self.pending_reads.remove(&request).unwrap();
+                        self.finish_read(request, read);
+                    }
+                }
+            }
 
-                self.duplicate_to_successors();
+            // The successor of a key found this replica's copy stale during a read
+            Message::ReadRepair(msg::ReadRepair {
+                node_id: id,
+                key: k,
+                record,
+                ..
+            }) => {
+                self.clock.observe(&record.version);
+                self.vnodes[v]
+                    .node
+                    .update_replica(id, vec![k], vec![Some(record)])
+                    .map_err(MessageError::Storage)?;
+            }
+
+            // Store data in node from a Set, then replicate and confirm it
+            Message::Store(msg::Store {
+                source: src,
+                key: k,
+                value,
+                id,
+                w,
+                version,
+                context,
+                ..
+            }) => {
+                self.clock.observe(&version);
+                let stored = match context {
+                    Some(context) => {
+                        self.vnodes[v]
+                            .node
+                            .set_causal(k.to_string(), Some(value), version.clone(), context)
+                    }
+                    None => self.vnodes[v].node.set(k.to_string(), value, version.clone()),
+                };
+                self.store_or_report(v, &src, id, w, k, version, stored)?;
+            }
+
+            // Delete data in node from a Delete, then replicate and confirm it
+            Message::Remove(msg::Remove {
+                source: src,
+                key: k,
+                id,
+                w,
+                version,
+                context,
+                ..
+            }) => {
+                self.clock.observe(&version);
+                let stored = match context {
+                    Some(context) => {
+                        self.vnodes[v]
+                            .node
+                            .set_causal(k.to_string(), None, version.clone(), context)
+                    }
+                    None => self.vnodes[v].node.delete(k.to_string(), version.clone()),
+                };
+                self.store_or_report(v, &src, id, w, k, version, stored)?;
             }
 
             // A request from a node to get a range of keys from the current node
             // Remove and transfer that data back to the requester
-            "transferRequest" => {
-                let src = msg.source.expect("transferRequest: needs source");
-                let min = msg.min.expect("transferRequest: needs min");
-                let max = msg.max.expect("transferRequest: needs max");
-
-                let (keys, values) = self.node.transfer_kvs_range(min, max);
-                self.send_to_broker(&msg::TransferKeys::new(&self.node_name, &src, keys, values));
+            Message::TransferRequest(msg::TransferRequest {
+                source: src,
+                min,
+                max,
+                ..
+            }) => {
+                let (keys, values) = self.vnodes[v].node.transfer_kvs_range(min, max);
+                self.send_message(msg::TransferKeys::new(&name, &src, keys.clone(), values));
+                self.vnodes[v]
+                    .node
+                    .remove_transferred(&keys)
+                    .map_err(MessageError::Storage)?;
+                // The replicas drop the keys too, since the requester now owns them
+                self.replicate_keys(v, keys, None);
             }
 
             // Store new data from a transfer and duplicate that data to successors
-            "transferKeys" => {
-                let keys = msg.keys.expect("transferKeys: need keys");
-                let values = msg.values.expect("transferKeys: need values");
-
-                for (k, v) in keys.iter().zip(values.iter()) {
-                    self.node.set(k.to_string(), v.to_string());
+            Message::TransferKeys(msg::TransferKeys { keys, values, .. }) => {
+                let mut stored = Vec::new();
+                let mut result = Ok(());
+                for (k, record) in keys.into_iter().zip(values) {
+                    self.clock.observe(&record.version);
+                    if let Err(e) = self.vnodes[v].node.merge(k.to_string(), record) {
+                        result = Err(MessageError::Storage(e));
+                        break;
+                    }
+                    stored.push(k);
                 }
 
-                self.duplicate_to_successors();
+                self.replicate_keys(v, stored, None);
+                result?;
             }
 
             // Store duplicated data in a local replica
-            "duplicate" => {
-                let id = msg.id.expect("duplicate: need id");
-                let keys = msg.keys.expect("duplicate: need keys");
-                let values = msg.values.expect("duplicate: need values");
-
+            Message::Duplicate(msg::Duplicate {
+                source: src,
+                node_id: id,
+                keys,
+                values,
+                seq,
+                ..
+            }) => {
                 let mut new_kvs = HashMap::new();
-                for (k, v) in keys.iter().zip(values.iter()) {
-                    new_kvs.insert(k.to_string(), v.to_string());
+                let mut tombstones = Vec::new();
+                for (k, record) in keys.into_iter().zip(values) {
+                    self.clock.observe(&record.version);
+                    if record.is_tombstone() {
+                        tombstones.push(k.to_string());
+                    }
+                    new_kvs.insert(k, record);
+                }
+                self.vnodes[v]
+                    .node
+                    .set_for_replica(id, new_kvs)
+                    .map_err(MessageError::Storage)?;
+                self.replica_seqs.insert(id, seq);
+
+                // Tell the owner which deletions this replica has seen
+                if !tombstones.is_empty() {
+                    self.send_message(msg::DuplicateAck::new(&name, &src, id, tombstones, None));
+                }
+            }
+
+            // Apply the changed keys to a local replica
+            // A sequence number other than the next one expected means an update was missed,
+            // so a full duplicate is requested
+            Message::Replicate(msg::Replicate {
+                source: src,
+                node_id: id,
+                seq,
+                keys,
+                records,
+                request,
+                ..
+            }) => {
+                let mut tombstones = Vec::new();
+                for (k, record) in keys.iter().zip(records.iter()) {
+                    if let Some(record) = record {
+                        self.clock.observe(&record.version);
+                        if record.is_tombstone() {
+                            tombstones.push(k.to_string());
+                        }
+                    }
+                }
+                self.vnodes[v]
+                    .node
+                    .update_replica(id, keys, records)
+                    .map_err(MessageError::Storage)?;
+
+                match self.replica_seqs.get(&id) {
+                    Some(last) if seq == last + 1 => {
+                        self.replica_seqs.insert(id, seq);
+                    }
+                    Some(last) if seq <= *last => (),
+                    _ => self.send_message(msg::ResyncRequest::new(&name, &src)),
+                }
+
+                // Tell the owner which deletions this replica has seen, and confirm the write it waits on
+                if !tombstones.is_empty() || request.is_some() {
+                    self.send_message(msg::DuplicateAck::new(&name, &src, id, tombstones, request));
+                }
+            }
+
+            // Compare hashes of a node's Merkle tree with the tree over the same range of the local replica
+            // Ask for the children of the nodes that differ, or for the keys of differing leaves
+            Message::MerkleHashes(msg::MerkleHashes {
+                source: src,
+                node_id: id,
+                min,
+                level,
+                indexes,
+                hashes,
+                ..
+            }) => {
+                let tree = self.vnodes[v].node.replica_merkle_tree(id, min);
+                if let Some(i) = indexes.iter().find(|i| tree.hash(level, **i).is_none()) {
+                    return Err(MessageError::OutOfRange {
+                        field: "indexes",
+                        value: *i as i64,
+                    });
+                }
+                let differences = tree.differences(level, &indexes, &hashes);
+                if differences.is_empty() {
+                    return Ok(());
+                }
+                if level == tree.depth() {
+                    self.send_message(msg::RepairRequest::new(&name, &src, min, differences));
+                } else {
+                    let children = differences
+                        .iter()
+                        .flat_map(|i| vec![2 * i, 2 * i + 1])
+                        .collect();
+                    self.send_message(msg::MerkleRequest::new(
+                        &name,
+                        &src,
+                        min,
+                        level + 1,
+                        children,
+                    ));
+                }
+            }
+
+            // A replica holder asking for hashes of the Merkle tree over this node's keys
+            Message::MerkleRequest(msg::MerkleRequest {
+                source: src,
+                min,
+                level,
+                indexes,
+                ..
+            }) => {
+                let node = &self.vnodes[v].node;
+                let tree = node.merkle_tree(min);
+                let (indexes, hashes) = indexes
+                    .into_iter()
+                    .filter_map(|i| tree.hash(level, i).map(|h| (i, h)))
+                    .unzip();
+                self.send_message(msg::MerkleHashes::new(
+                    &name,
+                    &src,
+                    node.get_id(),
+                    min,
+                    level,
+                    indexes,
+                    hashes,
+                ));
+            }
+
+            // A replica holder found its replica stale in some leaf ranges, send it those keys
+            Message::RepairRequest(msg::RepairRequest {
+                source: src,
+                min,
+                indexes,
+                ..
+            }) => {
+                let node = &self.vnodes[v].node;
+                let (keys, values) = node.leaf_entries(min, &indexes);
+                self.send_message(msg::Repair::new(
+                    &name,
+                    &src,
+                    node.get_id(),
+                    min,
+                    indexes,
+                    keys,
+                    values,
+                ));
+            }
+
+            // Replace the stale leaf ranges of a local replica
+            // Tell the owner which deletions this replica has seen, as a missed replication
+            // update would otherwise keep their tombstones forever
+            Message::Repair(msg::Repair {
+                source: src,
+                node_id: id,
+                min,
+                indexes,
+                keys,
+                values,
+                ..
+            }) => {
+                let mut tombstones = Vec::new();
+                for (k, record) in keys.iter().zip(values.iter()) {
+                    self.clock.observe(&record.version);
+                    if record.is_tombstone() {
+                        tombstones.push(k.to_string());
+                    }
+                }
+                self.vnodes[v]
+                    .node
+                    .repair_replica(id, min, &indexes, keys, values)
+                    .map_err(MessageError::Storage)?;
+                if !tombstones.is_empty() {
+                    self.send_message(msg::DuplicateAck::new(&name, &src, id, tombstones, None));
+                }
+            }
+
+            // A replica holder missed a replication update, send it the whole data
+            Message::ResyncRequest(msg::ResyncRequest { source: src, .. }) => {
+                self.duplicate_to(v, &src);
+            }
+
+            // A replica holder has stored these tombstones, so they may be dropped once every holder has
+            // If the replica was sent for a client write, confirm the write once enough holders have stored it
+            Message::DuplicateAck(msg::DuplicateAck {
+                source: src,
+                keys,
+                request,
+                ..
+            }) => {
+                let node = &mut self.vnodes[v].node;
+                node.ack_tombstones(&src, keys);
+                match node.collect_tombstones() {
+                    Ok(collected) if !collected.is_empty() => {
+                        self.replicate_keys(v, collected, None)
+                    }
+                    Ok(_) => (),
+                    Err(e) => self.record_error(&MessageError::Storage(e)),
+                }
+
+                if let Some(request) = request {
+                    if let Some(replication) = self.pending_replications.get_mut(&request) {
+                        replication.holders.insert(src);
+                        if replication.holders.len() >= replication.needed {
+                            let replication = self.pending_replications.remove(&request).unwrap();
+                            self.send_message(msg::StoreAck::new(
+                                &replication.owner,
+                                &request.coordinator,
+                                request.id,
+                                &replication.key,
+                                replication.version,
+                            ));
+                        }
+                    }
+                }
+            }
+
+            // The owner of a key could not store a Set or Delete, so the client is told it failed
+            Message::StoreFailed(msg::StoreFailed {
+                id,
+                key,
+                version,
+                error,
+                ..
+            }) => {
+                if let Some(write) = self.take_pending_write(id, &key, &version) {
+                    match write.value {
+                        Some(_) => self
+                            .send_message(msg::SetFailResponse::not_stored(id, &write.key, &error)),
+                        None => self.send_message(msg::DeleteFailResponse::not_stored(
+                            id, &write.key, &error,
+                        )),
+                    }
+                }
+            }
+
+            // The owner of a key confirmed a Set or Delete, so the client is answered
+            // or the hint kept for it is dropped
+            Message::StoreAck(msg::StoreAck {
+                id, key, version, ..
+            }) => {
+                if let Err(e) = self.hints.remove(&key, &version) {
+                    self.record_error(&MessageError::Storage(e));
+                }
+
+                if let Some(write) = self.take_pending_write(id, &key, &version) {
+                    match write.value {
+                        Some(value) => {
+                            self.send_message(msg::SetResponse::new(id, &write.key, &value))
+                        }
+                        None => self.send_message(msg::DeleteResponse::new(id, &write.key)),
+                    }
                 }
-                self.node.set_for_replica(id, new_kvs);
             }
 
             // Received ping from predecessor checking if current node is alive
-            "ping" => {
-                let src = msg.source.expect("ping: need source");
-                self.send_to_broker(&msg::Pong::new(&self.node_name, &src));
+            Message::Ping(msg::Ping { source: src, .. }) => {
+                self.send_message(msg::Pong::new(&name, &src));
+            }
+
+            // Received ping from successor or predecessor confirming it is alive
+            // Nodes that asked this node to ping the source are told it is alive
+            // Any node that answers is reachable, so its hints are handed off
+            Message::Pong(msg::Pong { source: src, .. }) => {
+                let now = self.now();
+                let vnode = &mut self.vnodes[v];
+                if src == vnode.node.get_successor().node_name {
+                    vnode.detector.pong_received(now);
+                    vnode.probe = None;
+                }
+                if vnode.watched_pred.as_deref() == Some(&src) {
+                    vnode.pred_detector.pong_received(now);
+                    vnode.pred_probe = None;
+                }
+                if let Some(probe) = self.indirect_probes.remove(&src) {
+                    for requester in probe.requesters {
+                        self.send_message(msg::PingAck::new(&name, &requester, &src));
+                    }
+                }
+                self.hand_off(&name, &src);
             }
 
-            // Received ping from successor confirming it is alive
-            "pong" => {
-                self.pings = 0;
+            // A node suspects one of its successors failed and asks this node to ping it
+            Message::PingReq(msg::PingReq {
+                source: src,
+                node_name: suspect,
+                ..
+            }) => {
+                let deadline = self.now() + Duration::from_millis(PROBE_TIMEOUT);
+                self.indirect_probes
+                    .entry(suspect.to_string())
+                    .or_insert_with(|| IndirectProbe {
+                        requesters: HashSet::new(),
+                        deadline,
+                    })
+                    .requesters
+                    .insert(src);
+                self.send_message(msg::Ping::new(&name, &suspect));
+            }
+
+            // Another node reached a suspected successor or predecessor, so it is not failed
+            Message::PingAck(msg::PingAck {
+                node_name: suspect, ..
+            }) => {
+                let now = self.now();
+                let vnode = &mut self.vnodes[v];
+                if vnode.probe.as_ref().is_some_and(|p| p.suspect == suspect) {
+                    vnode.detector.pong_received(now);
+                    vnode.probe = None;
+                }
+                if vnode.pred_probe.as_ref().is_some_and(|p| p.suspect == suspect) {
+                    vnode.pred_detector.pong_received(now);
+                    vnode.pred_probe = None;
+                }
             }
 
             // Received ping from self to keep locks flowing from periodic thread to broker loop
-            "pingSelf" => {
-                self.send_to_broker(&msg::PongSelf::new(&self.node_name));
+            Message::PingSelf(_) => {
+                self.send_message(msg::PongSelf::new(&self.node_name));
             }
 
             // Received pong from self
-            "pongSelf" => {}
+            Message::PongSelf(_) => {}
 
             // Request from a partitioned node to rejoin the ring
-            "rejoin" => {
-                let src = msg.destination.expect("rejoin: needs destination");
-                let dest = msg.source.expect("rejoin: needs source");
-
-                self.send_to_broker(&msg::RejoinAck::new(&src, &dest));
+            Message::Rejoin(msg::Rejoin {
+                source: dest,
+                destination: src,
+            }) => {
+                self.send_message(msg::RejoinAck::new(&src, &dest));
+                self.hand_off(&src, &dest);
             }
 
             // Acknowledgement from a node that the current node wants to rejoin, requests a successor from the partitioned node's ring
-            "rejoinAck" => {
-                let src = msg.source.expect("rejoin: needs source");
+            Message::RejoinAck(msg::RejoinAck { source: src, .. }) => {
+                self.hand_off(&name, &src);
+                let node = &mut self.vnodes[v].node;
+                node.reset_failed_successor();
+                let id = node.get_id();
+                node.push_query(id, None, QueryType::JoinAck);
+                self.send_message(msg::FindSucc::new(&name, &src, id, None))
+            }
 
-                self.node.reset_failed_successor();
-                self.node.push_query(self.node.get_id(), QueryType::JoinAck);
-                self.send_to_broker(&msg::FindSucc::new(
-                    &self.node_name,
-                    &src,
-                    self.node.get_id(),
-                    None,
-                ))
+            // Hands every key the process owns over to its successors, then leaves the ring
+            Message::Leave(msg::Leave { id }) => {
+                if self.leave.is_none() {
+                    self.start_leave(id);
+                }
+            }
+
+            // A leaving predecessor handed its keys over, so they are adopted and replicated
+            // and the leaving node's predecessor becomes this node's predecessor
+            Message::LeaveKeys(msg::LeaveKeys {
+                source: src,
+                keys,
+                values,
+                pred_id,
+                pred_name,
+                ..
+            }) => {
+                // The leaving node keeps sending its keys until they are all stored and acknowledged
+                for (k, record) in keys.iter().zip(values) {
+                    self.clock.observe(&record.version);
+                    self.vnodes[v]
+                        .node
+                        .merge(k.to_string(), record)
+                        .map_err(MessageError::Storage)?;
+                }
+                let pred = match (pred_id, pred_name) {
+                    (Some(pred_id), Some(pred_name)) => Some(NodeEntry::new(pred_id, &pred_name)),
+                    _ => None,
+                };
+                self.vnodes[v]
+                    .node
+                    .predecessor_left(node::physical_name(&src), pred);
+
+                self.replicate_keys(v, keys, None);
+                self.send_message(msg::LeaveAck::new(&name, &src));
+            }
+
+            // A leaving successor named the node to take as successor instead
+            // Replica holders that replace the leaving process's nodes get a full duplicate
+            Message::LeaveNotice(msg::LeaveNotice {
+                source: src,
+                node_id,
+                node_name,
+                ..
+            }) => {
+                let vnode = &mut self.vnodes[v];
+                let holders: Vec<String> = vnode
+                    .node
+                    .replica_holders()
+                    .into_iter()
+                    .map(|h| h.node_name)
+                    .collect();
+                vnode.node.successor_left(
+                    node::physical_name(&src),
+                    NodeEntry::new(node_id, &node_name),
+                );
+                vnode.detector.reset();
+                for holder in self.vnodes[v].node.replica_holders() {
+                    if !holders.contains(&holder.node_name) {
+                        self.duplicate_to(v, &holder.node_name);
+                    }
+                }
+            }
+
+            // A successor adopted the keys of one of the leaving process's virtual nodes
+            Message::LeaveAck(_) => {
+                if let Some(leave) = self.leave.as_mut() {
+                    if let Some(handoff) = leave.handoffs[v].as_mut() {
+                        handoff.acked = true;
+                    }
+                }
+                self.finish_leave();
             }
 
-            _ => println!("That message type cannot be handled."),
+            other => return Err(MessageError::Unhandled(msg::type_name(&other))),
         }
+        Ok(())
     }
 }
 
@@ -3,19 +3,183 @@
 // The purpose of this file is to hash a given key with SHA-1
 // and determine if a given key is between two other keys in the ring.
 
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
 use sha1::{Digest, Sha1};
 use std::cmp::Ordering;
+use std::fmt;
 
-/// Returns a hash for a given key
+/// The number of bytes in an identifier (the length of a SHA-1 digest)
+pub const ID_BYTES: usize = 20;
+/// The largest supported identifier space is the full SHA-1 digest (2^160)
+pub const MAX_BITS: u32 = (ID_BYTES * 8) as u32;
+
+/// A position on the ring, stored as a big-endian unsigned integer
+/// so that the derived ordering matches numeric ordering
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Id([u8; ID_BYTES]);
+
+impl Id {
+  /// Returns the id (self + 2^exp) mod 2^bits
+  ///
+  /// # Arguments
+  ///
+  /// * `exp` - The power of two to add
+  /// * `bits` - The width of the identifier space
+  pub fn add_pow2(&self, exp: u32, bits: u32) -> Id {
+    let mut bytes = self.0;
+    let mut carry = 1u16 << (exp % 8);
+    let mut i = ID_BYTES - 1 - (exp / 8) as usize;
+    loop {
+      let sum = bytes[i] as u16 + carry;
+      bytes[i] = sum as u8;
+      carry = sum >> 8;
+      if carry == 0 || i == 0 {
+        break;
+      }
+      i -= 1;
+    }
+    Id(bytes).truncate(bits)
+  }
+
+  /// Returns the id reduced modulo 2^bits
+  ///
+  /// # Arguments
+  ///
+  /// * `bits` - The width of the identifier space
+  pub fn truncate(&self, bits: u32) -> Id {
+    let mut bytes = self.0;
+    let keep = bits.min(MAX_BITS) as usize;
+    for (i, byte) in bytes.iter_mut().rev().enumerate() {
+      if (i + 1) * 8 <= keep {
+        continue;
+      } else if i * 8 < keep {
+        *byte &= (1u16 << (keep - i * 8)) as u8 - 1;
+      } else {
+        *byte = 0;
+      }
+    }
+    Id(bytes)
+  }
+
+  /// Returns the highest bits of the id as a number, which splits the ring
+  /// into 2^count equal ranges and tells which of them the id falls in
+  ///
+  /// # Arguments
+  ///
+  /// * `count` - The number of bits to take, at most 63
+  /// * `bits` - The width of the identifier space
+  pub fn high_bits(&self, count: u32, bits: u32) -> usize {
+    let mut n = 0;
+    for i in (bits - count..bits).rev() {
+      let byte = self.0[ID_BYTES - 1 - (i / 8) as usize];
+      n = (n << 1) | ((byte >> (i % 8)) & 1) as usize;
+    }
+    n
+  }
+
+  /// Returns the clockwise distance from this id to another, (other - self) mod 2^bits
+  ///
+  /// # Arguments
+  ///
+  /// * `other` - The id the distance is measured to
+  /// * `bits` - The width of the identifier space
+  pub fn distance(&self, other: Id, bits: u32) -> Id {
+    let mut bytes = [0; ID_BYTES];
+    let mut borrow = 0;
+    for i in (0..ID_BYTES).rev() {
+      let difference = other.0[i] as i16 - self.0[i] as i16 - borrow;
+      bytes[i] = difference.rem_euclid(256) as u8;
+      borrow = (difference < 0) as i16;
+    }
+    Id(bytes).truncate(bits)
+  }
+
+  /// Returns the number of bits needed to write the id as a number
+  pub fn bit_len(&self) -> u32 {
+    match self.0.iter().position(|byte| *byte != 0) {
+      Some(i) => ((ID_BYTES - i) * 8) as u32 - self.0[i].leading_zeros(),
+      None => 0,
+    }
+  }
+
+  /// Returns the id shifted right by a number of bits, keeping the lowest 64 bits left
+  ///
+  /// # Arguments
+  ///
+  /// * `shift` - The number of bits to drop
+  pub fn shifted(&self, shift: u32) -> u64 {
+    let mut n = 0;
+    for i in (shift..MAX_BITS.min(shift + 64)).rev() {
+      let byte = self.0[ID_BYTES - 1 - (i / 8) as usize];
+      n = (n << 1) | ((byte >> (i % 8)) & 1) as u64;
+    }
+    n
+  }
+}
+
+impl From<u64> for Id {
+  fn from(n: u64) -> Id {
+    let mut bytes = [0; ID_BYTES];
+    bytes[ID_BYTES - 8..].copy_from_slice(&n.to_be_bytes());
+    Id(bytes)
+  }
+}
+
+impl fmt::Display for Id {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for byte in self.0.iter() {
+      write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+  }
+}
+
+impl std::str::FromStr for Id {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Id, String> {
+    if s.is_empty() || s.len() > ID_BYTES * 2 || !s.is_ascii() {
+      return Err(format!("invalid id: {}", s));
+    }
+    // Left pad so that short ids parse as small numbers
+    let padded = format!("{:0>width$}", s, width = ID_BYTES * 2);
+    let mut bytes = [0; ID_BYTES];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&padded[i * 2..i * 2 + 2], 16)
+        .map_err(|_| format!("invalid id: {}", s))?;
+    }
+    Ok(Id(bytes))
+  }
+}
+
+/// Ids travel through the broker as hex strings since JSON numbers
+/// cannot represent the full 160-bit space
+impl Serialize for Id {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+impl<'de> Deserialize<'de> for Id {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
+  }
+}
+
+/// Returns a hash for a given key, reduced to the identifier space
 ///
 /// # Arguments
 ///
 /// * `key` - The key to be hashed
-pub fn hash(key: &str) -> i32 {
+/// * `bits` - The width of the identifier space
+pub fn hash(key: &str, bits: u32) -> Id {
   let mut hasher = Sha1::new();
   hasher.input(key.as_bytes());
-  let result = hasher.result();
-  *result.last().unwrap() as i32
+  let mut bytes = [0; ID_BYTES];
+  bytes.copy_from_slice(&hasher.result());
+  Id(bytes).truncate(bits)
 }
 
 /// Returns if a given id is in the range of a min and max key on the ring
@@ -27,7 +191,7 @@ pub fn hash(key: &str) -> i32 {
 /// * `min` - The lower bound key of the range
 /// * `max` - The upper bound key of the range
 /// * `incl` - Whether or not the upper bound is inclusive
-pub fn in_range(id: i32, min: i32, max: i32, incl: bool) -> bool {
+pub fn in_range(id: Id, min: Id, max: Id, incl: bool) -> bool {
   match min.cmp(&max) {
     Ordering::Less => {
       if incl {
@@ -46,3 +210,183 @@ pub fn in_range(id: i32, min: i32, max: i32, incl: bool) -> bool {
     Ordering::Equal => true,
   }
 }
+
+/// Returns which of 2^count equal parts of the range (min, max] an id falls in,
+/// counting clockwise from min, where equal bounds mean the whole ring
+///
+/// # Arguments
+///
+/// * `id` - The id, which must be in the range
+/// * `min` - The lower bound of the range
+/// * `max` - The inclusive upper bound of the range
+/// * `count` - The number of times the range is halved, at most 63 and at most bits
+/// * `bits` - The width of the identifier space
+pub fn bucket(id: Id, min: Id, max: Id, count: u32, bits: u32) -> usize {
+  // 0 for the id right after min
+  let offset = min.add_pow2(0, bits).distance(id, bits);
+  if min == max {
+    return offset.high_bits(count, bits);
+  }
+  // The top 64 bits of the range's length are enough to split it evenly
+  let length = min.distance(max, bits);
+  let shift = length.bit_len().saturating_sub(64);
+  let (offset, length) = (offset.shifted(shift) as u128, length.shifted(shift) as u128);
+  (((offset << count) / length) as usize).min((1 << count) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+
+  /// Returns whether an id lies in a range by walking clockwise from its lower bound
+  fn in_range_by_distance(id: Id, min: Id, max: Id, incl: bool) -> bool {
+    if min == max {
+      return true;
+    }
+    let (to_id, to_max) = (min.distance(id, MAX_BITS), min.distance(max, MAX_BITS));
+    to_id > Id([0; ID_BYTES]) && (to_id < to_max || (incl && to_id == to_max))
+  }
+
+  fn random_id(rng: &mut StdRng) -> Id {
+    let mut bytes = [0; ID_BYTES];
+    rng.fill(&mut bytes);
+    Id(bytes)
+  }
+
+  #[test]
+  fn in_range_on_a_small_ring() {
+    // Every id, pair of bounds and inclusiveness of the first 64 ids
+    for id in 0..64u64 {
+      for min in 0..64u64 {
+        for max in 0..64u64 {
+          for &incl in [false, true].iter() {
+            let (id, min, max) = (Id::from(id), Id::from(min), Id::from(max));
+            assert_eq!(
+              in_range(id, min, max, incl),
+              in_range_by_distance(id, min, max, incl),
+              "{} in ({}, {}) incl {}",
+              id,
+              min,
+              max,
+              incl
+            );
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn in_range_on_the_full_ring() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..10_000 {
+      let (id, min, max) = (
+        random_id(&mut rng),
+        random_id(&mut rng),
+        random_id(&mut rng),
+      );
+      // Ids at and next to the bounds are where off-by-ones hide
+      let ids = [
+        id,
+        min,
+        max,
+        min.add_pow2(0, MAX_BITS),
+        max.add_pow2(0, MAX_BITS),
+      ];
+      for &id in ids.iter() {
+        for &incl in [false, true].iter() {
+          assert_eq!(
+            in_range(id, min, max, incl),
+            in_range_by_distance(id, min, max, incl)
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn in_range_wraps_around_zero() {
+    // On a ring of 2^8 ids, 255 + 1 wraps to 0
+    let (min, max) = (Id::from(250), Id::from(5));
+    assert!(in_range(Id::from(255), min, max, false));
+    assert!(in_range(Id::from(0), min, max, false));
+    assert!(in_range(Id::from(255).add_pow2(0, 8), min, max, false));
+    assert!(!in_range(Id::from(5), min, max, false));
+    assert!(in_range(Id::from(5), min, max, true));
+    assert!(!in_range(Id::from(250), min, max, true));
+    assert!(!in_range(Id::from(100), min, max, true));
+  }
+
+  #[test]
+  fn in_range_with_equal_bounds_is_the_whole_ring() {
+    // A node alone on the ring is its own predecessor and successor, and owns everything
+    let mut rng = StdRng::seed_from_u64(2);
+    for _ in 0..1_000 {
+      let (id, bound) = (random_id(&mut rng), random_id(&mut rng));
+      assert!(in_range(id, bound, bound, false));
+      assert!(in_range(id, bound, bound, true));
+      assert!(in_range(bound, bound, bound, false));
+    }
+  }
+
+  #[test]
+  fn buckets_split_a_range_evenly() {
+    // (200, 40] on a ring of 2^8 ids wraps around zero and holds 96 ids, 12 per bucket
+    let (min, max) = (Id::from(200), Id::from(40));
+    let mut counts = [0; 8];
+    for id in 0..256u64 {
+      let id = Id::from(id);
+      if in_range(id, min, max, true) {
+        counts[bucket(id, min, max, 3, 8)] += 1;
+      }
+    }
+    assert_eq!(counts, [12; 8]);
+    assert_eq!(bucket(Id::from(201), min, max, 3, 8), 0);
+    assert_eq!(bucket(Id::from(0), min, max, 3, 8), 4);
+    assert_eq!(bucket(max, min, max, 3, 8), 7);
+
+    // Equal bounds split the whole ring, starting right after the bound
+    assert_eq!(bucket(Id::from(201), min, min, 3, 8), 0);
+    assert_eq!(bucket(min, min, min, 3, 8), 7);
+
+    // Ranges wider than 64 bits keep every bucket in use, here half of the full ring
+    let mut rng = StdRng::seed_from_u64(4);
+    let max = random_id(&mut rng);
+    let min = max.add_pow2(MAX_BITS - 1, MAX_BITS);
+    let mut seen = [false; 256];
+    for _ in 0..10_000 {
+      let id = random_id(&mut rng);
+      if in_range(id, min, max, true) {
+        seen[bucket(id, min, max, 8, MAX_BITS)] = true;
+      }
+    }
+    assert!(seen.iter().all(|seen| *seen));
+  }
+
+  #[test]
+  fn ranges_between_consecutive_ids_partition_the_ring() {
+    let mut rng = StdRng::seed_from_u64(3);
+    for _ in 0..200 {
+      let count = rng.gen_range(1, 20);
+      let mut bounds: Vec<Id> = (0..count).map(|_| random_id(&mut rng)).collect();
+      bounds.sort();
+      bounds.dedup();
+      for _ in 0..50 {
+        let id = match rng.gen_range(0, 3) {
+          0 => random_id(&mut rng),
+          1 => bounds[rng.gen_range(0, bounds.len())],
+          _ => bounds[rng.gen_range(0, bounds.len())].add_pow2(0, MAX_BITS),
+        };
+        let owners = (0..bounds.len())
+          .filter(|&i| {
+            let previous = bounds[(i + bounds.len() - 1) % bounds.len()];
+            in_range(id, previous, bounds[i], true)
+          })
+          .count();
+        assert_eq!(owners, 1, "{} in {:?}", id, bounds);
+      }
+    }
+  }
+}
@@ -0,0 +1,282 @@
+// File: hint.rs
+//
+// The purpose of this file is to keep the writes a node coordinated for an
+// owner that could not be reached, so that they can be handed off to the owner
+// once it is reachable again, or to whichever node owns their key by then.
+
+use crate::clock::{VectorClock, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+// Number of hints kept before new ones are refused
+pub const MAX_HINTS: usize = 1000;
+
+/// A Set or Delete that its owner did not confirm
+/// The value is None for a Delete
+/// Hints are told apart by key and version, since the version of a write this node
+/// coordinated is unique to it while the client's id may be reused
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hint {
+  pub owner: String,
+  pub id: i32,
+  pub key: String,
+  pub value: Option<String>,
+  pub version: Version,
+  pub context: Option<VectorClock>,
+}
+
+/// A bounded queue of hints, oldest first
+/// With a path, the queue is written to disk after every change so it survives crashes,
+/// and a change that cannot be written is undone
+pub struct HintQueue {
+  path: Option<PathBuf>,
+  hints: VecDeque<Hint>,
+}
+
+impl HintQueue {
+  /// Returns a new HintQueue object that is only kept in memory
+  pub fn in_memory() -> HintQueue {
+    HintQueue {
+      path: None,
+      hints: VecDeque::new(),
+    }
+  }
+
+  /// Opens a queue kept in a file, recovering the hints in it if it exists
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - File holding the queue
+  pub fn open(path: &Path) -> io::Result<HintQueue> {
+    let hints = if path.exists() {
+      serde_json::from_reader(BufReader::new(File::open(path)?))?
+    } else {
+      VecDeque::new()
+    };
+    Ok(HintQueue {
+      path: Some(path.to_path_buf()),
+      hints,
+    })
+  }
+
+  /// Adds a hint, or refuses it if the queue is full so that no kept hint is lost
+  ///
+  /// # Arguments
+  ///
+  /// * `hint` - The unconfirmed write
+  pub fn push(&mut self, hint: Hint) -> io::Result<()> {
+    if self.hints.len() >= MAX_HINTS {
+      return Err(io::Error::other(format!(
+        "hint queue is full, dropping write {}",
+        hint.id
+      )));
+    }
+    self.hints.push_back(hint);
+    if let Err(e) = self.save() {
+      self.hints.pop_back();
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// Returns the number of hints in the queue
+  #[cfg(test)]
+  pub fn len(&self) -> usize {
+    self.hints.len()
+  }
+
+  /// Returns the owners that have hints waiting for them
+  pub fn owners(&self) -> Vec<String> {
+    let mut owners: Vec<String> = Vec::new();
+    for hint in self.hints.iter() {
+      if !owners.contains(&hint.owner) {
+        owners.push(hint.owner.to_string());
+      }
+    }
+    owners
+  }
+
+  /// Returns every hint in the queue, oldest first
+  pub fn iter(&self) -> impl Iterator<Item = &Hint> {
+    self.hints.iter()
+  }
+
+  /// Returns the hint kept for a write, if any
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - Key of the write
+  /// * `version` - Version of the write
+  pub fn get(&self, key: &str, version: &Version) -> Option<Hint> {
+    self
+      .hints
+      .iter()
+      .find(|h| h.key == key && h.version == *version)
+      .cloned()
+  }
+
+  /// Returns the hints waiting for an owner, oldest first
+  ///
+  /// # Arguments
+  ///
+  /// * `owner` - Name of the owner
+  pub fn for_owner(&self, owner: &str) -> Vec<Hint> {
+    self
+      .hints
+      .iter()
+      .filter(|h| h.owner == owner)
+      .cloned()
+      .collect()
+  }
+
+  /// Drops the hint for a write once its owner has confirmed it
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - Key of the write
+  /// * `version` - Version of the write
+  pub fn remove(&mut self, key: &str, version: &Version) -> io::Result<()> {
+    let matches = |h: &Hint| h.key == key && h.version == *version;
+    if !self.hints.iter().any(matches) {
+      return Ok(());
+    }
+    let hints = self.hints.clone();
+    self.hints.retain(|h| !matches(h));
+    if let Err(e) = self.save() {
+      self.hints = hints;
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// Writes the queue to its file, replacing the old one atomically
+  fn save(&self) -> io::Result<()> {
+    if let Some(path) = &self.path {
+      let tmp_path = path.with_extension("json.tmp");
+      let mut tmp = File::create(&tmp_path)?;
+      serde_json::to_writer(&mut tmp, &self.hints)?;
+      tmp.sync_all()?;
+      fs::rename(&tmp_path, path)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+
+  fn version(wall: i32) -> Version {
+    Version {
+      wall: wall as u64,
+      logical: 0,
+      writer: String::from("node-0"),
+    }
+  }
+
+  fn hint(owner: &str, id: i32) -> Hint {
+    Hint {
+      owner: String::from(owner),
+      id,
+      key: format!("key-{}", id),
+      value: Some(id.to_string()),
+      version: version(id),
+      context: None,
+    }
+  }
+
+  /// Drops the hint made by `hint` for an id
+  fn confirm(queue: &mut HintQueue, id: i32) {
+    queue.remove(&format!("key-{}", id), &version(id)).unwrap();
+  }
+
+  fn ids(hints: &[Hint]) -> Vec<i32> {
+    hints.iter().map(|h| h.id).collect()
+  }
+
+  #[test]
+  fn full_queue_refuses_new_hints() {
+    let mut queue = HintQueue::in_memory();
+    for id in 0..MAX_HINTS as i32 {
+      queue.push(hint("node-1", id)).unwrap();
+    }
+    assert!(queue.push(hint("node-1", MAX_HINTS as i32)).is_err());
+    assert_eq!(queue.len(), MAX_HINTS);
+    let hints = queue.for_owner("node-1");
+    assert_eq!(hints[0].id, 0);
+    assert_eq!(hints[MAX_HINTS - 1].id, MAX_HINTS as i32 - 1);
+
+    // Room is made once an owner confirms a hint
+    confirm(&mut queue, 0);
+    queue.push(hint("node-1", MAX_HINTS as i32)).unwrap();
+    assert_eq!(queue.len(), MAX_HINTS);
+  }
+
+  #[test]
+  fn hints_are_kept_per_owner_until_confirmed() {
+    let mut queue = HintQueue::in_memory();
+    queue.push(hint("node-1", 1)).unwrap();
+    queue.push(hint("node-2", 2)).unwrap();
+    queue.push(hint("node-1", 3)).unwrap();
+    assert_eq!(queue.owners(), vec!["node-1", "node-2"]);
+    assert_eq!(ids(&queue.for_owner("node-1")), vec![1, 3]);
+
+    confirm(&mut queue, 1);
+    confirm(&mut queue, 4);
+    assert_eq!(ids(&queue.for_owner("node-1")), vec![3]);
+    confirm(&mut queue, 2);
+    assert_eq!(queue.owners(), vec!["node-1"]);
+  }
+
+  #[test]
+  fn hints_with_a_reused_client_id_are_kept_apart() {
+    let mut queue = HintQueue::in_memory();
+    let first = hint("node-1", 1);
+    let second = Hint {
+      key: String::from("key-2"),
+      version: version(2),
+      ..hint("node-1", 1)
+    };
+    queue.push(first).unwrap();
+    queue.push(second).unwrap();
+
+    assert_eq!(queue.get("key-2", &version(2)).unwrap().key, "key-2");
+    assert!(queue.get("key-2", &version(1)).is_none());
+    confirm(&mut queue, 1);
+    assert_eq!(queue.len(), 1);
+    assert!(queue.get("key-2", &version(2)).is_some());
+  }
+
+  #[test]
+  fn hints_survive_a_reopen() {
+    let dir = env::temp_dir().join(format!("halo-hints-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hints.json");
+    {
+      let mut queue = HintQueue::open(&path).unwrap();
+      queue.push(hint("node-1", 1)).unwrap();
+      queue.push(hint("node-2", 2)).unwrap();
+      queue.push(hint("node-1", 3)).unwrap();
+      confirm(&mut queue, 1);
+    }
+
+    let queue = HintQueue::open(&path).unwrap();
+    assert_eq!(ids(&queue.for_owner("node-1")), vec![3]);
+    assert_eq!(ids(&queue.for_owner("node-2")), vec![2]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn failed_save_leaves_the_queue_unchanged() {
+    let dir = env::temp_dir().join(format!("halo-hints-missing-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut queue = HintQueue::open(&dir.join("hints.json")).unwrap();
+    assert!(queue.push(hint("node-1", 1)).is_err());
+    assert_eq!(queue.len(), 0);
+  }
+}
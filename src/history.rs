@@ -0,0 +1,280 @@
+// File: history.rs
+//
+// The purpose of this file is to check that the sets and gets clients made
+// against a cluster are linearizable: that every operation appears to take
+// effect at one instant between its request and its reply, as on a single
+// register per key.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// A client operation on a key: a Set of a value, or a Get and the value it found,
+/// None when the key had no value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+  Set(String),
+  Get(Option<String>),
+}
+
+/// A client operation in a history: the request id, the key, the operation, when it was
+/// requested, and when it was answered
+/// A Set without a reply, or with an error, may or may not have taken effect,
+/// so it has no reply time and may take effect at any point after its request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Operation {
+  pub id: i32,
+  pub key: String,
+  pub op: Op,
+  pub call: u64,
+  pub ret: Option<u64>,
+}
+
+impl fmt::Display for Operation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let ret = match self.ret {
+      Some(ret) => ret.to_string(),
+      None => String::from("?"),
+    };
+    match &self.op {
+      Op::Set(value) => write!(f, "[{}, {}] set {} = {:?}", self.call, ret, self.key, value),
+      Op::Get(value) => write!(
+        f,
+        "[{}, {}] get {} -> {:?}",
+        self.call, ret, self.key, value
+      ),
+    }
+  }
+}
+
+/// A history that is not linearizable: the key it fails on, and a subsequence of its
+/// operations on that key that is still not linearizable, and would be without any one
+/// of them other than the Sets its Gets need
+/// The subsequence keeps a Set of every value its Gets found that some Set wrote,
+/// so that a stale read shows the write it missed rather than a value out of nowhere
+#[derive(Debug)]
+pub struct Violation {
+  pub key: String,
+  pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "operations on {} are not linearizable:", self.key)?;
+    for operation in self.operations.iter() {
+      write!(f, "\n  {}", operation)?;
+    }
+    Ok(())
+  }
+}
+
+/// Checks that a history is linearizable against a register per key
+/// Keys are independent registers, so each key's operations are checked on their own
+/// Returns the first key whose operations are not linearizable, with a minimal
+/// subsequence of them that shows it
+///
+/// # Arguments
+///
+/// * `history` - Every client operation
+pub fn check(history: &[Operation]) -> Result<(), Violation> {
+  let mut by_key: BTreeMap<&str, Vec<&Operation>> = BTreeMap::new();
+  for operation in history.iter() {
+    by_key.entry(&operation.key).or_default().push(operation);
+  }
+  for (key, mut operations) in by_key {
+    if linearizable(&operations) {
+      continue;
+    }
+    let written: HashSet<&str> = operations
+      .iter()
+      .filter_map(|o| match &o.op {
+        Op::Set(value) => Some(value.as_str()),
+        Op::Get(_) => None,
+      })
+      .collect();
+    // Every operation the violation does not need is dropped, one at a time
+    let mut i = 0;
+    while i < operations.len() {
+      let removed = operations.remove(i);
+      if linearizable(&operations) || !explained(&operations, &written) {
+        operations.insert(i, removed);
+        i += 1;
+      }
+    }
+    return Err(Violation {
+      key: key.to_string(),
+      operations: operations.into_iter().cloned().collect(),
+    });
+  }
+  Ok(())
+}
+
+/// Returns whether every value found by a Get among some operations, and written by
+/// some Set of the history, is written by a Set among the operations
+///
+/// # Arguments
+///
+/// * `operations` - The operations
+/// * `written` - The values written by the Sets of the history
+fn explained(operations: &[&Operation], written: &HashSet<&str>) -> bool {
+  operations.iter().all(|o| match &o.op {
+    Op::Get(Some(value)) if written.contains(value.as_str()) => operations
+      .iter()
+      .any(|w| w.op == Op::Set(value.to_string())),
+    _ => true,
+  })
+}
+
+/// Returns whether operations on one register are linearizable
+/// Searches for an order in which every operation takes effect, starting with the
+/// register empty, as in Wing and Gong's algorithm with Lowe's memoization: the
+/// operations that can take effect next are those requested before any operation
+/// still to take effect was answered, and a set of operations that have taken effect
+/// along with the register's value is only explored once
+///
+/// # Arguments
+///
+/// * `operations` - The operations
+fn linearizable(operations: &[&Operation]) -> bool {
+  let mut done = vec![false; operations.len()];
+  search(operations, &mut done, &None, &mut HashSet::new())
+}
+
+/// Searches for an order in which the operations left take effect
+///
+/// # Arguments
+///
+/// * `operations` - The operations
+/// * `done` - Which operations have taken effect
+/// * `value` - The register's value after them
+/// * `explored` - The operations that have taken effect and register values already explored
+fn search(
+  operations: &[&Operation],
+  done: &mut Vec<bool>,
+  value: &Option<String>,
+  explored: &mut HashSet<(Vec<bool>, Option<String>)>,
+) -> bool {
+  // Operations that were never answered need not take effect at all
+  if operations
+    .iter()
+    .zip(done.iter())
+    .all(|(o, done)| *done || o.ret.is_none())
+  {
+    return true;
+  }
+  if !explored.insert((done.clone(), value.clone())) {
+    return false;
+  }
+  let horizon = operations
+    .iter()
+    .zip(done.iter())
+    .filter(|(_, done)| !**done)
+    .filter_map(|(o, _)| o.ret)
+    .min()
+    .unwrap_or(u64::MAX);
+  for i in 0..operations.len() {
+    if done[i] || operations[i].call > horizon {
+      continue;
+    }
+    let next = match &operations[i].op {
+      Op::Set(set) => Some(set.to_string()),
+      Op::Get(got) if got == value => value.clone(),
+      Op::Get(_) => continue,
+    };
+    done[i] = true;
+    if search(operations, done, &next, explored) {
+      return true;
+    }
+    done[i] = false;
+  }
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set(id: i32, value: &str, call: u64, ret: Option<u64>) -> Operation {
+    Operation {
+      id,
+      key: String::from("X"),
+      op: Op::Set(String::from(value)),
+      call,
+      ret,
+    }
+  }
+
+  fn get(id: i32, value: Option<&str>, call: u64, ret: u64) -> Operation {
+    Operation {
+      id,
+      key: String::from("X"),
+      op: Op::Get(value.map(String::from)),
+      call,
+      ret: Some(ret),
+    }
+  }
+
+  #[test]
+  fn sequential_history_is_linearizable() {
+    let history = vec![
+      get(1, None, 0, 1),
+      set(2, "1", 2, Some(3)),
+      get(3, Some("1"), 4, 5),
+      set(4, "2", 6, Some(7)),
+      get(5, Some("2"), 8, 9),
+    ];
+    assert!(check(&history).is_ok());
+  }
+
+  #[test]
+  fn concurrent_operations_take_effect_in_either_order() {
+    let history = vec![
+      set(1, "1", 0, Some(10)),
+      set(2, "2", 1, Some(9)),
+      get(3, Some("1"), 11, 12),
+      get(4, Some("2"), 2, 5),
+    ];
+    assert!(check(&history).is_ok());
+  }
+
+  #[test]
+  fn unanswered_set_may_take_effect_late_or_never() {
+    let late = vec![
+      set(1, "1", 0, None),
+      get(2, None, 1, 2),
+      get(3, Some("1"), 30, 31),
+    ];
+    assert!(check(&late).is_ok());
+    let never = vec![set(1, "1", 0, None), get(2, None, 30, 31)];
+    assert!(check(&never).is_ok());
+  }
+
+  #[test]
+  fn stale_read_is_reported_with_minimal_operations() {
+    let history = vec![
+      set(1, "1", 0, Some(1)),
+      get(2, Some("1"), 2, 3),
+      set(3, "2", 4, Some(5)),
+      get(4, Some("2"), 6, 7),
+      get(5, Some("1"), 8, 9),
+      Operation {
+        id: 6,
+        key: String::from("Y"),
+        op: Op::Set(String::from("3")),
+        call: 0,
+        ret: Some(1),
+      },
+    ];
+    let violation = check(&history).unwrap_err();
+    assert_eq!(violation.key, "X");
+    let ids: Vec<i32> = violation.operations.iter().map(|o| o.id).collect();
+    assert_eq!(ids, vec![1, 3, 5]);
+  }
+
+  #[test]
+  fn value_never_set_is_a_violation() {
+    let history = vec![set(1, "1", 0, Some(1)), get(2, Some("2"), 2, 3)];
+    let violation = check(&history).unwrap_err();
+    let ids: Vec<i32> = violation.operations.iter().map(|o| o.id).collect();
+    assert_eq!(ids, vec![2]);
+  }
+}
@@ -5,11 +5,35 @@
 extern crate zmq;
 #[macro_use]
 extern crate chan;
+use clock::{SystemClock, Versioning};
+use detector::{DetectorKind, DetectorSettings};
+use hint::HintQueue;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::PathBuf;
+use std::sync::Arc;
+use storage::{DiskStorage, MemoryStorage, Storage, StorageKind};
 use structopt::StructOpt;
+use transport::{BrokerTransport, PeerTransport, Transport, TransportKind};
+#[cfg(test)]
+mod chi;
+mod clock;
+mod detector;
+mod error;
 mod handler;
 mod hash;
+mod hint;
+#[cfg(test)]
+mod history;
+mod merkle;
 mod msg;
 mod node;
+#[cfg(test)]
+mod script;
+#[cfg(test)]
+mod sim;
+mod storage;
+mod transport;
 
 /// Holds data parsed from the command line to initialize node
 #[derive(StructOpt, Debug)]
@@ -21,29 +45,199 @@ pub struct CLI {
   #[structopt(long)]
   node_name: String,
 
+  /// How messages reach other nodes: broker sends them through chistributed,
+  /// peer sends them straight to the endpoints given with --peer-endpoint
+  #[structopt(long, default_value = "broker")]
+  transport: TransportKind,
+
   #[structopt(long)]
-  pub_endpoint: String,
+  pub_endpoint: Option<String>,
 
   #[structopt(long)]
-  router_endpoint: String,
+  router_endpoint: Option<String>,
 
+  /// Endpoint this node receives messages on with the peer transport
+  #[structopt(long)]
+  endpoint: Option<String>,
+
+  /// Another node's name and endpoint for the peer transport, as name=endpoint
+  #[structopt(long, parse(try_from_str = transport::parse_endpoint))]
+  peer_endpoint: Vec<(String, String)>,
+
+  /// Endpoint replies to clients are published on with the peer transport
+  #[structopt(long)]
+  reply_endpoint: Option<String>,
+
+  /// Other nodes to join, by default every node given with --peer-endpoint
   #[structopt(long)]
   peer: Option<Vec<String>>,
+
+  /// Width of the identifier space in bits (1 to 160)
+  #[structopt(long, default_value = "160")]
+  ring_bits: u32,
+
+  /// Number of positions on the ring owned by this node
+  #[structopt(long, default_value = "1")]
+  vnodes: usize,
+
+  /// Storage backend for the node's data: memory or disk
+  #[structopt(long, default_value = "memory")]
+  storage: StorageKind,
+
+  /// Directory where the disk backend keeps each node's log and snapshot
+  #[structopt(long, default_value = "halo-data", parse(from_os_str))]
+  data_dir: PathBuf,
+
+  /// How conflicting writes to a key are resolved: lww keeps the newest write,
+  /// vector keeps concurrent writes as siblings until a client resolves them
+  #[structopt(long, default_value = "lww")]
+  versioning: Versioning,
+
+  /// Number of copies of each key, counting the owner's
+  #[structopt(long, default_value = "3")]
+  replication_factor: usize,
+
+  /// Number of copies that must store a set or delete before the client is answered,
+  /// unless the request has its own w
+  #[structopt(long, default_value = "1")]
+  write_quorum: usize,
+
+  /// Number of copies a get consults, unless the request has its own r
+  #[structopt(long, default_value = "1")]
+  read_quorum: usize,
+
+  /// How a failed successor is detected: phi suspects it once a pong is late given
+  /// how pongs arrived so far, counter once a number of pings in a row went unanswered
+  #[structopt(long, default_value = "phi")]
+  failure_detector: DetectorKind,
+
+  /// Number of unanswered pings in a row before the counter detector suspects a successor
+  #[structopt(long, default_value = "2")]
+  missed_pings: u32,
+
+  /// Suspicion level above which the phi detector suspects a successor
+  #[structopt(long, default_value = "8.0")]
+  phi_threshold: f64,
 }
 
 /// Main function
 fn main() {
   // Command line arguments are parsed and the message handler is constructed.
   let cli_options = CLI::from_args();
+  if cli_options.ring_bits == 0 || cli_options.ring_bits > hash::MAX_BITS {
+    eprintln!("--ring-bits must be between 1 and {}", hash::MAX_BITS);
+    std::process::exit(1);
+  }
+  // Virtual node names are a node's name, the separator and an index, so no name may hold it
+  let mut names = std::iter::once(&cli_options.node_name)
+    .chain(cli_options.peer_endpoint.iter().map(|(name, _)| name))
+    .chain(cli_options.peer.iter().flatten());
+  if names.any(|name| name.contains(node::VNODE_SEPARATOR)) {
+    eprintln!(
+      "--node-name and the names given with --peer-endpoint and --peer cannot contain '{}'",
+      node::VNODE_SEPARATOR
+    );
+    std::process::exit(1);
+  }
+  if cli_options.vnodes == 0 {
+    eprintln!("--vnodes must be at least 1");
+    std::process::exit(1);
+  }
+  if cli_options.replication_factor == 0 {
+    eprintln!("--replication-factor must be at least 1");
+    std::process::exit(1);
+  }
+  for quorum in [cli_options.write_quorum, cli_options.read_quorum] {
+    if quorum == 0 || quorum > cli_options.replication_factor {
+      eprintln!("--write-quorum and --read-quorum must be between 1 and --replication-factor");
+      std::process::exit(1);
+    }
+  }
+  if cli_options.missed_pings == 0 {
+    eprintln!("--missed-pings must be at least 1");
+    std::process::exit(1);
+  }
+  if cli_options.phi_threshold <= 0.0 {
+    eprintln!("--phi-threshold must be positive");
+    std::process::exit(1);
+  }
+  let detector = DetectorSettings {
+    kind: cli_options.failure_detector,
+    missed_pings: cli_options.missed_pings,
+    phi_threshold: cli_options.phi_threshold,
+  };
+  // The disk backend also keeps the node's hinted writes
+  let node_dir = cli_options.data_dir.join(&cli_options.node_name);
+  let (storage, hints): (Box<dyn Storage>, HintQueue) = match cli_options.storage {
+    StorageKind::Memory => (Box::new(MemoryStorage::default()), HintQueue::in_memory()),
+    StorageKind::Disk => (
+      Box::new(DiskStorage::open(&node_dir).expect("cannot open disk storage")),
+      HintQueue::open(&node_dir.join("hints.json")).expect("cannot open hint queue"),
+    ),
+  };
   let ctx = zmq::Context::new();
+  let (transport, peer): (Box<dyn Transport>, Option<Vec<String>>) = match cli_options.transport {
+    TransportKind::Broker => match (&cli_options.pub_endpoint, &cli_options.router_endpoint) {
+      (Some(pub_endpoint), Some(router_endpoint)) => (
+        Box::new(BrokerTransport::new(
+          &ctx,
+          &cli_options.node_name,
+          pub_endpoint,
+          router_endpoint,
+        )),
+        cli_options.peer,
+      ),
+      _ => {
+        eprintln!("--transport broker needs --pub-endpoint and --router-endpoint");
+        std::process::exit(1);
+      }
+    },
+    TransportKind::Peer => match &cli_options.endpoint {
+      Some(endpoint) => {
+        let names: Vec<String> = cli_options
+          .peer_endpoint
+          .iter()
+          .map(|(name, _)| name.to_string())
+          .collect();
+        let peer = cli_options
+          .peer
+          .or_else(|| Some(names).filter(|names| !names.is_empty()));
+        (
+          Box::new(PeerTransport::new(
+            &ctx,
+            &cli_options.node_name,
+            endpoint,
+            &cli_options.peer_endpoint,
+            cli_options.reply_endpoint.as_deref(),
+          )),
+          peer,
+        )
+      }
+      None => {
+        eprintln!("--transport peer needs --endpoint");
+        std::process::exit(1);
+      }
+    },
+  };
   let mut handler = handler::Handler::new(
-    ctx,
+    transport,
     &cli_options.node_name,
-    &cli_options.pub_endpoint,
-    &cli_options.router_endpoint,
-    cli_options.peer,
+    peer,
+    cli_options.ring_bits,
+    cli_options.vnodes,
+    storage,
+    cli_options.versioning,
+    cli_options.replication_factor,
+    cli_options.write_quorum,
+    cli_options.read_quorum,
+    hints,
+    detector,
+    Arc::new(SystemClock),
+    StdRng::from_entropy(),
+    cli_options.debug,
   );
 
-  // The handler begins listening for messages from the broker.
+  // The handler begins listening for messages from the network.
   handler.listen_to_publisher();
+  println!("{} left the ring", cli_options.node_name);
 }
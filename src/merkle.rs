@@ -0,0 +1,243 @@
+// File: merkle.rs
+//
+// The purpose of this file is to summarize a node's keys with a Merkle tree
+// so that a node and its replica holders can find which parts of the node's
+// range of keys they disagree on without exchanging every key.
+
+use crate::hash::{self, Id};
+use crate::storage::Record;
+
+// Depth of the tree, which splits the range of keys into 2^MERKLE_DEPTH leaf ranges
+pub const MERKLE_DEPTH: u32 = 8;
+
+/// A binary hash tree over equal parts of a range of the ring, (min, max]
+/// Level 0 holds the root and the last level holds one hash per leaf range,
+/// each parent hashing the concatenation of its two children
+pub struct MerkleTree {
+  bits: u32,
+  levels: Vec<Vec<Id>>,
+}
+
+impl MerkleTree {
+  /// Returns a new MerkleTree object built from the keys in a range and their records
+  /// Keys outside the range are left out
+  ///
+  /// # Arguments
+  ///
+  /// * `entries` - The keys and records to summarize
+  /// * `min` - The id right before the range
+  /// * `max` - The last id in the range
+  /// * `bits` - The width of the identifier space
+  pub fn new(entries: Vec<(String, Record)>, min: Id, max: Id, bits: u32) -> MerkleTree {
+    let depth = MerkleTree::depth_for(bits);
+
+    // Every key is summarized in the leaf of the range its hash falls in,
+    // in key order so that the same keys always give the same hash
+    let mut leaves: Vec<Vec<(String, Record)>> = vec![Vec::new(); 1 << depth];
+    for (k, record) in entries {
+      if hash::in_range(hash::hash(&k, bits), min, max, true) {
+        leaves[MerkleTree::leaf(&k, min, max, bits)].push((k, record));
+      }
+    }
+    let mut level: Vec<Id> = leaves
+      .into_iter()
+      .map(|mut leaf| {
+        leaf.sort_by(|a, b| a.0.cmp(&b.0));
+        let summary: String = leaf
+          .iter()
+          .map(|(k, record)| {
+            let record = serde_json::to_string(record).expect("cannot convert record to string");
+            format!("{}={};", k, record)
+          })
+          .collect();
+        hash::hash(&summary, hash::MAX_BITS)
+      })
+      .collect();
+
+    let mut levels = vec![level.clone()];
+    while level.len() > 1 {
+      level = level
+        .chunks(2)
+        .map(|pair| hash::hash(&format!("{}{}", pair[0], pair[1]), hash::MAX_BITS))
+        .collect();
+      levels.push(level.clone());
+    }
+    levels.reverse();
+
+    MerkleTree { bits, levels }
+  }
+
+  /// Returns the depth of the tree for an identifier space,
+  /// which never has more leaves than ids
+  ///
+  /// # Arguments
+  ///
+  /// * `bits` - The width of the identifier space
+  pub fn depth_for(bits: u32) -> u32 {
+    MERKLE_DEPTH.min(bits)
+  }
+
+  /// Returns the index of the leaf range a key in the range (min, max] falls in
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - The key
+  /// * `min` - The id right before the range
+  /// * `max` - The last id in the range
+  /// * `bits` - The width of the identifier space
+  pub fn leaf(key: &str, min: Id, max: Id, bits: u32) -> usize {
+    let id = hash::hash(key, bits);
+    hash::bucket(id, min, max, MerkleTree::depth_for(bits), bits)
+  }
+
+  /// Returns the depth of the tree, the level of its leaves
+  pub fn depth(&self) -> u32 {
+    MerkleTree::depth_for(self.bits)
+  }
+
+  /// Returns the hash of a node in the tree, if it exists
+  ///
+  /// # Arguments
+  ///
+  /// * `level` - The level of the node, 0 being the root
+  /// * `index` - The position of the node in its level
+  pub fn hash(&self, level: u32, index: usize) -> Option<Id> {
+    self
+      .levels
+      .get(level as usize)
+      .and_then(|l| l.get(index))
+      .cloned()
+  }
+
+  /// Returns the nodes at a level whose hashes differ from the given ones
+  ///
+  /// # Arguments
+  ///
+  /// * `level` - The level of the nodes
+  /// * `indexes` - The positions of the nodes in their level
+  /// * `hashes` - The other tree's hashes of the nodes
+  pub fn differences(&self, level: u32, indexes: &[usize], hashes: &[Id]) -> Vec<usize> {
+    indexes
+      .iter()
+      .zip(hashes.iter())
+      .filter(|(i, h)| self.hash(level, **i) != Some(**h))
+      .map(|(i, _)| *i)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::clock::Version;
+
+  fn entries(count: usize) -> Vec<(String, Record)> {
+    (0..count)
+      .map(|i| {
+        let version = Version {
+          wall: i as u64,
+          logical: 0,
+          writer: String::from("node-0"),
+        };
+        (format!("key-{}", i), Record::new(i.to_string(), version))
+      })
+      .collect()
+  }
+
+  /// Returns a tree over the whole ring, which is the range of a node alone on it
+  fn whole_ring(entries: Vec<(String, Record)>, bits: u32) -> MerkleTree {
+    MerkleTree::new(entries, Id::from(0), Id::from(0), bits)
+  }
+
+  /// Returns the leaf a key falls in on the whole ring
+  fn ring_leaf(key: &str, bits: u32) -> usize {
+    MerkleTree::leaf(key, Id::from(0), Id::from(0), bits)
+  }
+
+  /// Returns the leaves found to differ by descending from the root of one tree
+  /// into the children of every node whose hash differs from the other tree's
+  fn differing_leaves(tree: &MerkleTree, other: &MerkleTree) -> Vec<usize> {
+    let mut indexes = vec![0];
+    for level in 0..=tree.depth() {
+      let hashes: Vec<Id> = indexes
+        .iter()
+        .map(|i| other.hash(level, *i).unwrap())
+        .collect();
+      let differing = tree.differences(level, &indexes, &hashes);
+      if level == tree.depth() {
+        return differing;
+      }
+      indexes = differing
+        .iter()
+        .flat_map(|i| vec![2 * i, 2 * i + 1])
+        .collect();
+    }
+    unreachable!()
+  }
+
+  #[test]
+  fn equal_stores_have_equal_roots() {
+    let mut reversed = entries(100);
+    reversed.reverse();
+    let tree = whole_ring(entries(100), 160);
+    let other = whole_ring(reversed, 160);
+    assert_eq!(tree.hash(0, 0), other.hash(0, 0));
+    assert!(differing_leaves(&tree, &other).is_empty());
+    assert_ne!(
+      tree.hash(0, 0),
+      whole_ring(entries(99), 160).hash(0, 0)
+    );
+  }
+
+  #[test]
+  fn one_differing_key_gives_one_differing_leaf() {
+    let tree = whole_ring(entries(100), 160);
+
+    let mut changed = entries(100);
+    changed[42].1.value = Some(String::from("changed"));
+    let other = whole_ring(changed, 160);
+    assert_ne!(tree.hash(0, 0), other.hash(0, 0));
+    assert_eq!(
+      differing_leaves(&tree, &other),
+      vec![ring_leaf("key-42", 160)]
+    );
+
+    let missing = whole_ring(entries(100).into_iter().skip(1).collect(), 160);
+    assert_eq!(
+      differing_leaves(&tree, &missing),
+      vec![ring_leaf("key-0", 160)]
+    );
+  }
+
+  #[test]
+  fn tree_has_no_more_leaves_than_ids() {
+    let tree = whole_ring(entries(10), 4);
+    assert_eq!(tree.depth(), 4);
+    assert!(tree.hash(4, 15).is_some());
+    assert!(tree.hash(4, 16).is_none());
+    assert!((0..10).all(|i| ring_leaf(&format!("key-{}", i), 4) < 16));
+  }
+
+  #[test]
+  fn leaves_split_the_compared_range_rather_than_the_ring() {
+    // A sixteenth of the ring, as a node on a ring of 16 nodes would own
+    let (min, max) = (Id::from(0), Id::from(0).add_pow2(156, 160));
+    let owned: Vec<(String, Record)> = entries(4000)
+      .into_iter()
+      .filter(|(k, _)| hash::in_range(hash::hash(k, 160), min, max, true))
+      .collect();
+    let mut leaves: Vec<usize> = owned
+      .iter()
+      .map(|(k, _)| MerkleTree::leaf(k, min, max, 160))
+      .collect();
+    leaves.sort();
+    leaves.dedup();
+    // Splitting the whole ring would leave the keys in 16 of the 256 leaves
+    assert!(leaves.len() > 128, "{} leaves used", leaves.len());
+
+    // Keys outside the range do not change the tree
+    let tree = MerkleTree::new(owned, min, max, 160);
+    let all = MerkleTree::new(entries(4000), min, max, 160);
+    assert_eq!(tree.hash(0, 0), all.hash(0, 0));
+  }
+}
@@ -3,34 +3,345 @@
 // The purpose of this file is to define message types
 // to send/receive from the broker.
 
+use crate::clock::{VectorClock, Version};
+use crate::hash::Id;
+use crate::node;
+use crate::storage::Record;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
-/// For parsing data from a received message of any type
+/// Every message exchanged with the broker, tagged by its type
+/// Requests from the broker, replies to it and the Halo protocol all share this
+/// definition, so a message that does not match its type's fields is rejected when parsed
 #[derive(Serialize, Deserialize, Debug)]
-pub struct RcvdMessage {
-    pub r#type: String,
-    pub key: Option<String>,
-    pub value: Option<String>,
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Message {
+    Hello(Hello),
+    Set(Set),
+    Get(Get),
+    Delete(Delete),
+    Leave(Leave),
+    HelloResponse(HelloResponse),
+    SetResponse(SetReply),
+    GetResponse(GetReply),
+    DeleteResponse(DeleteReply),
+    LeaveResponse(LeaveReply),
+    Join(Join),
+    JoinAck(JoinAck),
+    JoinError(JoinError),
+    FindSucc(FindSucc),
+    FindSuccResponse(FindSuccResponse),
+    GetPred(GetPred),
+    GetPredResponse(GetPredResponse),
+    Notify(Notify),
+    Retrieve(Retrieve),
+    RetrieveResponse(RetrieveResponse),
+    ReadReplica(ReadReplica),
+    ReadReplicaResponse(ReadReplicaResponse),
+    ReadRepair(ReadRepair),
+    Store(Store),
+    Remove(Remove),
+    StoreAck(StoreAck),
+    StoreFailed(StoreFailed),
+    TransferRequest(TransferRequest),
+    TransferKeys(TransferKeys),
+    Duplicate(Duplicate),
+    Replicate(Replicate),
+    ResyncRequest(ResyncRequest),
+    MerkleHashes(MerkleHashes),
+    MerkleRequest(MerkleRequest),
+    RepairRequest(RepairRequest),
+    Repair(Repair),
+    DuplicateAck(DuplicateAck),
+    Ping(Ping),
+    Pong(Pong),
+    PingReq(PingReq),
+    PingAck(PingAck),
+    PingSelf(PingSelf),
+    PongSelf(PongSelf),
+    Rejoin(Rejoin),
+    RejoinAck(RejoinAck),
+    LeaveKeys(LeaveKeys),
+    LeaveNotice(LeaveNotice),
+    LeaveAck(LeaveAck),
+}
+
+/// The replies to a Set, which share the setResponse type
+/// A hinted reply is tried before a stored one, since it has every field of a stored one
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum SetReply {
+    Hinted(SetHintedResponse),
+    Stored(SetResponse),
+    Failed(SetFailResponse),
+}
+
+/// The replies to a Get, which share the getResponse type
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum GetReply {
+    Found(GetSuccessResponse),
+    Siblings(GetSiblingsResponse),
+    Failed(GetFailResponse),
+}
+
+/// The replies to a Delete, which share the deleteResponse type
+/// A hinted reply is tried before a deleted one, since it has every field of a deleted one
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum DeleteReply {
+    Hinted(DeleteHintedResponse),
+    Deleted(DeleteResponse),
+    Failed(DeleteFailResponse),
+}
+
+/// A client request as the nodes serving it refer to it: the node that received it
+/// from the client, and the id the client gave it
+/// Ids only tell apart the requests of one coordinator, since clients number their own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    pub coordinator: String,
+    pub id: i32,
+}
+
+impl RequestId {
+    /// Returns a new RequestId object
+    ///
+    /// # Arguments
+    ///
+    /// * `coordinator` - the node that received the request from the client
+    /// * `id` - the id of the request from the client
+    pub fn new(coordinator: &str, id: i32) -> RequestId {
+        RequestId {
+            coordinator: String::from(coordinator),
+            id,
+        }
+    }
+}
+
+/// The replies to a Leave, which share the leaveResponse type
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum LeaveReply {
+    Left(LeaveResponse),
+    Failed(LeaveFailResponse),
+}
+
+/// Lets a message struct be passed wherever a Message is expected
+macro_rules! into_message {
+    ($($variant:ident),* $(,)?) => {
+        $(impl From<$variant> for Message {
+            fn from(msg: $variant) -> Message {
+                Message::$variant(msg)
+            }
+        })*
+    };
+}
+
+/// Lets a reply struct be passed wherever a Message is expected
+macro_rules! into_reply {
+    ($($inner:ident => $variant:ident($reply:ident::$case:ident)),* $(,)?) => {
+        $(impl From<$inner> for Message {
+            fn from(msg: $inner) -> Message {
+                Message::$variant($reply::$case(msg))
+            }
+        })*
+    };
+}
+
+into_message!(
+    Hello,
+    Set,
+    Get,
+    Delete,
+    Leave,
+    HelloResponse,
+    Join,
+    JoinAck,
+    JoinError,
+    FindSucc,
+    FindSuccResponse,
+    GetPred,
+    GetPredResponse,
+    Notify,
+    Retrieve,
+    RetrieveResponse,
+    ReadReplica,
+    ReadReplicaResponse,
+    ReadRepair,
+    Store,
+    Remove,
+    StoreAck,
+    StoreFailed,
+    TransferRequest,
+    TransferKeys,
+    Duplicate,
+    Replicate,
+    ResyncRequest,
+    MerkleHashes,
+    MerkleRequest,
+    RepairRequest,
+    Repair,
+    DuplicateAck,
+    Ping,
+    Pong,
+    PingReq,
+    PingAck,
+    PingSelf,
+    PongSelf,
+    Rejoin,
+    RejoinAck,
+    LeaveKeys,
+    LeaveNotice,
+    LeaveAck,
+);
+
+into_reply!(
+    SetResponse => SetResponse(SetReply::Stored),
+    SetHintedResponse => SetResponse(SetReply::Hinted),
+    SetFailResponse => SetResponse(SetReply::Failed),
+    GetSuccessResponse => GetResponse(GetReply::Found),
+    GetSiblingsResponse => GetResponse(GetReply::Siblings),
+    GetFailResponse => GetResponse(GetReply::Failed),
+    DeleteResponse => DeleteResponse(DeleteReply::Deleted),
+    DeleteHintedResponse => DeleteResponse(DeleteReply::Hinted),
+    DeleteFailResponse => DeleteResponse(DeleteReply::Failed),
+    LeaveResponse => LeaveResponse(LeaveReply::Left),
+    LeaveFailResponse => LeaveResponse(LeaveReply::Failed),
+);
+
+/// Parses a message received from the broker, restoring the virtual node names
+/// that were moved out of its routing fields
+/// Returns the name of the node the message is addressed to, if any, along with the message
+///
+/// # Arguments
+///
+/// * `contents` - The received message
+pub fn parse(contents: &str) -> serde_json::Result<(Option<String>, Message)> {
+    let mut json: serde_json::Value = serde_json::from_str(contents)?;
+    if let Some(fields) = json.as_object_mut() {
+        for (field, vnode_field) in &[
+            ("source", "source_vnode"),
+            ("destination", "destination_vnode"),
+        ] {
+            if let Some(vnode) = fields.remove(*vnode_field) {
+                fields.insert(String::from(*field), vnode);
+            }
+        }
+    }
+    let destination = json
+        .get("destination")
+        .and_then(|d| d.as_str())
+        .map(String::from);
+    Ok((destination, serde_json::from_value(json)?))
+}
+
+/// Returns the error reply to a client request that could not be parsed, if its type
+/// has a reply that can carry an error and it has an id to answer
+///
+/// # Arguments
+///
+/// * `contents` - The received message
+/// * `error` - Why the message could not be parsed
+pub fn error_reply(contents: &str, error: &str) -> Option<Message> {
+    let json: serde_json::Value = serde_json::from_str(contents).ok()?;
+    let id = i32::try_from(json.get("id")?.as_i64()?).ok()?;
+    let error = String::from("Malformed request: ") + error;
+    match json.get("type")?.as_str()? {
+        "set" => Some(SetFailResponse { id, error }.into()),
+        "get" => Some(GetFailResponse { id, error }.into()),
+        "delete" => Some(DeleteFailResponse { id, error }.into()),
+        "leave" => Some(LeaveFailResponse { id, error }.into()),
+        _ => None,
+    }
+}
+
+/// Returns the type of a message, as tagged when it is sent
+///
+/// # Arguments
+///
+/// * `msg` - The message
+pub fn type_name(msg: &Message) -> String {
+    serde_json::to_value(msg)
+        .ok()
+        .and_then(|json| json.get("type")?.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// Moves virtual node names out of the source and destination fields of an
+/// outgoing message, since transports only know how to route to processes
+///
+/// # Arguments
+///
+/// * `json` - The outgoing message
+pub fn route_to_processes(json: &mut serde_json::Value) {
+    if let Some(fields) = json.as_object_mut() {
+        for (field, vnode_field) in &[
+            ("source", "source_vnode"),
+            ("destination", "destination_vnode"),
+        ] {
+            let vnode = match fields.get(*field).and_then(|v| v.as_str()) {
+                Some(name) if node::physical_name(name) != name => String::from(name),
+                _ => continue,
+            };
+            fields.insert(
+                String::from(*field),
+                serde_json::json!(node::physical_name(&vnode)),
+            );
+            fields.insert(String::from(*vnode_field), serde_json::json!(vnode));
+        }
+    }
+}
+
+/// A request from the broker to start taking part in the network
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hello {}
+
+/// A client request to set a key, with the write quorum and
+/// causal context it was sent with, if any
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Set {
+    pub id: i32,
+    pub key: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub w: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<VectorClock>,
+}
+
+/// A client request to get a key, with the read quorum it was sent with, if any
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Get {
+    pub id: i32,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r: Option<usize>,
+}
+
+/// A client request to delete a key, with the write quorum and
+/// causal context it was sent with, if any
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Delete {
+    pub id: i32,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub w: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<VectorClock>,
+}
+
+/// A request to hand the node's keys over to its successors and leave the ring
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Leave {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<i32>,
-    pub destination: Option<String>,
-    pub source: Option<String>,
-    pub query_id: Option<i32>,
-    pub node_name: Option<String>,
-    pub node_id: Option<i32>,
-    pub pred_id: Option<i32>,
-    pub pred_name: Option<String>,
-    pub min: Option<i32>,
-    pub max: Option<i32>,
-    pub keys: Option<Vec<String>>,
-    pub values: Option<Vec<String>>,
-    pub failed: Option<bool>,
 }
 
 /// To send to broker to confirm joining the network
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct HelloResponse {
-    r#type: String,
-    source: String,
+    pub source: String,
 }
 
 impl HelloResponse {
@@ -41,19 +352,17 @@ impl HelloResponse {
     /// * `source` - The source node
     pub fn new(source: &str) -> HelloResponse {
         HelloResponse {
-            r#type: String::from("helloResponse"),
             source: String::from(source),
         }
     }
 }
 
 /// A confirmation of a Set operation
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct SetResponse {
-    r#type: String,
-    id: i32,
-    key: String,
-    value: String,
+    pub id: i32,
+    pub key: String,
+    pub value: String,
 }
 
 impl SetResponse {
@@ -66,7 +375,6 @@ impl SetResponse {
     /// * `value` - value set
     pub fn new(id: i32, key: &str, value: &str) -> SetResponse {
         SetResponse {
-            r#type: String::from("setResponse"),
             id,
             key: String::from(key),
             value: String::from(value),
@@ -74,13 +382,77 @@ impl SetResponse {
     }
 }
 
+/// An acceptance of a Set its owner did not confirm in time
+/// The write is kept as a hint and stored once its owner, or whichever node takes over
+/// its key, is reachable, so reads may not see it until then
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetHintedResponse {
+    pub id: i32,
+    pub key: String,
+    pub value: String,
+    pub hinted: bool,
+}
+
+impl SetHintedResponse {
+    /// Returns a new SetHintedResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Set request
+    /// * `key` - key set to
+    /// * `value` - value set
+    pub fn new(id: i32, key: &str, value: &str) -> SetHintedResponse {
+        SetHintedResponse {
+            id,
+            key: String::from(key),
+            value: String::from(value),
+            hinted: true,
+        }
+    }
+}
+
+/// A failed Set query response
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetFailResponse {
+    pub id: i32,
+    pub error: String,
+}
+
+impl SetFailResponse {
+    /// Returns a new SetFailResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Set request
+    /// * `key` - key set to
+    pub fn new(id: i32, key: &str) -> SetFailResponse {
+        SetFailResponse {
+            id,
+            error: String::from("Timed out storing key: ") + key,
+        }
+    }
+
+    /// Returns a new SetFailResponse object for a Set the owner of the key could not store
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Set request
+    /// * `key` - key set to
+    /// * `error` - why the owner could not store it
+    pub fn not_stored(id: i32, key: &str, error: &str) -> SetFailResponse {
+        SetFailResponse {
+            id,
+            error: format!("Cannot store key: {}: {}", key, error),
+        }
+    }
+}
+
 /// A successful Get query response
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetSuccessResponse {
-    r#type: String,
-    id: i32,
-    key: String,
-    value: String,
+    pub id: i32,
+    pub key: String,
+    pub value: String,
 }
 
 impl GetSuccessResponse {
@@ -93,7 +465,6 @@ impl GetSuccessResponse {
     /// * `value` - value found
     pub fn new(id: i32, key: &str, value: &str) -> GetSuccessResponse {
         GetSuccessResponse {
-            r#type: String::from("getResponse"),
             id,
             key: String::from(key),
             value: String::from(value),
@@ -101,12 +472,45 @@ impl GetSuccessResponse {
     }
 }
 
+/// A Get query response under vector-clock versioning, holding every concurrent value
+/// and the causal context a later Set or Delete passes back to resolve them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetSiblingsResponse {
+    pub id: i32,
+    pub key: String,
+    pub values: Vec<String>,
+    pub context: VectorClock,
+}
+
+impl GetSiblingsResponse {
+    /// Returns a new GetSiblingsResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Get request
+    /// * `key` - key requested
+    /// * `values` - concurrent values found
+    /// * `context` - causal context of the values
+    pub fn new(
+        id: i32,
+        key: &str,
+        values: Vec<String>,
+        context: VectorClock,
+    ) -> GetSiblingsResponse {
+        GetSiblingsResponse {
+            id,
+            key: String::from(key),
+            values,
+            context,
+        }
+    }
+}
+
 /// A failed Get query response
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetFailResponse {
-    r#type: String,
-    id: i32,
-    error: String,
+    pub id: i32,
+    pub error: String,
 }
 
 impl GetFailResponse {
@@ -118,21 +522,139 @@ impl GetFailResponse {
     /// * `key` - key requested
     pub fn new(id: i32, key: &str) -> GetFailResponse {
         GetFailResponse {
-            r#type: String::from("getResponse"),
             id,
             error: String::from("No such key: ") + key,
         }
     }
+
+    /// Returns a new GetFailResponse object for a Get that not enough replicas answered in time
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Get request
+    /// * `key` - key requested
+    pub fn timed_out(id: i32, key: &str) -> GetFailResponse {
+        GetFailResponse {
+            id,
+            error: String::from("Timed out reading key: ") + key,
+        }
+    }
+
+    /// Returns a new GetFailResponse object for a Get that cannot be served as requested
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Get request
+    /// * `key` - key requested
+    /// * `error` - why it cannot be served
+    pub fn not_read(id: i32, key: &str, error: &str) -> GetFailResponse {
+        GetFailResponse {
+            id,
+            error: format!("Cannot read key: {}: {}", key, error),
+        }
+    }
+}
+
+/// A confirmation of a Delete operation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteResponse {
+    pub id: i32,
+    pub key: String,
+}
+
+impl DeleteResponse {
+    /// Returns a new DeleteResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Delete request
+    /// * `key` - key deleted
+    pub fn new(id: i32, key: &str) -> DeleteResponse {
+        DeleteResponse {
+            id,
+            key: String::from(key),
+        }
+    }
+}
+
+/// An acceptance of a Delete its owner did not confirm in time
+/// The deletion is kept as a hint and stored once its owner, or whichever node takes over
+/// its key, is reachable, so reads may still see the key until then
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteHintedResponse {
+    pub id: i32,
+    pub key: String,
+    pub hinted: bool,
+}
+
+impl DeleteHintedResponse {
+    /// Returns a new DeleteHintedResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Delete request
+    /// * `key` - key deleted
+    pub fn new(id: i32, key: &str) -> DeleteHintedResponse {
+        DeleteHintedResponse {
+            id,
+            key: String::from(key),
+            hinted: true,
+        }
+    }
+}
+
+/// A confirmation that a node handed its keys over and left the ring
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaveResponse {
+    pub id: i32,
+    pub source: String,
+}
+
+impl LeaveResponse {
+    /// Returns a new LeaveResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Leave request
+    /// * `source` - the node that left
+    pub fn new(id: i32, source: &str) -> LeaveResponse {
+        LeaveResponse {
+            id,
+            source: String::from(source),
+        }
+    }
+}
+
+/// A refusal to leave the ring
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaveFailResponse {
+    pub id: i32,
+    pub error: String,
+}
+
+impl LeaveFailResponse {
+    /// Returns a new LeaveFailResponse object for a node that holds keys
+    /// but knows no node on another process to hand them over to
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Leave request
+    pub fn no_successor(id: i32) -> LeaveFailResponse {
+        LeaveFailResponse {
+            id,
+            error: String::from("Cannot leave: no other node is known to hand the keys over to"),
+        }
+    }
 }
 
 // CUSTOM MESSAGES FOR HALO PROTOCOL
 
 /// A request to join the ring
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Join {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
 }
 
 impl Join {
@@ -142,21 +664,21 @@ impl Join {
     ///
     /// * `source` - the requesting node
     /// * `destination` - the node that the requestor wants to join
-    pub fn new(source: &str, destination: &str) -> Join {
+    /// * `node_id` - the hashed id the requesting node wants to take on the ring
+    pub fn new(source: &str, destination: &str, node_id: Id) -> Join {
         Join {
-            r#type: String::from("join"),
             source: String::from(source),
             destination: String::from(destination),
+            node_id,
         }
     }
 }
 
 /// A response to a node wanting to join the ring
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct JoinAck {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl JoinAck {
@@ -168,21 +690,50 @@ impl JoinAck {
     /// * `destination` - the requesting node
     pub fn new(source: &str, destination: &str) -> JoinAck {
         JoinAck {
-            r#type: String::from("joinAck"),
             source: String::from(source),
             destination: String::from(destination),
         }
     }
 }
 
+/// A rejection of a node wanting to join the ring because
+/// another node already owns the same id
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JoinError {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub node_name: String,
+    pub error: String,
+}
+
+impl JoinError {
+    /// Returns a new JoinError object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that the requestor wants to join
+    /// * `destination` - the requesting node
+    /// * `node_id` - the id that is already taken
+    /// * `node_name` - the node that already owns the id
+    pub fn new(source: &str, destination: &str, node_id: Id, node_name: &str) -> JoinError {
+        JoinError {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            node_name: String::from(node_name),
+            error: format!("Id {} is already owned by {}", node_id, node_name),
+        }
+    }
+}
+
 /// A request to find the successor of a key query
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct FindSucc {
-    r#type: String,
-    source: String,
-    destination: String,
-    query_id: i32,
-    id: Option<i32>,
+    pub source: String,
+    pub destination: String,
+    pub query_id: Id,
+    pub id: Option<i32>,
 }
 
 impl FindSucc {
@@ -194,9 +745,8 @@ impl FindSucc {
     /// * `destination` - a node checking itself and/or passing the query on
     /// * `query-id` - key being queried
     /// * `id` - an optional id used for some queries
-    pub fn new(source: &str, destination: &str, query_id: i32, id: Option<i32>) -> FindSucc {
+    pub fn new(source: &str, destination: &str, query_id: Id, id: Option<i32>) -> FindSucc {
         FindSucc {
-            r#type: String::from("findSucc"),
             source: String::from(source),
             destination: String::from(destination),
             query_id,
@@ -206,15 +756,14 @@ impl FindSucc {
 }
 
 /// A response returning the successor of a key query
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct FindSuccResponse {
-    r#type: String,
-    source: String,
-    destination: String,
-    node_name: String,
-    node_id: i32,
-    query_id: i32,
-    id: Option<i32>,
+    pub source: String,
+    pub destination: String,
+    pub node_name: String,
+    pub node_id: Id,
+    pub query_id: Id,
+    pub id: Option<i32>,
 }
 
 impl FindSuccResponse {
@@ -232,12 +781,11 @@ impl FindSuccResponse {
         source: &str,
         destination: &str,
         node_name: &str,
-        node_id: i32,
-        query_id: i32,
+        node_id: Id,
+        query_id: Id,
         id: Option<i32>,
     ) -> FindSuccResponse {
         FindSuccResponse {
-            r#type: String::from("findSuccResponse"),
             source: String::from(source),
             destination: String::from(destination),
             node_name: String::from(node_name),
@@ -249,11 +797,10 @@ impl FindSuccResponse {
 }
 
 /// A request to get the predecessor of a node
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetPred {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl GetPred {
@@ -265,7 +812,6 @@ impl GetPred {
     /// * `destination` - the node being requested
     pub fn new(source: &str, destination: &str) -> GetPred {
         GetPred {
-            r#type: String::from("getPred"),
             source: String::from(source),
             destination: String::from(destination),
         }
@@ -273,13 +819,12 @@ impl GetPred {
 }
 
 /// A response to get the predecessor of a node
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetPredResponse {
-    r#type: String,
-    source: String,
-    destination: String,
-    pred_id: Option<i32>,
-    pred_name: Option<String>,
+    pub source: String,
+    pub destination: String,
+    pub pred_id: Option<Id>,
+    pub pred_name: Option<String>,
 }
 
 impl GetPredResponse {
@@ -294,11 +839,10 @@ impl GetPredResponse {
     pub fn new(
         source: &str,
         destination: &str,
-        pred_id: Option<i32>,
+        pred_id: Option<Id>,
         pred_name: Option<String>,
     ) -> GetPredResponse {
         GetPredResponse {
-            r#type: String::from("getPredResponse"),
             source: String::from(source),
             destination: String::from(destination),
             pred_id,
@@ -309,13 +853,12 @@ impl GetPredResponse {
 
 /// A node notifies a successor that it thinks that
 /// it could be the successor's predecessor
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Notify {
-    r#type: String,
-    source: String,
-    destination: String,
-    node_id: i32,
-    failed: bool,
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub failed: bool,
 }
 
 impl Notify {
@@ -327,9 +870,8 @@ impl Notify {
     /// * `destination` - the node requesting its successor's predecessor
     /// * `pred_id` - the predecessor, if it has one (hashed id)
     /// * `pred_name` - the predecessor, if it has one (name)
-    pub fn new(source: &str, destination: &str, node_id: i32, failed: bool) -> Notify {
+    pub fn new(source: &str, destination: &str, node_id: Id, failed: bool) -> Notify {
         Notify {
-            r#type: String::from("notify"),
             source: String::from(source),
             destination: String::from(destination),
             node_id,
@@ -340,13 +882,13 @@ impl Notify {
 
 /// A request for a node to retrieve data, if it exists,
 /// and return a GetResponse to the client
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Retrieve {
-    r#type: String,
-    source: String,
-    destination: String,
-    key: String,
-    id: i32,
+    pub source: String,
+    pub destination: String,
+    pub key: String,
+    pub id: i32,
+    pub r: usize,
 }
 
 impl Retrieve {
@@ -358,104 +900,427 @@ impl Retrieve {
     /// * `destination` - the successor of the key for the data
     /// * `key` - the key to be searched for locally
     /// * `id` - the id of the Get request from the client
-    pub fn new(source: &str, destination: &str, key: &str, id: i32) -> Retrieve {
+    /// * `r` - the number of copies, counting the successor's own, to read from
+    pub fn new(source: &str, destination: &str, key: &str, id: i32, r: usize) -> Retrieve {
         Retrieve {
-            r#type: String::from("retrieve"),
             source: String::from(source),
             destination: String::from(destination),
             key: String::from(key),
             id,
+            r,
         }
     }
 }
 
-/// A request for a node to store data locally
-#[derive(Serialize)]
-pub struct Store {
-    r#type: String,
-    source: String,
-    destination: String,
-    key: String,
-    value: String,
+/// The newest copy of a key the successor of the key found for a Get, sent back to the
+/// node that received the request from the client, or none if the key does not exist
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RetrieveResponse {
+    pub source: String,
+    pub destination: String,
+    pub key: String,
+    pub id: i32,
+    pub record: Option<Record>,
 }
 
-impl Store {
-    /// Returns a new Store object
+impl RetrieveResponse {
+    /// Returns a new RetrieveResponse object
     ///
     /// # Arguments
     ///
-    /// * `source` - the node that received the Set request from the client
-    /// * `destination` - the successor of the key for the data
-    /// * `key` - the key to be stored for locally
-    /// * `value` - the data to be stored
-    pub fn new(source: &str, destination: &str, key: &str, value: &str) -> Store {
-        Store {
-            r#type: String::from("store"),
+    /// * `source` - the successor of the key
+    /// * `destination` - the node that received the Get request from the client
+    /// * `key` - the key searched for
+    /// * `id` - the id of the Get request from the client
+    /// * `record` - the newest copy of the key found
+    pub fn new(
+        source: &str,
+        destination: &str,
+        key: &str,
+        id: i32,
+        record: Option<Record>,
+    ) -> RetrieveResponse {
+        RetrieveResponse {
             source: String::from(source),
             destination: String::from(destination),
             key: String::from(key),
-            value: String::from(value),
+            id,
+            record,
         }
     }
 }
 
-/// A request for a node to transfer its keys in a given range
-#[derive(Serialize)]
-pub struct TransferRequest {
-    r#type: String,
-    source: String,
-    destination: String,
-    min: i32,
-    max: i32,
+/// A request from the successor of a key for a replica holder's copy of it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadReplica {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub key: String,
+    pub request: RequestId,
 }
 
-impl TransferRequest {
-    /// Returns a new TransferRequest object
+impl ReadReplica {
+    /// Returns a new ReadReplica object
     ///
     /// # Arguments
     ///
-    /// * `source` - the node that wants the keys
-    /// * `destination` - the node that contains the requested keys
-    /// * `min` - the minimum key to be transferred
-    /// * `max` - the maximum key to be transferred
-    pub fn new(source: &str, destination: &str, min: i32, max: i32) -> TransferRequest {
-        TransferRequest {
-            r#type: String::from("transferRequest"),
+    /// * `source` - the successor of the key
+    /// * `destination` - the replica holder
+    /// * `node_id` - the hashed id of the successor, which the replica is stored under
+    /// * `key` - the key to be searched for in the replica
+    /// * `request` - the Get request from the client
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        key: &str,
+        request: RequestId,
+    ) -> ReadReplica {
+        ReadReplica {
             source: String::from(source),
             destination: String::from(destination),
-            min,
-            max,
+            node_id,
+            key: String::from(key),
+            request,
         }
     }
 }
 
-/// A response from a node transferring its keys in a given range
-#[derive(Serialize)]
-pub struct TransferKeys {
-    r#type: String,
-    source: String,
-    destination: String,
-    keys: Vec<String>,
-    values: Vec<String>,
+/// A replica holder's copy of a key, or none if the replica does not hold it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadReplicaResponse {
+    pub source: String,
+    pub destination: String,
+    pub key: String,
+    pub request: RequestId,
+    pub record: Option<Record>,
 }
 
-impl TransferKeys {
-    /// Returns a new TransferKeys object
+impl ReadReplicaResponse {
+    /// Returns a new ReadReplicaResponse object
     ///
     /// # Arguments
     ///
-    /// * `source` - the node that contains the requested keys
-    /// * `destination` - the node that wants the keys
-    /// * `keys` - the transferred keys
-    /// * `valurs` - the transferred values
+    /// * `source` - the replica holder
+    /// * `destination` - the successor of the key
+    /// * `key` - the key searched for
+    /// * `request` - the Get request from the client
+    /// * `record` - the replica's copy of the key
     pub fn new(
         source: &str,
         destination: &str,
-        keys: Vec<String>,
-        values: Vec<String>,
-    ) -> TransferKeys {
+        key: &str,
+        request: RequestId,
+        record: Option<Record>,
+    ) -> ReadReplicaResponse {
+        ReadReplicaResponse {
+            source: String::from(source),
+            destination: String::from(destination),
+            key: String::from(key),
+            request,
+            record,
+        }
+    }
+}
+
+/// A failed Delete query response
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteFailResponse {
+    pub id: i32,
+    pub error: String,
+}
+
+impl DeleteFailResponse {
+    /// Returns a new DeleteFailResponse object
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Delete request
+    /// * `key` - key deleted
+    pub fn new(id: i32, key: &str) -> DeleteFailResponse {
+        DeleteFailResponse {
+            id,
+            error: String::from("Timed out deleting key: ") + key,
+        }
+    }
+
+    /// Returns a new DeleteFailResponse object for a Delete the owner of the key could not store
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the Delete request
+    /// * `key` - key deleted
+    /// * `error` - why the owner could not store the deletion
+    pub fn not_stored(id: i32, key: &str, error: &str) -> DeleteFailResponse {
+        DeleteFailResponse {
+            id,
+            error: format!("Cannot delete key: {}: {}", key, error),
+        }
+    }
+}
+
+/// The newest copy of a key, for a replica holder whose copy was found stale during a read
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadRepair {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub key: String,
+    pub record: Record,
+}
+
+impl ReadRepair {
+    /// Returns a new ReadRepair object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the successor of the key
+    /// * `destination` - the replica holder
+    /// * `node_id` - the hashed id of the successor, which the replica is stored under
+    /// * `key` - the key being repaired
+    /// * `record` - the newest copy of the key
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        key: &str,
+        record: Record,
+    ) -> ReadRepair {
+        ReadRepair {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            key: String::from(key),
+            record,
+        }
+    }
+}
+
+/// A request for a node to store data locally
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Store {
+    pub source: String,
+    pub destination: String,
+    pub key: String,
+    pub value: String,
+    pub id: i32,
+    pub w: usize,
+    pub version: Version,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<VectorClock>,
+}
+
+impl Store {
+    /// Returns a new Store object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that received the Set request from the client
+    /// * `destination` - the successor of the key for the data
+    /// * `key` - the key to be stored for locally
+    /// * `value` - the data to be stored
+    /// * `id` - the id of the Set request from the client
+    /// * `w` - the number of copies, counting the successor's own, that must store the data
+    /// * `version` - the version the coordinator gave the write
+    /// * `context` - the causal context of the write under vector-clock versioning
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        source: &str,
+        destination: &str,
+        key: &str,
+        value: &str,
+        id: i32,
+        w: usize,
+        version: Version,
+        context: Option<VectorClock>,
+    ) -> Store {
+        Store {
+            source: String::from(source),
+            destination: String::from(destination),
+            key: String::from(key),
+            value: String::from(value),
+            id,
+            w,
+            version,
+            context,
+        }
+    }
+}
+
+/// A request for a node to delete data locally
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Remove {
+    pub source: String,
+    pub destination: String,
+    pub key: String,
+    pub id: i32,
+    pub w: usize,
+    pub version: Version,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<VectorClock>,
+}
+
+impl Remove {
+    /// Returns a new Remove object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that received the Delete request from the client
+    /// * `destination` - the successor of the key for the data
+    /// * `key` - the key to be deleted locally
+    /// * `id` - the id of the Delete request from the client
+    /// * `w` - the number of copies, counting the successor's own, that must delete the data
+    /// * `version` - the version the coordinator gave the deletion
+    /// * `context` - the causal context of the deletion under vector-clock versioning
+    pub fn new(
+        source: &str,
+        destination: &str,
+        key: &str,
+        id: i32,
+        w: usize,
+        version: Version,
+        context: Option<VectorClock>,
+    ) -> Remove {
+        Remove {
+            source: String::from(source),
+            destination: String::from(destination),
+            key: String::from(key),
+            id,
+            w,
+            version,
+            context,
+        }
+    }
+}
+
+/// A confirmation from the owner of a key that a Set or Delete was stored
+/// The key and version tell the write apart from others with the same client id
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreAck {
+    pub source: String,
+    pub destination: String,
+    pub id: i32,
+    pub key: String,
+    pub version: Version,
+}
+
+impl StoreAck {
+    /// Returns a new StoreAck object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the successor of the key that stored the data
+    /// * `destination` - the node that received the request from the client
+    /// * `id` - the id of the request from the client
+    /// * `key` - the key that was written
+    /// * `version` - the version of the write
+    pub fn new(source: &str, destination: &str, id: i32, key: &str, version: Version) -> StoreAck {
+        StoreAck {
+            source: String::from(source),
+            destination: String::from(destination),
+            id,
+            key: String::from(key),
+            version,
+        }
+    }
+}
+
+/// A report from the owner of a key that it could not durably store a Set or Delete
+/// The key and version tell the write apart from others with the same client id
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreFailed {
+    pub source: String,
+    pub destination: String,
+    pub id: i32,
+    pub key: String,
+    pub version: Version,
+    pub error: String,
+}
+
+impl StoreFailed {
+    /// Returns a new StoreFailed object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the successor of the key that failed to store the data
+    /// * `destination` - the node that received the request from the client
+    /// * `id` - the id of the request from the client
+    /// * `key` - the key that was written
+    /// * `version` - the version of the write
+    /// * `error` - why the data could not be stored
+    pub fn new(
+        source: &str,
+        destination: &str,
+        id: i32,
+        key: &str,
+        version: Version,
+        error: &str,
+    ) -> StoreFailed {
+        StoreFailed {
+            source: String::from(source),
+            destination: String::from(destination),
+            id,
+            key: String::from(key),
+            version,
+            error: String::from(error),
+        }
+    }
+}
+
+/// A request for a node to transfer its keys in a given range
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferRequest {
+    pub source: String,
+    pub destination: String,
+    pub min: Id,
+    pub max: Id,
+}
+
+impl TransferRequest {
+    /// Returns a new TransferRequest object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that wants the keys
+    /// * `destination` - the node that contains the requested keys
+    /// * `min` - the minimum key to be transferred
+    /// * `max` - the maximum key to be transferred
+    pub fn new(source: &str, destination: &str, min: Id, max: Id) -> TransferRequest {
+        TransferRequest {
+            source: String::from(source),
+            destination: String::from(destination),
+            min,
+            max,
+        }
+    }
+}
+
+/// A response from a node transferring its keys in a given range
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferKeys {
+    pub source: String,
+    pub destination: String,
+    pub keys: Vec<String>,
+    pub values: Vec<Record>,
+}
+
+impl TransferKeys {
+    /// Returns a new TransferKeys object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that contains the requested keys
+    /// * `destination` - the node that wants the keys
+    /// * `keys` - the transferred keys
+    /// * `valurs` - the transferred values, or tombstones for deleted keys
+    pub fn new(
+        source: &str,
+        destination: &str,
+        keys: Vec<String>,
+        values: Vec<Record>,
+    ) -> TransferKeys {
         TransferKeys {
-            r#type: String::from("transferKeys"),
             source: String::from(source),
             destination: String::from(destination),
             keys,
@@ -465,14 +1330,14 @@ impl TransferKeys {
 }
 
 /// A node duplicating its key/values to successors to be stored as replicas
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Duplicate {
-    r#type: String,
-    source: String,
-    destination: String,
-    id: i32,
-    keys: Vec<String>,
-    values: Vec<String>,
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub keys: Vec<String>,
+    pub values: Vec<Record>,
+    pub seq: u64,
 }
 
 impl Duplicate {
@@ -482,32 +1347,299 @@ impl Duplicate {
     ///
     /// * `source` - the node that is duplicating is data
     /// * `destination` - the node that is storing the replica
+    /// * `node_id` - the hashed id of the node that is duplicating its data
     /// * `keys` - the duplicated keys
-    /// * `values` - the duplicated values
+    /// * `values` - the duplicated values, or tombstones for deleted keys
+    /// * `seq` - the sequence number of the last replication update the duplicate includes
     pub fn new(
         source: &str,
         destination: &str,
-        id: i32,
+        node_id: Id,
         keys: Vec<String>,
-        values: Vec<String>,
+        values: Vec<Record>,
+        seq: u64,
     ) -> Duplicate {
         Duplicate {
-            r#type: String::from("duplicate"),
             source: String::from(source),
             destination: String::from(destination),
-            id,
+            node_id,
+            keys,
+            values,
+            seq,
+        }
+    }
+}
+
+/// An update to a replica with only the keys that changed since the previous update
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Replicate {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub seq: u64,
+    pub keys: Vec<String>,
+    pub records: Vec<Option<Record>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestId>,
+}
+
+impl Replicate {
+    /// Returns a new Replicate object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that is replicating its data
+    /// * `destination` - the node that is storing the replica
+    /// * `node_id` - the hashed id of the node that is replicating its data
+    /// * `seq` - the sequence number of the update, one more than the previous update's
+    /// * `keys` - the changed keys
+    /// * `records` - the changed values or tombstones, or None for keys the node no longer holds
+    /// * `request` - the client request that must be confirmed once the update is stored
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        seq: u64,
+        keys: Vec<String>,
+        records: Vec<Option<Record>>,
+        request: Option<RequestId>,
+    ) -> Replicate {
+        Replicate {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            seq,
+            keys,
+            records,
+            request,
+        }
+    }
+}
+
+/// A replica holder that missed a replication update asking for a full Duplicate
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResyncRequest {
+    pub source: String,
+    pub destination: String,
+}
+
+impl ResyncRequest {
+    /// Returns a new ResyncRequest object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that is storing the replica
+    /// * `destination` - the node that is replicating its data
+    pub fn new(source: &str, destination: &str) -> ResyncRequest {
+        ResyncRequest {
+            source: String::from(source),
+            destination: String::from(destination),
+        }
+    }
+}
+
+/// Hashes of nodes at one level of the Merkle tree over a node's range of keys,
+/// for a replica holder to compare with its own tree over the same range of the replica
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MerkleHashes {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub min: Id,
+    pub level: u32,
+    pub indexes: Vec<usize>,
+    pub hashes: Vec<Id>,
+}
+
+impl MerkleHashes {
+    /// Returns a new MerkleHashes object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node whose keys the tree is over
+    /// * `destination` - the node that is storing the replica
+    /// * `node_id` - the hashed id of the node whose keys the tree is over, the end of the range
+    /// * `min` - the id right before the range of keys the tree is over
+    /// * `level` - the level of the nodes in the tree, 0 being the root
+    /// * `indexes` - the positions of the nodes in their level
+    /// * `hashes` - the hashes of the nodes
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        min: Id,
+        level: u32,
+        indexes: Vec<usize>,
+        hashes: Vec<Id>,
+    ) -> MerkleHashes {
+        MerkleHashes {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            min,
+            level,
+            indexes,
+            hashes,
+        }
+    }
+}
+
+/// A replica holder asking for the hashes of nodes at one level of a node's Merkle tree,
+/// the children of the nodes it found differences in
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MerkleRequest {
+    pub source: String,
+    pub destination: String,
+    pub min: Id,
+    pub level: u32,
+    pub indexes: Vec<usize>,
+}
+
+impl MerkleRequest {
+    /// Returns a new MerkleRequest object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that is storing the replica
+    /// * `destination` - the node whose keys the tree is over
+    /// * `min` - the id right before the range of keys the tree is over
+    /// * `level` - the level of the nodes in the tree
+    /// * `indexes` - the positions of the nodes in their level
+    pub fn new(
+        source: &str,
+        destination: &str,
+        min: Id,
+        level: u32,
+        indexes: Vec<usize>,
+    ) -> MerkleRequest {
+        MerkleRequest {
+            source: String::from(source),
+            destination: String::from(destination),
+            min,
+            level,
+            indexes,
+        }
+    }
+}
+
+/// A replica holder asking for the keys in the leaf ranges of a node's Merkle tree
+/// its replica differs in
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RepairRequest {
+    pub source: String,
+    pub destination: String,
+    pub min: Id,
+    pub indexes: Vec<usize>,
+}
+
+impl RepairRequest {
+    /// Returns a new RepairRequest object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that is storing the replica
+    /// * `destination` - the node whose keys the tree is over
+    /// * `min` - the id right before the range of keys the tree is over
+    /// * `indexes` - the leaf ranges that differ
+    pub fn new(source: &str, destination: &str, min: Id, indexes: Vec<usize>) -> RepairRequest {
+        RepairRequest {
+            source: String::from(source),
+            destination: String::from(destination),
+            min,
+            indexes,
+        }
+    }
+}
+
+/// The keys a node holds in some leaf ranges of its Merkle tree, replacing
+/// a replica holder's copy of those ranges
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Repair {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub min: Id,
+    pub indexes: Vec<usize>,
+    pub keys: Vec<String>,
+    pub values: Vec<Record>,
+}
+
+impl Repair {
+    /// Returns a new Repair object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node whose keys are being repaired
+    /// * `destination` - the node that is storing the replica
+    /// * `node_id` - the hashed id of the node whose keys are being repaired
+    /// * `min` - the id right before the range of keys the tree is over
+    /// * `indexes` - the leaf ranges being repaired
+    /// * `keys` - the keys in those ranges
+    /// * `values` - the values of those keys, or tombstones for deleted keys
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        min: Id,
+        indexes: Vec<usize>,
+        keys: Vec<String>,
+        values: Vec<Record>,
+    ) -> Repair {
+        Repair {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            min,
+            indexes,
             keys,
             values,
         }
     }
 }
 
+/// A replica holder confirming it stored a Duplicate or Replicate, and which tombstones it stored
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DuplicateAck {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestId>,
+}
+
+impl DuplicateAck {
+    /// Returns a new DuplicateAck object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that is storing the replica
+    /// * `destination` - the node that duplicated its data
+    /// * `node_id` - the hashed id of the node that duplicated its data
+    /// * `keys` - the deleted keys that were stored as tombstones
+    /// * `request` - the client request the Replicate was sent for, if any
+    pub fn new(
+        source: &str,
+        destination: &str,
+        node_id: Id,
+        keys: Vec<String>,
+        request: Option<RequestId>,
+    ) -> DuplicateAck {
+        DuplicateAck {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            keys,
+            request,
+        }
+    }
+}
+
 /// A node checking if its successor is still alive
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Ping {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl Ping {
@@ -519,7 +1651,6 @@ impl Ping {
     /// * `destination` - the successor node to be checked
     pub fn new(source: &str, destination: &str) -> Ping {
         Ping {
-            r#type: String::from("ping"),
             source: String::from(source),
             destination: String::from(destination),
         }
@@ -527,11 +1658,10 @@ impl Ping {
 }
 
 /// A response to a node checking if its successor is still alive
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Pong {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl Pong {
@@ -543,19 +1673,68 @@ impl Pong {
     /// * `destination` - the node performing the check
     pub fn new(source: &str, destination: &str) -> Pong {
         Pong {
-            r#type: String::from("pong"),
             source: String::from(source),
             destination: String::from(destination),
         }
     }
 }
 
+/// A request from a node that suspects its successor or predecessor failed,
+/// asking another node to ping it on its behalf
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingReq {
+    pub source: String,
+    pub destination: String,
+    pub node_name: String,
+}
+
+impl PingReq {
+    /// Returns a new PingReq object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node suspecting a neighbour
+    /// * `destination` - the node asked to ping it
+    /// * `node_name` - the suspected neighbour
+    pub fn new(source: &str, destination: &str, node_name: &str) -> PingReq {
+        PingReq {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_name: String::from(node_name),
+        }
+    }
+}
+
+/// A confirmation that a node asked to ping a suspected neighbour got a pong from it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingAck {
+    pub source: String,
+    pub destination: String,
+    pub node_name: String,
+}
+
+impl PingAck {
+    /// Returns a new PingAck object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the node that pinged the suspect
+    /// * `destination` - the node suspecting its neighbour
+    /// * `node_name` - the suspect that answered
+    pub fn new(source: &str, destination: &str, node_name: &str) -> PingAck {
+        PingAck {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_name: String::from(node_name),
+        }
+    }
+}
+
 /// A Ping from a node to itself
 /// A way of keeping the periodic stabilization thread unblocked
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PingSelf {
-    r#type: String,
-    destination: String,
+    pub destination: String,
 }
 
 impl PingSelf {
@@ -566,7 +1745,6 @@ impl PingSelf {
     /// * `destination` - the node pinging itself
     pub fn new(destination: &str) -> PingSelf {
         PingSelf {
-            r#type: String::from("pingSelf"),
             destination: String::from(destination),
         }
     }
@@ -574,10 +1752,9 @@ impl PingSelf {
 
 /// A Pong from a node to itself
 /// A way of keeping the periodic stabilization thread unblocked
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PongSelf {
-    r#type: String,
-    destination: String,
+    pub destination: String,
 }
 
 impl PongSelf {
@@ -588,18 +1765,16 @@ impl PongSelf {
     /// * `destination` - the node ponging itself
     pub fn new(destination: &str) -> PongSelf {
         PongSelf {
-            r#type: String::from("pongSelf"),
             destination: String::from(destination),
         }
     }
 }
 
 /// A request from a node to rejoin a failed successor
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Rejoin {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl Rejoin {
@@ -611,7 +1786,6 @@ impl Rejoin {
     /// * `destination` - the node to be rejoined
     pub fn new(source: &str, destination: &str) -> Rejoin {
         Rejoin {
-            r#type: String::from("rejoin"),
             source: String::from(source),
             destination: String::from(destination),
         }
@@ -619,11 +1793,10 @@ impl Rejoin {
 }
 
 /// A response to a node trying to rejoin a ring
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct RejoinAck {
-    r#type: String,
-    source: String,
-    destination: String,
+    pub source: String,
+    pub destination: String,
 }
 
 impl RejoinAck {
@@ -635,9 +1808,389 @@ impl RejoinAck {
     /// * `destination` - the node wanting to rejoin
     pub fn new(source: &str, destination: &str) -> RejoinAck {
         RejoinAck {
-            r#type: String::from("rejoinAck"),
             source: String::from(source),
             destination: String::from(destination),
         }
     }
 }
+
+/// A leaving node handing the keys it owns to its successor
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaveKeys {
+    pub source: String,
+    pub destination: String,
+    pub keys: Vec<String>,
+    pub values: Vec<Record>,
+    pub pred_id: Option<Id>,
+    pub pred_name: Option<String>,
+}
+
+impl LeaveKeys {
+    /// Returns a new LeaveKeys object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the leaving node
+    /// * `destination` - the successor adopting the keys
+    /// * `keys` - the handed over keys
+    /// * `values` - the handed over values, or tombstones for deleted keys
+    /// * `pred_id` - the leaving node's predecessor, if it has one (hashed id)
+    /// * `pred_name` - the leaving node's predecessor, if it has one (name)
+    pub fn new(
+        source: &str,
+        destination: &str,
+        keys: Vec<String>,
+        values: Vec<Record>,
+        pred_id: Option<Id>,
+        pred_name: Option<String>,
+    ) -> LeaveKeys {
+        LeaveKeys {
+            source: String::from(source),
+            destination: String::from(destination),
+            keys,
+            values,
+            pred_id,
+            pred_name,
+        }
+    }
+}
+
+/// A leaving node telling its predecessor which node to take as successor
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaveNotice {
+    pub source: String,
+    pub destination: String,
+    pub node_id: Id,
+    pub node_name: String,
+}
+
+impl LeaveNotice {
+    /// Returns a new LeaveNotice object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the leaving node
+    /// * `destination` - the leaving node's predecessor
+    /// * `node_id` - the successor adopting the leaving node's keys (hashed id)
+    /// * `node_name` - the successor adopting the leaving node's keys (name)
+    pub fn new(source: &str, destination: &str, node_id: Id, node_name: &str) -> LeaveNotice {
+        LeaveNotice {
+            source: String::from(source),
+            destination: String::from(destination),
+            node_id,
+            node_name: String::from(node_name),
+        }
+    }
+}
+
+/// A successor confirming it adopted a leaving node's keys
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaveAck {
+    pub source: String,
+    pub destination: String,
+}
+
+impl LeaveAck {
+    /// Returns a new LeaveAck object
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - the successor adopting the keys
+    /// * `destination` - the leaving node
+    pub fn new(source: &str, destination: &str) -> LeaveAck {
+        LeaveAck {
+            source: String::from(source),
+            destination: String::from(destination),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Number of variants of Message, each of which has at least one sample
+    const VARIANTS: usize = 48;
+
+    /// Returns the type a message must be tagged with on the wire
+    /// Every variant is listed, so that none can be added or renamed without pinning its tag
+    fn wire_type(msg: &Message) -> &'static str {
+        match msg {
+            Message::Hello(_) => "hello",
+            Message::Set(_) => "set",
+            Message::Get(_) => "get",
+            Message::Delete(_) => "delete",
+            Message::Leave(_) => "leave",
+            Message::HelloResponse(_) => "helloResponse",
+            Message::SetResponse(_) => "setResponse",
+            Message::GetResponse(_) => "getResponse",
+            Message::DeleteResponse(_) => "deleteResponse",
+            Message::LeaveResponse(_) => "leaveResponse",
+            Message::Join(_) => "join",
+            Message::JoinAck(_) => "joinAck",
+            Message::JoinError(_) => "joinError",
+            Message::FindSucc(_) => "findSucc",
+            Message::FindSuccResponse(_) => "findSuccResponse",
+            Message::GetPred(_) => "getPred",
+            Message::GetPredResponse(_) => "getPredResponse",
+            Message::Notify(_) => "notify",
+            Message::Retrieve(_) => "retrieve",
+            Message::RetrieveResponse(_) => "retrieveResponse",
+            Message::ReadReplica(_) => "readReplica",
+            Message::ReadReplicaResponse(_) => "readReplicaResponse",
+            Message::ReadRepair(_) => "readRepair",
+            Message::Store(_) => "store",
+            Message::Remove(_) => "remove",
+            Message::StoreAck(_) => "storeAck",
+            Message::StoreFailed(_) => "storeFailed",
+            Message::TransferRequest(_) => "transferRequest",
+            Message::TransferKeys(_) => "transferKeys",
+            Message::Duplicate(_) => "duplicate",
+            Message::Replicate(_) => "replicate",
+            Message::ResyncRequest(_) => "resyncRequest",
+            Message::MerkleHashes(_) => "merkleHashes",
+            Message::MerkleRequest(_) => "merkleRequest",
+            Message::RepairRequest(_) => "repairRequest",
+            Message::Repair(_) => "repair",
+            Message::DuplicateAck(_) => "duplicateAck",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::PingReq(_) => "pingReq",
+            Message::PingAck(_) => "pingAck",
+            Message::PingSelf(_) => "pingSelf",
+            Message::PongSelf(_) => "pongSelf",
+            Message::Rejoin(_) => "rejoin",
+            Message::RejoinAck(_) => "rejoinAck",
+            Message::LeaveKeys(_) => "leaveKeys",
+            Message::LeaveNotice(_) => "leaveNotice",
+            Message::LeaveAck(_) => "leaveAck",
+        }
+    }
+
+    fn version() -> Version {
+        Version {
+            wall: 1,
+            logical: 2,
+            writer: String::from("node-1"),
+        }
+    }
+
+    fn context() -> VectorClock {
+        let mut context = VectorClock::default();
+        context.set("node-1", 3);
+        context
+    }
+
+    /// Returns a sample of every message, and of every reply a client can be sent
+    fn samples() -> Vec<Message> {
+        let (a, b) = ("node-1", "node-2");
+        let (id, min, max) = (Id::from(7), Id::from(3), Id::from(9));
+        let record = || Record::new(String::from("1"), version());
+        let request = || RequestId::new(a, 4);
+        vec![
+            Hello {}.into(),
+            Set {
+                id: 1,
+                key: String::from("X"),
+                value: String::from("1"),
+                w: Some(2),
+                context: Some(context()),
+            }
+            .into(),
+            Get {
+                id: 1,
+                key: String::from("X"),
+                r: Some(2),
+            }
+            .into(),
+            Delete {
+                id: 1,
+                key: String::from("X"),
+                w: None,
+                context: None,
+            }
+            .into(),
+            Leave { id: Some(1) }.into(),
+            HelloResponse::new(a).into(),
+            SetResponse::new(1, "X", "1").into(),
+            SetHintedResponse::new(1, "X", "1").into(),
+            SetFailResponse::new(1, "X").into(),
+            GetSuccessResponse::new(1, "X", "1").into(),
+            GetSiblingsResponse::new(1, "X", vec![String::from("1"), String::from("2")], context())
+                .into(),
+            GetFailResponse::new(1, "X").into(),
+            DeleteResponse::new(1, "X").into(),
+            DeleteHintedResponse::new(1, "X").into(),
+            DeleteFailResponse::new(1, "X").into(),
+            LeaveResponse::new(1, a).into(),
+            LeaveFailResponse::no_successor(1).into(),
+            Join::new(a, b, id).into(),
+            JoinAck::new(a, b).into(),
+            JoinError::new(a, b, id, "node-3").into(),
+            FindSucc::new(a, b, id, Some(1)).into(),
+            FindSuccResponse::new(a, b, "node-3", id, min, None).into(),
+            GetPred::new(a, b).into(),
+            GetPredResponse::new(a, b, Some(id), Some(String::from("node-3"))).into(),
+            Notify::new(a, b, id, true).into(),
+            Retrieve::new(a, b, "X", 1, 2).into(),
+            RetrieveResponse::new(a, b, "X", 1, Some(record())).into(),
+            ReadReplica::new(a, b, id, "X", request()).into(),
+            ReadReplicaResponse::new(a, b, "X", request(), None).into(),
+            ReadRepair::new(a, b, id, "X", record()).into(),
+            Store::new(a, b, "X", "1", 1, 2, version(), Some(context())).into(),
+            Remove::new(a, b, "X", 1, 2, version(), None).into(),
+            StoreAck::new(a, b, 1, "X", version()).into(),
+            StoreFailed::new(a, b, 1, "X", version(), "disk full").into(),
+            TransferRequest::new(a, b, min, max).into(),
+            TransferKeys::new(a, b, vec![String::from("X")], vec![record()]).into(),
+            Duplicate::new(a, b, id, vec![String::from("X")], vec![record()], 5).into(),
+            Replicate::new(
+                a,
+                b,
+                id,
+                5,
+                vec![String::from("X"), String::from("Y")],
+                vec![Some(Record::tombstone(version())), None],
+                Some(request()),
+            )
+            .into(),
+            ResyncRequest::new(a, b).into(),
+            MerkleHashes::new(a, b, id, min, 1, vec![0, 1], vec![min, max]).into(),
+            MerkleRequest::new(a, b, min, 1, vec![1]).into(),
+            RepairRequest::new(a, b, min, vec![3]).into(),
+            Repair::new(a, b, id, min, vec![3], vec![String::from("X")], vec![record()]).into(),
+            DuplicateAck::new(a, b, id, vec![String::from("X")], None).into(),
+            Ping::new(a, b).into(),
+            Pong::new(a, b).into(),
+            PingReq::new(a, b, "node-3").into(),
+            PingAck::new(a, b, "node-3").into(),
+            PingSelf::new(a).into(),
+            PongSelf::new(a).into(),
+            Rejoin::new(a, b).into(),
+            RejoinAck::new(a, b).into(),
+            LeaveKeys::new(a, b, vec![String::from("X")], vec![record()], None, None).into(),
+            LeaveNotice::new(a, b, id, "node-3").into(),
+            LeaveAck::new(a, b).into(),
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips_under_its_wire_type() {
+        let samples = samples();
+        let covered: HashSet<&str> = samples.iter().map(wire_type).collect();
+        assert_eq!(covered.len(), VARIANTS);
+        for msg in samples {
+            let json = serde_json::to_value(&msg).unwrap();
+            assert_eq!(json["type"], wire_type(&msg), "{}", json);
+            assert_eq!(type_name(&msg), wire_type(&msg));
+            // Parsing gives back the same message, and the same reply among those sharing a type
+            let (_, parsed) = parse(&json.to_string()).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn client_messages_keep_the_format_chistributed_expects() {
+        let cases: Vec<(Message, serde_json::Value)> = vec![
+            (
+                HelloResponse::new("node-1").into(),
+                serde_json::json!({"type": "helloResponse", "source": "node-1"}),
+            ),
+            (
+                SetResponse::new(1, "X", "1").into(),
+                serde_json::json!({"type": "setResponse", "id": 1, "key": "X", "value": "1"}),
+            ),
+            (
+                SetFailResponse::new(1, "X").into(),
+                serde_json::json!({
+                    "type": "setResponse",
+                    "id": 1,
+                    "error": "Timed out storing key: X"
+                }),
+            ),
+            (
+                GetSuccessResponse::new(1, "X", "1").into(),
+                serde_json::json!({"type": "getResponse", "id": 1, "key": "X", "value": "1"}),
+            ),
+            (
+                GetFailResponse::new(1, "X").into(),
+                serde_json::json!({"type": "getResponse", "id": 1, "error": "No such key: X"}),
+            ),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(serde_json::to_value(&msg).unwrap(), expected);
+        }
+        for request in [
+            r#"{"type": "hello"}"#,
+            r#"{"type": "set", "id": 1, "key": "X", "value": "1"}"#,
+            r#"{"type": "get", "id": 1, "key": "X"}"#,
+        ]
+        .iter()
+        {
+            assert!(parse(request).is_ok(), "{}", request);
+        }
+    }
+
+    #[test]
+    fn parse_restores_virtual_node_names() {
+        let (destination, msg) = parse(
+            r#"{"type": "ping", "source": "node-2", "source_vnode": "node-2:1",
+                "destination": "node-1", "destination_vnode": "node-1:3"}"#,
+        )
+        .unwrap();
+        assert_eq!(destination.as_deref(), Some("node-1:3"));
+        match msg {
+            Message::Ping(ping) => assert_eq!(ping.source, "node-2:1"),
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed_messages() {
+        assert!(parse("not json").is_err());
+        assert!(parse(r#"["set"]"#).is_err());
+        assert!(parse(r#"{"id": 1, "key": "X"}"#).is_err());
+        assert!(parse(r#"{"type": "frobnicate", "id": 1}"#).is_err());
+        assert!(parse(r#"{"type": "set", "id": 1, "key": "X"}"#).is_err());
+        assert!(parse(r#"{"type": "set", "id": "1", "key": "X", "value": "1"}"#).is_err());
+        assert!(parse(r#"{"type": "findSucc", "source": "node-2", "destination": "node-1"}"#)
+            .is_err());
+    }
+
+    /// Returns the error a reply carries, with its type
+    fn error_of(reply: Option<Message>) -> Option<(String, String)> {
+        let json = serde_json::to_value(reply?).unwrap();
+        Some((
+            json["type"].as_str()?.to_string(),
+            json["error"].as_str()?.to_string(),
+        ))
+    }
+
+    #[test]
+    fn error_reply_answers_client_requests_with_an_id() {
+        for (kind, reply) in [
+            ("set", "setResponse"),
+            ("get", "getResponse"),
+            ("delete", "deleteResponse"),
+            ("leave", "leaveResponse"),
+        ]
+        .iter()
+        {
+            let contents = format!(r#"{{"type": "{}", "id": 7}}"#, kind);
+            let (reply_kind, error) = error_of(error_reply(&contents, "missing field")).unwrap();
+            assert_eq!(reply_kind, *reply);
+            assert_eq!(error, "Malformed request: missing field");
+        }
+    }
+
+    #[test]
+    fn error_reply_is_none_without_a_client_request_to_answer() {
+        assert!(error_reply("not json", "").is_none());
+        assert!(error_reply(r#"{"type": "set", "key": "X"}"#, "").is_none());
+        assert!(error_reply(r#"{"type": "set", "id": "7"}"#, "").is_none());
+        assert!(error_reply(r#"{"type": "set", "id": 4294967296}"#, "").is_none());
+        assert!(error_reply(r#"{"type": "findSucc", "id": 7}"#, "").is_none());
+        assert!(error_reply(r#"{"id": 7}"#, "").is_none());
+    }
+}
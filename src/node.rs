@@ -3,23 +3,61 @@
 // The purpose of this file is to perform all read and write operations
 // of the local storage of the node.
 
-use crate::handler::M;
+use crate::clock::{Dot, VectorClock, Version};
 use crate::hash;
-use std::collections::HashMap;
+use crate::merkle::MerkleTree;
+use crate::storage::{Record, SharedStorage};
+use hash::Id;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+// Separates a process name from the index of one of its virtual nodes
+pub const VNODE_SEPARATOR: char = ':';
+
+/// Returns the name of a process's i-th virtual node
+/// The first virtual node keeps the process name so a single virtual node behaves as before
+///
+/// # Arguments
+///
+/// * `node_name` - the process's name
+/// * `i` - index of the virtual node
+pub fn vnode_name(node_name: &str, i: usize) -> String {
+  if i == 0 {
+    String::from(node_name)
+  } else {
+    format!("{}{}{}", node_name, VNODE_SEPARATOR, i)
+  }
+}
+
+/// Returns the name of the process that owns a virtual node
+///
+/// # Arguments
+///
+/// * `vnode_name` - the virtual node's name
+pub fn physical_name(vnode_name: &str) -> &str {
+  vnode_name
+    .split(VNODE_SEPARATOR)
+    .next()
+    .unwrap_or(vnode_name)
+}
 
 /// Contains all local node storage, including node metadata, finger table,
-/// successor list, key/value store, replica key/value stories, pending queries,
-/// and the last failed successor
+/// successor list, shared key/value storage, pending queries,
+/// the last failed successor, the replicas that have seen each deletion,
+/// and the sequence number of the last replication update sent to replica holders
 pub struct Node {
+  m: u32,
   id: NodeEntry,
   finger_table: Vec<FingerEntry>,
   successor: NodeEntry,
   predecessor: Option<NodeEntry>,
   successor_list: Vec<SuccessorEntry>,
-  store: HashMap<String, String>,
-  replica_store: HashMap<i32, HashMap<String, String>>,
-  current_queries: HashMap<i32, QueryType>,
+  replicas: usize,
+  storage: SharedStorage,
+  current_queries: HashMap<(Id, Option<i32>), QueryType>,
   last_failed_successor: Option<SuccessorEntry>,
+  tombstones: HashMap<String, HashSet<String>>,
+  replication_seq: u64,
 }
 
 impl Node {
@@ -27,18 +65,27 @@ impl Node {
   ///
   /// # Arguments
   ///
-  /// * `m` - The number of entries in the finger table
+  /// * `m` - The width of the identifier space (and number of entries in the finger table)
   /// * `node_name` - The node's name
   /// * `id` - The hashed key of the node
   /// * `tau` - The number of entries in the successor list
-  pub fn new(m: i32, node_name: &str, id: i32, tau: i32) -> Node {
+  /// * `replicas` - The number of successors that hold a replica of the node's data
+  /// * `storage` - The key/value storage shared with the process's other virtual nodes
+  pub fn new(
+    m: u32,
+    node_name: &str,
+    id: Id,
+    tau: i32,
+    replicas: usize,
+    storage: SharedStorage,
+  ) -> Node {
     let node: NodeEntry = NodeEntry::new(id, node_name);
     let mut new_finger_table = Vec::new();
     let mut new_successor_list = Vec::new();
 
     // initialize finger table
     for i in 1..(m + 1) {
-      let start: i32 = (id + i32::pow(2, (i - 1) as u32)) % i32::pow(2, m as u32);
+      let start = id.add_pow2(i - 1, m);
       new_finger_table.push(FingerEntry::new(start, id, node_name));
     }
 
@@ -49,25 +96,28 @@ impl Node {
 
     // initialize as if node is the only node in the ring
     Node {
+      m,
       id: node,
       finger_table: new_finger_table,
       successor: NodeEntry::new(id, node_name),
       predecessor: Some(NodeEntry::new(id, node_name)),
       successor_list: new_successor_list,
-      store: HashMap::new(),
-      replica_store: HashMap::new(),
+      replicas,
+      storage,
       current_queries: HashMap::new(),
       last_failed_successor: None,
+      tombstones: HashMap::new(),
+      replication_seq: 0,
     }
   }
 
-  /// Returns the data for a given key, if it exists in the local store
+  /// Returns the record for a given key, including every sibling, if it exists in the local store
   ///
   /// # Arguments
   ///
   /// * `key` - the key being queried
-  pub fn get(&self, key: &str) -> Option<&String> {
-    self.store.get(key)
+  pub fn get(&self, key: &str) -> Option<Record> {
+    self.storage.read().get(key)
   }
 
   /// Sets the data for a given key in the local store
@@ -76,8 +126,79 @@ impl Node {
   ///
   /// * `key` - the key being stored
   /// * `value` - the value being stored
-  pub fn set(&mut self, key: String, value: String) {
-    self.store.insert(key, value);
+  /// * `version` - the version of the write
+  pub fn set(&mut self, key: String, value: String, version: Version) -> io::Result<()> {
+    self.merge(key, Record::new(value, version)).map(|_| ())
+  }
+
+  /// Deletes a key from the local store by leaving a tombstone in its place
+  /// The tombstone is dropped once every replica holder has seen it
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the key being deleted
+  /// * `version` - the version of the deletion
+  pub fn delete(&mut self, key: String, version: Version) -> io::Result<()> {
+    self.merge(key, Record::tombstone(version)).map(|_| ())
+  }
+
+  /// Writes or deletes a key under vector-clock versioning
+  /// The write replaces every sibling its context has seen and is kept alongside the rest,
+  /// so a write without a context is concurrent with every sibling already stored
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the key being written
+  /// * `value` - the value being stored, or None to delete the key
+  /// * `version` - the version of the write
+  /// * `context` - the causal context the client read before writing
+  pub fn set_causal(
+    &mut self,
+    key: String,
+    value: Option<String>,
+    version: Version,
+    context: VectorClock,
+  ) -> io::Result<()> {
+    // This node's count must exceed every count it has given out for the key
+    let writer = physical_name(&self.id.node_name);
+    let mut count = context.get(writer);
+    if let Some(existing) = self.storage.read().get(&key) {
+      count = count.max(existing.context().get(writer));
+    }
+    let dot = Dot {
+      node_name: String::from(writer),
+      count: count + 1,
+    };
+    self
+      .merge(key, Record::causal(value, version, context, dot))
+      .map(|_| ())
+  }
+
+  /// Stores a record, which may be a tombstone, merged with the version already
+  /// held locally (see Record::merge)
+  /// Returns whether the local store changed, or why the record could not be stored
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the key being stored
+  /// * `record` - the value or tombstone being stored
+  pub fn merge(&mut self, key: String, record: Record) -> io::Result<bool> {
+    let mut storage = self.storage.write();
+    let record = match storage.get(&key) {
+      Some(existing) => match existing.merge(&record) {
+        Some(merged) => merged,
+        None => return Ok(false),
+      },
+      None => record,
+    };
+    let tombstone = record.is_tombstone();
+    storage.set(key.clone(), record)?;
+    if tombstone {
+      self.tombstones.insert(key, HashSet::new());
+    } else {
+      self.tombstones.remove(&key);
+    }
+    Ok(true)
   }
 
   /// Returns the node's successor
@@ -99,10 +220,10 @@ impl Node {
 
   /// Returns the node's predecessor, if it exists
   pub fn get_predecessor(&self) -> Option<NodeEntry> {
-    match &self.predecessor {
-      Some(pred) => Some(NodeEntry::new(pred.id, &pred.node_name)),
-      None => None,
-    }
+    self
+      .predecessor
+      .as_ref()
+      .map(|pred| NodeEntry::new(pred.id, &pred.node_name))
   }
 
   /// Sets the node's predecessor
@@ -141,10 +262,57 @@ impl Node {
   }
 
   /// Get the node's hashed id
-  pub fn get_id(&self) -> i32 {
+  pub fn get_id(&self) -> Id {
     self.id.id
   }
 
+  /// Get the node's name
+  pub fn get_name(&self) -> &str {
+    &self.id.node_name
+  }
+
+  /// Get the width of the identifier space
+  pub fn ring_bits(&self) -> u32 {
+    self.m
+  }
+
+  /// Returns a known node other than `node_name` that already owns the given id,
+  /// if one exists in the node's view of the ring
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - the id being checked
+  /// * `node_name` - the node that wants to own the id
+  pub fn find_id_owner(&self, id: Id, node_name: &str) -> Option<NodeEntry> {
+    let mut known = vec![&self.id, &self.successor];
+    if let Some(pred) = &self.predecessor {
+      known.push(pred);
+    }
+    known.extend(self.successor_list.iter().map(|s| &s.node));
+    known.extend(self.finger_table.iter().map(|f| &f.node));
+    known
+      .into_iter()
+      .find(|n| n.id == id && n.node_name != node_name)
+      .map(|n| NodeEntry::new(n.id, &n.node_name))
+  }
+
+  /// Moves the node to a new position on the ring after an id collision
+  /// Resets the ring state as if the node were alone, but keeps its data
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - the node's new hashed id
+  pub fn rekey(&mut self, id: Id) {
+    *self = Node::new(
+      self.m,
+      &self.id.node_name,
+      id,
+      self.successor_list.len() as i32,
+      self.replicas,
+      self.storage.clone(),
+    );
+  }
+
   /// Find the predecessor of a given id
   /// If it is not the current node, find the closest preceding finger
   /// to route the query to
@@ -152,7 +320,7 @@ impl Node {
   /// # Arguments
   ///
   /// * `id` - key being queried
-  pub fn find_predecessor(&self, id: i32) -> (bool, NodeEntry) {
+  pub fn find_predecessor(&self, id: Id) -> (bool, NodeEntry) {
     if hash::in_range(id, self.id.id, self.successor.id, true) {
       (true, NodeEntry::new(self.id.id, &self.id.node_name))
     } else {
@@ -161,13 +329,16 @@ impl Node {
   }
 
   /// Pushes a new query to a queue of outstanding queries
+  /// Queries are told apart by their id as well as their key, since concurrent
+  /// client requests can query the same key
   ///
   /// # Arguments
   ///
   /// * `query` - key being queried
+  /// * `id` - id passed with the query's response, if any
   /// * `type` - the type of query being performed
-  pub fn push_query(&mut self, query: i32, r#type: QueryType) {
-    self.current_queries.insert(query, r#type);
+  pub fn push_query(&mut self, query: Id, id: Option<i32>, r#type: QueryType) {
+    self.current_queries.insert((query, id), r#type);
   }
 
   /// Retrieves and removes an outstanding query from the queue,
@@ -176,8 +347,9 @@ impl Node {
   /// # Arguments
   ///
   /// * `query` - key being queried
-  pub fn pop_query(&mut self, query: i32) -> Option<QueryType> {
-    self.current_queries.remove(&query)
+  /// * `id` - id passed with the query's response, if any
+  pub fn pop_query(&mut self, query: Id, id: Option<i32>) -> Option<QueryType> {
+    self.current_queries.remove(&(query, id))
   }
 
   /// Sets the successor's predecessor as a new successor if its key is closer
@@ -186,7 +358,7 @@ impl Node {
   ///
   /// * `pred_id` - the successor's predecessor (id)
   /// * `pred_name` - the successor's predecessor (name)
-  pub fn stabilize_successor(&mut self, pred_id: i32, pred_name: &str) {
+  pub fn stabilize_successor(&mut self, pred_id: Id, pred_name: &str) {
     if hash::in_range(pred_id, self.id.id, self.successor.id, false) {
       self.set_successor(NodeEntry::new(pred_id, pred_name))
     }
@@ -206,24 +378,24 @@ impl Node {
   /// * `node_name` - the node suggesting it may be the predecessor (name)
   pub fn stabilize_predecessor(
     &mut self,
-    node_id: i32,
+    node_id: Id,
     node_name: &str,
     failed: bool,
-  ) -> TransferType {
+  ) -> io::Result<TransferType> {
     match &self.predecessor {
       Some(pred) => {
         if failed {
           let id = pred.id;
-          self.transfer_from_replicas(node_id, id);
+          let keys = self.transfer_from_replicas(node_id, id)?;
           self.predecessor = Some(NodeEntry::new(node_id, node_name));
-          TransferType::Duplicate
+          Ok(TransferType::Replicate(keys))
         } else if hash::in_range(node_id, pred.id, self.id.id, false) {
-          self.set_predecessor(Some(NodeEntry::new(node_id, node_name)))
+          Ok(self.set_predecessor(Some(NodeEntry::new(node_id, node_name))))
         } else {
-          TransferType::Nothing
+          Ok(TransferType::Nothing)
         }
       }
-      None => self.set_predecessor(Some(NodeEntry::new(node_id, node_name))),
+      None => Ok(self.set_predecessor(Some(NodeEntry::new(node_id, node_name)))),
     }
   }
 
@@ -232,7 +404,7 @@ impl Node {
   /// # Arguments
   ///
   /// * `i` - index in finger table
-  pub fn get_finger_start(&self, i: i32) -> i32 {
+  pub fn get_finger_start(&self, i: u32) -> Id {
     self.finger_table[i as usize].start
   }
 
@@ -242,34 +414,44 @@ impl Node {
   ///
   /// * `i` - index in finger table
   /// * `node` - new finger table entry
-  pub fn set_finger(&mut self, i: i32, node: NodeEntry) {
+  pub fn set_finger(&mut self, i: u32, node: NodeEntry) {
     self.finger_table[i as usize].node = node;
   }
 
-  /// Remove and transfer local keys/values from a given range of keys
+  /// Returns the local keys/values in a given range of keys, to be transferred to another node
   /// Returns keys and values as seperate vectors
+  /// The keys stay in the local store until remove_transferred is called once they are sent
   ///
   /// # Arguments
   ///
   /// * `min` - lower bound of keys to be transferred
   /// * `max` - upper bound of keys to be transferred
-  pub fn transfer_kvs_range(&mut self, min: i32, max: i32) -> (Vec<String>, Vec<String>) {
-    let mut keys = Vec::new();
-    let mut values = Vec::new();
-    for k in self
-      .store
-      .keys()
-      .filter(|key| hash::in_range(hash::hash(key), min, max, true))
-    {
-      keys.push(String::from(k));
-    }
-    for k in &keys {
-      if let Some(v) = self.store.remove(k) {
-        values.push(v);
-      }
-    }
+  pub fn transfer_kvs_range(&self, min: Id, max: Id) -> (Vec<String>, Vec<Record>) {
+    let m = self.m;
+    self
+      .storage
+      .read()
+      .entries()
+      .into_iter()
+      .filter(|(key, _)| hash::in_range(hash::hash(key, m), min, max, true))
+      .unzip()
+  }
 
-    (keys, values)
+  /// Removes keys that were transferred to another node from the local store
+  /// A key that cannot be removed stays behind as a stale copy, which the new owner's
+  /// newer versions supersede, and the first such failure is returned
+  ///
+  /// # Arguments
+  ///
+  /// * `keys` - the keys that were transferred
+  pub fn remove_transferred(&mut self, keys: &[String]) -> io::Result<()> {
+    let mut storage = self.storage.write();
+    for k in keys {
+      storage.remove(k)?;
+      // the new owner keeps tracking the tombstone
+      self.tombstones.remove(k);
+    }
+    Ok(())
   }
 
   /// Get the indices of all live successors in the successor list
@@ -284,12 +466,17 @@ impl Node {
     indexes
   }
 
+  /// Returns the length of the successor list
+  pub fn successor_count(&self) -> usize {
+    self.successor_list.len()
+  }
+
   /// Returns the successor's id at a given index in the successor list
   ///
   /// # Arguments
   ///
   /// * `i` - index in the successor list
-  pub fn successor_at_index(&self, i: usize) -> i32 {
+  pub fn successor_at_index(&self, i: usize) -> Id {
     self.successor_list[i].node.id
   }
 
@@ -301,7 +488,7 @@ impl Node {
   /// * `i` - index in the successor list
   /// * `succ` - new successor entry
   pub fn fix_successor(&mut self, i: i32, succ: NodeEntry) -> bool {
-    let old_succ = (&self.successor_list[i as usize].node.node_name).to_string();
+    let old_succ = self.successor_list[i as usize].node.node_name.to_string();
     let was_failed = self.successor_list[i as usize].failed;
     self.successor_list[i as usize].node = succ;
     self.successor_list[i as usize].failed = false;
@@ -310,14 +497,25 @@ impl Node {
       && self.successor_list[i as usize].node.node_name != self.id.node_name
   }
 
-  /// Set a new key/value store replica for a given node's id
+  /// Set a new key/value store replica for a given node's id from a full duplicate
+  /// Keys the replica already holds are merged with the incoming version
   ///
   /// # Arguments
   ///
   /// * `id` - replicated node's id
   /// * `kvs` - replicated node's key/value store
-  pub fn set_for_replica(&mut self, id: i32, kvs: HashMap<String, String>) {
-    self.replica_store.insert(id, kvs);
+  pub fn set_for_replica(&mut self, id: Id, mut kvs: HashMap<String, Record>) -> io::Result<()> {
+    let mut storage = self.storage.write();
+    if let Some(old) = storage.replica(id) {
+      for (k, record) in kvs.iter_mut() {
+        if let Some(old_record) = old.get(k) {
+          *record = old_record
+            .merge(record)
+            .unwrap_or_else(|| old_record.clone());
+        }
+      }
+    }
+    storage.set_replica(id, kvs)
   }
 
   /// Get the live successors from the successor list
@@ -330,15 +528,293 @@ impl Node {
     successors
   }
 
-  /// Duplicates current node's store
-  /// Returns a tuple of keys and values
-  pub fn duplicate_store(&self) -> (Vec<String>, Vec<String>) {
+  /// Get every node this node knows of: its live successors, fingers and predecessor
+  /// Returns a vector of nodes, which may repeat
+  pub fn known_nodes(&self) -> Vec<NodeEntry> {
+    let fingers = self
+      .finger_table
+      .iter()
+      .map(|f| NodeEntry::new(f.node.id, &f.node.node_name));
+    self
+      .live_successors()
+      .into_iter()
+      .chain(fingers)
+      .chain(self.get_predecessor())
+      .collect()
+  }
+
+  /// Get the nodes that can ping a suspected neighbour on this node's behalf:
+  /// live successors then fingers, on distinct processes other than this node's and the suspect's
+  /// Returns at most `count` nodes
+  ///
+  /// # Arguments
+  ///
+  /// * `suspect` - name of the suspected successor or predecessor
+  /// * `count` - number of nodes wanted
+  pub fn probe_helpers(&self, suspect: &str, count: usize) -> Vec<NodeEntry> {
+    let mut helpers: Vec<NodeEntry> = Vec::new();
+    let fingers = self
+      .finger_table
+      .iter()
+      .map(|f| NodeEntry::new(f.node.id, &f.node.node_name));
+    for candidate in self.live_successors().into_iter().chain(fingers) {
+      if helpers.len() == count {
+        break;
+      }
+      let process = physical_name(&candidate.node_name);
+      if process != physical_name(&self.id.node_name)
+        && process != physical_name(suspect)
+        && helpers
+          .iter()
+          .all(|h| physical_name(&h.node_name) != process)
+      {
+        helpers.push(candidate);
+      }
+    }
+    helpers
+  }
+
+  /// Get the live successors that hold this node's replicas: the first ones on
+  /// distinct processes, up to the number of replicas, skipping the process's own
+  /// virtual nodes since they share its storage
+  /// Returns a vector of successor nodes
+  pub fn replica_holders(&self) -> Vec<NodeEntry> {
+    let mut holders: Vec<NodeEntry> = Vec::new();
+    for succ in self.live_successors() {
+      if holders.len() == self.replicas {
+        break;
+      }
+      if physical_name(&succ.node_name) != physical_name(&self.id.node_name)
+        && holders
+          .iter()
+          .all(|h| physical_name(&h.node_name) != physical_name(&succ.node_name))
+      {
+        holders.push(succ);
+      }
+    }
+    holders
+  }
+
+  /// Returns a key's record from the replica held for a given node's id, if it exists
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - replicated node's id
+  /// * `key` - the key being queried
+  pub fn get_replica(&self, id: Id, key: &str) -> Option<Record> {
+    self.storage.read().replica_entry(id, key)
+  }
+
+  /// Applies a replication update from the node with a given id to its replica
+  /// Keys without a record are no longer held by that node and are dropped
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - replicated node's id
+  /// * `keys` - the updated keys
+  /// * `records` - the updated records, or None for dropped keys
+  pub fn update_replica(
+    &mut self,
+    id: Id,
+    keys: Vec<String>,
+    records: Vec<Option<Record>>,
+  ) -> io::Result<()> {
+    let mut storage = self.storage.write();
+    for (k, record) in keys.into_iter().zip(records) {
+      let record = match (storage.replica_entry(id, &k), record) {
+        (Some(existing), Some(record)) => match existing.merge(&record) {
+          Some(merged) => Some(merged),
+          None => continue,
+        },
+        (_, record) => record,
+      };
+      storage.set_replica_entry(id, k, record)?;
+    }
+    Ok(())
+  }
+
+  /// Returns the records of the given keys for a replication update, or None for
+  /// keys the node no longer holds, and the update's sequence number
+  ///
+  /// # Arguments
+  ///
+  /// * `keys` - the keys that changed
+  pub fn replication_update(&mut self, keys: &[String]) -> (Vec<Option<Record>>, u64) {
+    self.replication_seq += 1;
+    let storage = self.storage.read();
     (
-      self.store.keys().map(|k| k.to_string()).collect(),
-      self.store.values().map(|v| v.to_string()).collect(),
+      keys.iter().map(|k| storage.get(k)).collect(),
+      self.replication_seq,
     )
   }
 
+  /// Returns the sequence number of the last replication update
+  /// A full duplicate of the store brings a replica up to this sequence number
+  pub fn replication_seq(&self) -> u64 {
+    self.replication_seq
+  }
+
+  /// Records that a replica holder has stored the given tombstones
+  ///
+  /// # Arguments
+  ///
+  /// * `holder` - the replica holder's name
+  /// * `keys` - the deleted keys it has seen
+  pub fn ack_tombstones(&mut self, holder: &str, keys: Vec<String>) {
+    for k in keys {
+      if let Some(seen) = self.tombstones.get_mut(&k) {
+        seen.insert(String::from(holder));
+      }
+    }
+  }
+
+  /// Drops tombstones that every current replica holder has seen,
+  /// since no replica is left that could bring the deleted value back
+  /// Returns the keys that were dropped
+  /// A tombstone that cannot be dropped is kept, and collected again later
+  pub fn collect_tombstones(&mut self) -> io::Result<Vec<String>> {
+    let holders = self.replica_holders();
+    let collected: Vec<String> = self
+      .tombstones
+      .iter()
+      .filter(|(_, seen)| holders.iter().all(|h| seen.contains(&h.node_name)))
+      .map(|(k, _)| k.to_string())
+      .collect();
+
+    let mut storage = self.storage.write();
+    for k in collected.iter() {
+      if storage.get(k).is_some_and(|r| r.is_tombstone()) {
+        storage.remove(k)?;
+      }
+      self.tombstones.remove(k);
+    }
+    Ok(collected)
+  }
+
+  /// Returns whether a deletion of a key is still waiting for replica holders to see it
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the deleted key
+  #[cfg(test)]
+  pub fn tracks_tombstone(&self, key: &str) -> bool {
+    self.tombstones.contains_key(key)
+  }
+
+  /// Returns a key's record from every replica this node's store holds
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - the key being queried
+  #[cfg(test)]
+  pub fn replica_records(&self, key: &str) -> Vec<Record> {
+    let storage = self.storage.read();
+    storage
+      .replica_ids()
+      .into_iter()
+      .filter_map(|id| storage.replica_entry(id, key))
+      .collect()
+  }
+
+  /// Duplicates the keys this node owns, (predecessor, node], from the shared store
+  /// Returns a tuple of keys and values, where deleted keys have tombstones as values
+  pub fn duplicate_store(&self) -> (Vec<String>, Vec<Record>) {
+    let min = match &self.predecessor {
+      Some(pred) => pred.id,
+      None => self.id.id,
+    };
+    self
+      .storage
+      .read()
+      .entries()
+      .into_iter()
+      .filter(|(k, _)| hash::in_range(hash::hash(k, self.m), min, self.id.id, true))
+      .unzip()
+  }
+
+  /// Returns a Merkle tree over the keys this node holds in the range (min, node]
+  ///
+  /// # Arguments
+  ///
+  /// * `min` - the id right before the range, the start of the range the node owns
+  pub fn merkle_tree(&self, min: Id) -> MerkleTree {
+    let entries = self.storage.read().entries();
+    MerkleTree::new(entries, min, self.id.id, self.m)
+  }
+
+  /// Returns a Merkle tree over the replica held for a given node's id, in the range (min, id]
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - replicated node's id
+  /// * `min` - the id right before the range, the start of the range the replicated node owns
+  pub fn replica_merkle_tree(&self, id: Id, min: Id) -> MerkleTree {
+    let kvs = self.storage.read().replica(id).unwrap_or_default();
+    MerkleTree::new(kvs.into_iter().collect(), min, id, self.m)
+  }
+
+  /// Returns the keys this node holds that fall in the given leaf ranges of its Merkle tree
+  /// over the range (min, node]
+  /// Returns a tuple of keys and values, where deleted keys have tombstones as values
+  ///
+  /// # Arguments
+  ///
+  /// * `min` - the id right before the range the tree is over
+  /// * `leaves` - the leaf ranges
+  pub fn leaf_entries(&self, min: Id, leaves: &[usize]) -> (Vec<String>, Vec<Record>) {
+    let max = self.id.id;
+    self
+      .storage
+      .read()
+      .entries()
+      .into_iter()
+      .filter(|(k, _)| {
+        hash::in_range(hash::hash(k, self.m), min, max, true)
+          && leaves.contains(&MerkleTree::leaf(k, min, max, self.m))
+      })
+      .unzip()
+  }
+
+  /// Replaces the keys in the given leaf ranges of the replica held for a given node's id
+  /// with the ones that node holds, dropping keys it no longer holds
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - replicated node's id
+  /// * `min` - the id right before the range the replicated node's tree is over
+  /// * `leaves` - the leaf ranges being repaired
+  /// * `keys` - the keys the replicated node holds in those ranges
+  /// * `values` - the values of those keys, or tombstones for deleted keys
+  pub fn repair_replica(
+    &mut self,
+    id: Id,
+    min: Id,
+    leaves: &[usize],
+    keys: Vec<String>,
+    values: Vec<Record>,
+  ) -> io::Result<()> {
+    let mut storage = self.storage.write();
+    let stale: Vec<String> = storage
+      .replica(id)
+      .unwrap_or_default()
+      .into_keys()
+      .filter(|k| {
+        hash::in_range(hash::hash(k, self.m), min, id, true)
+          && leaves.contains(&MerkleTree::leaf(k, min, id, self.m))
+          && !keys.contains(k)
+      })
+      .collect();
+    for k in stale {
+      storage.set_replica_entry(id, k, None)?;
+    }
+    for (k, record) in keys.into_iter().zip(values) {
+      if storage.replica_entry(id, &k).as_ref() != Some(&record) {
+        storage.set_replica_entry(id, k, Some(record))?;
+      }
+    }
+    Ok(())
+  }
+
   /// Handles the failure of a successor
   /// Set the successor as the last failed successor,
   /// use the next successor list entry as the new successor,
@@ -354,7 +830,63 @@ impl Node {
     ));
   }
 
-  /// A debug helper function for displaying the node's successor list
+  /// Handles a process leaving the ring from right after this node
+  /// Drops the leaving process's virtual nodes from the successor list and finger table,
+  /// and takes the node that adopted their keys as successor if one of them was the successor
+  ///
+  /// # Arguments
+  ///
+  /// * `process` - name of the leaving process
+  /// * `succ` - the node that adopted the leaving process's keys
+  pub fn successor_left(&mut self, process: &str, succ: NodeEntry) {
+    let len = self.successor_list.len();
+    self
+      .successor_list
+      .retain(|s| physical_name(&s.node.node_name) != process);
+    while self.successor_list.len() < len {
+      self
+        .successor_list
+        .push(SuccessorEntry::new(self.id.id, &self.id.node_name));
+    }
+    for finger in self.finger_table.iter_mut() {
+      if physical_name(&finger.node.node_name) == process {
+        finger.node = NodeEntry::new(succ.id, &succ.node_name);
+      }
+    }
+    if physical_name(&self.successor.node_name) == process {
+      self.set_successor(succ);
+    }
+  }
+
+  /// Handles a process leaving the ring from right before this node after handing its keys over
+  /// Takes the leaving process's predecessor as predecessor without transferring any keys
+  ///
+  /// # Arguments
+  ///
+  /// * `process` - name of the leaving process
+  /// * `pred` - the leaving process's predecessor, if it had one
+  pub fn predecessor_left(&mut self, process: &str, pred: Option<NodeEntry>) {
+    let left = match &self.predecessor {
+      Some(old_pred) => physical_name(&old_pred.node_name) == process,
+      None => false,
+    };
+    if left {
+      self.predecessor = pred;
+    }
+  }
+
+  /// Handles the failure of the predecessor
+  /// Clears the predecessor until another node notifies this node,
+  /// and takes over the keys of the failed predecessor's replica
+  /// Returns the keys that changed in the local store
+  pub fn predecessor_failure(&mut self) -> io::Result<Vec<String>> {
+    match self.predecessor.take() {
+      Some(pred) => self.promote_replicas(|i| i == pred.id),
+      None => Ok(Vec::new()),
+    }
+  }
+
+  // A debug helper function for displaying the node's successor list
   // pub fn display_ring(&self) {
   //   println!("{}'s RING", self.id.node_name);
   //   for (i, s) in self.successor_list.iter().enumerate() {
@@ -364,11 +896,10 @@ impl Node {
 
   /// Returns the last failed successor, if it exists
   pub fn get_failed_successor(&self) -> Option<NodeEntry> {
-    if let Some(fail) = &self.last_failed_successor {
-      Some(NodeEntry::new(fail.node.id, &fail.node.node_name))
-    } else {
-      None
-    }
+    self
+      .last_failed_successor
+      .as_ref()
+      .map(|fail| NodeEntry::new(fail.node.id, &fail.node.node_name))
   }
 
   /// Resets the last failed successor to nil
@@ -377,17 +908,16 @@ impl Node {
   }
 
   /// Finds the closest preceding finger for a given key
+  /// Fingers pointing back at the node are skipped: for the node's own id the range
+  /// covers the whole ring, and routing to itself would never make progress
   ///
   /// # Arguments
   ///
   /// * `id` - the key being queried
-  fn closest_preceding_finger(&self, id: i32) -> NodeEntry {
-    for i in (0..M).rev() {
-      if hash::in_range(self.finger_table[i as usize].node.id, self.id.id, id, false) {
-        return NodeEntry::new(
-          self.finger_table[i as usize].node.id,
-          &self.finger_table[i as usize].node.node_name,
-        );
+  fn closest_preceding_finger(&self, id: Id) -> NodeEntry {
+    for finger in self.finger_table.iter().rev() {
+      if finger.node.id != self.id.id && hash::in_range(finger.node.id, self.id.id, id, false) {
+        return NodeEntry::new(finger.node.id, &finger.node.node_name);
       }
     }
     NodeEntry::new(self.id.id, &self.id.node_name)
@@ -395,27 +925,55 @@ impl Node {
 
   /// Transfer keys from stored replicas to the local store
   /// when a predecessor fails
+  /// Returns the keys that changed in the local store
   ///
   /// # Arguments
   ///
   /// * `min` - lower bound of keys to transfer
   /// * `max` - upper bound of keys to transfer
-  fn transfer_from_replicas(&mut self, min: i32, max: i32) {
-    for i in self.replica_store.keys().collect::<Vec<&i32>>() {
-      if hash::in_range(*i, min, max, true) {
-        if let Some(kvs) = self.replica_store.get(i) {
-          self
-            .store
-            .extend(kvs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+  fn transfer_from_replicas(&mut self, min: Id, max: Id) -> io::Result<Vec<String>> {
+    self.promote_replicas(|i| hash::in_range(i, min, max, true))
+  }
+
+  /// Transfer keys from the stored replicas of the nodes matching a filter to the local store
+  /// Returns the keys that changed in the local store
+  ///
+  /// # Arguments
+  ///
+  /// * `owned` - whether the replica of a node, given its id, is taken over
+  fn promote_replicas<F: Fn(Id) -> bool>(&mut self, owned: F) -> io::Result<Vec<String>> {
+    let mut transferred = Vec::new();
+    let mut storage = self.storage.write();
+    for i in storage.replica_ids() {
+      if owned(i) {
+        if let Some(kvs) = storage.replica(i) {
+          for (k, v) in kvs {
+            let v = match storage.get(&k) {
+              Some(existing) => match existing.merge(&v) {
+                Some(merged) => merged,
+                None => continue,
+              },
+              None => v,
+            };
+            let tombstone = v.is_tombstone();
+            storage.set(k.clone(), v)?;
+            if tombstone {
+              self.tombstones.insert(k.clone(), HashSet::new());
+            } else {
+              self.tombstones.remove(&k);
+            }
+            transferred.push(k);
+          }
         }
       }
     }
+    Ok(transferred)
   }
 }
 
 /// Finger data for the node's finger table
 struct FingerEntry {
-  start: i32,
+  start: Id,
   node: NodeEntry,
 }
 
@@ -427,7 +985,7 @@ impl FingerEntry {
   /// * `start` - start of the key range that the finger is the successor of
   /// * `id` - the node that contains the requested keys
   /// * `node_name` - the minimum key to be transferred
-  fn new(start: i32, id: i32, node_name: &str) -> FingerEntry {
+  fn new(start: Id, id: Id, node_name: &str) -> FingerEntry {
     FingerEntry {
       start,
       node: NodeEntry::new(id, node_name),
@@ -437,7 +995,7 @@ impl FingerEntry {
 
 /// Node metadata
 pub struct NodeEntry {
-  pub id: i32,
+  pub id: Id,
   pub node_name: String,
 }
 
@@ -448,7 +1006,7 @@ impl NodeEntry {
   ///
   /// * `id` - the hashed key of the node's name
   /// * `node_name` - the node's name
-  pub fn new(id: i32, node_name: &str) -> NodeEntry {
+  pub fn new(id: Id, node_name: &str) -> NodeEntry {
     NodeEntry {
       id,
       node_name: String::from(node_name),
@@ -469,7 +1027,7 @@ impl SuccessorEntry {
   ///
   /// * `id` - the hashed key of the successor's name
   /// * `node_name` - the successor's name
-  fn new(id: i32, node_name: &str) -> SuccessorEntry {
+  fn new(id: Id, node_name: &str) -> SuccessorEntry {
     SuccessorEntry {
       node: NodeEntry::new(id, node_name),
       failed: true,
@@ -481,16 +1039,192 @@ impl SuccessorEntry {
 pub enum QueryType {
   JoinAck,
   FixFinger,
-  Get(String),
-  Set(String, String),
+  Get(String, usize),
+  Set(String, String, Version, Option<VectorClock>),
+  Delete(String, Version, Option<VectorClock>),
   FixSuccessor,
+  Hint(String, Version),
 }
 
 /// Ways that a node can transfer/be transferred keys from other nodes
 /// after a predecessor change
 pub enum TransferType {
-  Get(i32, i32),          // get in range
-  Send(i32, i32, String), // send in range, to predecessor name
-  Duplicate,
+  Get(Id, Id),            // get in range
+  Send(Id, Id, String),   // send in range, to predecessor name
+  Replicate(Vec<String>), // replicate the keys taken over from a failed predecessor
   Nothing,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::{MemoryStorage, Storage};
+  use parking_lot::RwLock;
+  use proptest::prelude::{any, prop};
+  use proptest::{prop_assert, prop_assert_eq, proptest};
+  use rand::rngs::StdRng;
+  use rand::{Rng, SeedableRng};
+  use std::sync::Arc;
+
+  /// Returns the id among sorted node ids that owns a key: the first one clockwise from it
+  fn owner(ids: &[Id], key: Id) -> Id {
+    *ids.iter().find(|&&id| id >= key).unwrap_or(&ids[0])
+  }
+
+  /// Returns a ring of nodes with distinct ids, named by their position on the ring,
+  /// with every successor, predecessor and finger as stabilization would leave them
+  ///
+  /// # Arguments
+  ///
+  /// * `m` - The width of the identifier space
+  /// * `seeds` - What each node's id is hashed from, fewer nodes when ids collide
+  fn ring(m: u32, seeds: &[u64]) -> (Vec<Id>, HashMap<String, Node>) {
+    let mut ids: Vec<Id> = seeds
+      .iter()
+      .map(|seed| hash::hash(&seed.to_string(), m))
+      .collect();
+    ids.sort();
+    ids.dedup();
+    let entry = |id: Id| {
+      let i = ids.binary_search(&id).unwrap();
+      NodeEntry::new(id, &format!("node-{}", i))
+    };
+    let mut nodes = HashMap::new();
+    for (i, &id) in ids.iter().enumerate() {
+      let storage: Box<dyn Storage> = Box::new(MemoryStorage::default());
+      let storage = Arc::new(RwLock::new(storage));
+      let mut node = Node::new(m, &format!("node-{}", i), id, 3, 2, storage);
+      node.set_successor(entry(ids[(i + 1) % ids.len()]));
+      node.set_predecessor(Some(entry(ids[(i + ids.len() - 1) % ids.len()])));
+      for f in 1..m {
+        let start = node.get_finger_start(f);
+        node.set_finger(f, entry(owner(&ids, start)));
+      }
+      nodes.insert(String::from(node.get_name()), node);
+    }
+    (ids, nodes)
+  }
+
+  /// Looks a key up from a node the way handlers route FindSucc messages, asking each node
+  /// for the predecessor of the key until one knows its successor owns it
+  /// Returns the owner found and the number of hops to other nodes taken
+  fn lookup(nodes: &HashMap<String, Node>, from: &str, key: Id) -> (Id, usize) {
+    let mut node = &nodes[from];
+    let mut hops = 0;
+    loop {
+      match node.find_predecessor(key) {
+        (true, _) => return (node.get_successor().id, hops),
+        (false, next) => {
+          assert_ne!(next.node_name, node.get_name(), "lookup of {} stuck", key);
+          assert!(hops < nodes.len(), "lookup of {} loops", key);
+          node = &nodes[&next.node_name];
+          hops += 1;
+        }
+      }
+    }
+  }
+
+  /// Returns keys of a ring: hashes, and the node ids and the ids just past them,
+  /// where inclusive and exclusive bounds differ
+  ///
+  /// # Arguments
+  ///
+  /// * `m` - The width of the identifier space
+  /// * `ids` - The node ids
+  /// * `seeds` - What the other keys are hashed from
+  fn keys(m: u32, ids: &[Id], seeds: &[u64]) -> Vec<Id> {
+    let mut keys: Vec<Id> = seeds
+      .iter()
+      .map(|seed| hash::hash(&seed.to_string(), m))
+      .collect();
+    keys.extend(ids.iter().copied());
+    keys.extend(ids.iter().map(|id| id.add_pow2(0, m)));
+    keys
+  }
+
+  proptest! {
+    #[test]
+    fn lookups_from_any_node_reach_the_owner_in_log_hops(
+      // Narrow rings make node ids crowd together and wrap around often
+      m in prop::sample::select(vec![8, 16, 160]),
+      seeds in prop::collection::vec(any::<u64>(), 1..100),
+      key_seeds in prop::collection::vec(any::<u64>(), 20),
+      start in any::<prop::sample::Index>(),
+    ) {
+      let (ids, nodes) = ring(m, &seeds);
+      let bound = 2 * (ids.len() as f64).log2().ceil().max(1.0) as usize;
+      for (i, key) in keys(m, &ids, &key_seeds).into_iter().enumerate() {
+        let from = format!("node-{}", (start.index(ids.len()) + i) % ids.len());
+        let (found, hops) = lookup(&nodes, &from, key);
+        prop_assert_eq!(found, owner(&ids, key), "lookup of {} from {}", key, from);
+        prop_assert!(
+          hops <= bound,
+          "lookup of {} from {} took {} hops among {} nodes",
+          key,
+          from,
+          hops,
+          ids.len()
+        );
+      }
+    }
+
+    #[test]
+    fn key_ranges_partition_the_ring(
+      m in prop::sample::select(vec![8, 16, 160]),
+      seeds in prop::collection::vec(any::<u64>(), 1..100),
+      key_seeds in prop::collection::vec(any::<u64>(), 20),
+    ) {
+      let (ids, nodes) = ring(m, &seeds);
+      for key in keys(m, &ids, &key_seeds) {
+        // Each node owns the keys after its predecessor, up to and including its own id
+        let owners: Vec<Id> = nodes
+          .values()
+          .filter(|node| {
+            let predecessor = node.get_predecessor().unwrap();
+            hash::in_range(key, predecessor.id, node.get_id(), true)
+          })
+          .map(|node| node.get_id())
+          .collect();
+        prop_assert_eq!(owners, vec![owner(&ids, key)], "owners of {}", key);
+      }
+    }
+  }
+
+  #[test]
+  fn id_owned_by_another_node_is_found_and_rekey_moves_away() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let seeds: Vec<u64> = (0..10).map(|_| rng.gen()).collect();
+    let (ids, mut nodes) = ring(8, &seeds);
+    let node = nodes.get_mut("node-0").unwrap();
+    let successor = node.get_successor();
+
+    // A known node, or the node itself, owning an id collides with any other name
+    let owner = node.find_id_owner(successor.id, "newcomer").unwrap();
+    assert_eq!(
+      (owner.id, owner.node_name),
+      (successor.id, successor.node_name.to_string())
+    );
+    assert!(node.find_id_owner(ids[0], "newcomer").is_some());
+    // but not with the name that already owns it
+    assert!(node
+      .find_id_owner(successor.id, &successor.node_name)
+      .is_none());
+    assert!(node.find_id_owner(ids[0], "node-0").is_none());
+
+    let version = Version {
+      wall: 1,
+      logical: 0,
+      writer: String::from("node-0"),
+    };
+    node
+      .set(String::from("X"), String::from("1"), version)
+      .unwrap();
+    let id = hash::hash("node-0#1", 8);
+    node.rekey(id);
+    assert_eq!(node.get_id(), id);
+    assert_eq!(node.get_name(), "node-0");
+    assert_eq!(node.get_successor().id, id);
+    assert!(node.find_id_owner(successor.id, "newcomer").is_none());
+    assert_eq!(node.get("X").unwrap().values(), vec!["1"]);
+  }
+}
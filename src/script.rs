@@ -0,0 +1,316 @@
+// File: script.rs
+//
+// The purpose of this file is to run the chistributed scripts in scripts/
+// against a simulated cluster instead of the chistributed broker, and to
+// check the answer to every get in a script against the results expected of it.
+
+use crate::chi::{self, Command};
+use crate::handler::WRITE_TIMEOUT;
+use crate::sim::Simulation;
+
+/// What a get is expected to be answered with
+#[derive(Debug, PartialEq)]
+pub enum Expected {
+  /// The key's value
+  Value(String),
+  /// Any value, when concurrent writes leave more than one acceptable
+  Any,
+  /// An error
+  Error,
+  /// No answer at all, as from a failed node
+  NoReply,
+}
+
+/// A get as expected in a script's expectations: the node, the key, and the answer
+#[derive(Debug, PartialEq)]
+pub struct Expectation {
+  pub node: String,
+  pub key: String,
+  pub expected: Expected,
+}
+
+/// The answer to a get in a script: the node, the key, and the reply, if any
+#[derive(Debug)]
+pub struct Outcome {
+  pub node: String,
+  pub key: String,
+  pub reply: Option<serde_json::Value>,
+}
+
+/// Parses the expectations of a script: one line per get in the script, in order,
+/// with the node, the key, and either the value in quotes or one of the words
+/// any, error and none
+/// Blank lines are skipped, and everything after a # outside quotes is a comment
+///
+/// # Arguments
+///
+/// * `contents` - The expectations' contents
+pub fn parse_expectations(contents: &str) -> Result<Vec<Expectation>, String> {
+  let mut expectations = Vec::new();
+  for (i, line) in contents.lines().enumerate() {
+    let line = strip_comment(line);
+    let quoted = line.trim_end().ends_with('"');
+    let words = chi::tokenize(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+    if words.is_empty() {
+      continue;
+    }
+    if words.len() != 3 {
+      return Err(format!("line {}: expected node, key and result", i + 1));
+    }
+    let expected = match words[2].as_str() {
+      value if quoted => Expected::Value(String::from(value)),
+      "any" => Expected::Any,
+      "error" => Expected::Error,
+      "none" => Expected::NoReply,
+      other => return Err(format!("line {}: unknown result {}", i + 1, other)),
+    };
+    expectations.push(Expectation {
+      node: words[0].to_string(),
+      key: words[1].to_string(),
+      expected,
+    });
+  }
+  Ok(expectations)
+}
+
+/// Returns a line of expectations without its comment, if any
+///
+/// # Arguments
+///
+/// * `line` - The line
+fn strip_comment(line: &str) -> &str {
+  let mut quoted = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      '"' => quoted = !quoted,
+      '#' if !quoted => return &line[..i],
+      _ => {}
+    }
+  }
+  line
+}
+
+/// Runs a script against a simulated cluster of the nodes it starts
+/// Returns the answer to every get, in order
+/// After the script ends, requests still in flight are given time to be answered
+///
+/// # Arguments
+///
+/// * `seed` - Seed of the simulation
+/// * `commands` - The script
+pub fn run(seed: u64, commands: &[Command]) -> Vec<Outcome> {
+  let names: Vec<&str> = commands
+    .iter()
+    .filter_map(|c| match c {
+      Command::Start(node) => Some(node.as_str()),
+      _ => None,
+    })
+    .collect();
+  let mut sim = Simulation::new(seed, &names);
+  let mut gets = Vec::new();
+  for command in commands {
+    match command {
+      Command::Start(node) => sim.start(node),
+      Command::Set { node, key, value } => {
+        sim.set(node, key, value);
+      }
+      Command::Get { node, key } => gets.push((node, key, sim.get(node, key))),
+      Command::FailNode(node) => sim.fail(node),
+      Command::RecoverNode(node) => sim.recover(node),
+      Command::CreatePartition { name, side, other } => {
+        let side: Vec<&str> = side.iter().map(|n| n.as_str()).collect();
+        let other: Option<Vec<&str>> = other
+          .as_ref()
+          .map(|other| other.iter().map(|n| n.as_str()).collect());
+        sim.create_partition(name, &side, other.as_deref());
+      }
+      Command::RemovePartition(name) => sim.remove_partition(name),
+      Command::Wait(millis) => sim.run_for(*millis),
+      Command::Quit => break,
+    }
+  }
+  sim.run_for(WRITE_TIMEOUT);
+  gets
+    .into_iter()
+    .map(|(node, key, id)| Outcome {
+      node: node.to_string(),
+      key: key.to_string(),
+      reply: sim.reply(id).cloned(),
+    })
+    .collect()
+}
+
+/// Checks the answers to a script's gets against its expectations
+/// Returns a description of every get that was not answered as expected
+///
+/// # Arguments
+///
+/// * `outcomes` - The answer to every get, in order
+/// * `expectations` - The expected answer to every get, in order
+pub fn check(outcomes: &[Outcome], expectations: &[Expectation]) -> Vec<String> {
+  let mut mismatches = Vec::new();
+  if outcomes.len() != expectations.len() {
+    mismatches.push(format!(
+      "script has {} gets, but {} are expected",
+      outcomes.len(),
+      expectations.len()
+    ));
+  }
+  for (i, (outcome, expectation)) in outcomes.iter().zip(expectations.iter()).enumerate() {
+    if outcome.node != expectation.node || outcome.key != expectation.key {
+      mismatches.push(format!(
+        "get {} is of {} on {}, but {} on {} is expected",
+        i + 1,
+        outcome.key,
+        outcome.node,
+        expectation.key,
+        expectation.node
+      ));
+      continue;
+    }
+    let value = outcome
+      .reply
+      .as_ref()
+      .and_then(|r| r.get("value"))
+      .and_then(|v| v.as_str());
+    let error = outcome
+      .reply
+      .as_ref()
+      .is_some_and(|r| r.get("error").is_some());
+    let matches = match &expectation.expected {
+      Expected::Value(expected) => value == Some(expected.as_str()),
+      Expected::Any => value.is_some(),
+      Expected::Error => error,
+      Expected::NoReply => outcome.reply.is_none(),
+    };
+    if !matches {
+      mismatches.push(format!(
+        "get {} of {} on {}: expected {:?}, got {:?}",
+        i + 1,
+        outcome.key,
+        outcome.node,
+        expectation.expected,
+        outcome.reply
+      ));
+    }
+  }
+  mismatches
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::Path;
+
+  /// Runs a script from scripts/ and checks it against the expectations next to it
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the script, without its extension
+  fn run_script(name: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts");
+    let script = fs::read_to_string(dir.join(format!("{}.chi", name))).expect("cannot read script");
+    let expectations =
+      fs::read_to_string(dir.join(format!("{}.expected", name))).expect("cannot read expectations");
+    let commands = chi::parse(&script).expect("cannot parse script");
+    let expectations = parse_expectations(&expectations).expect("cannot parse expectations");
+    let mismatches = check(&run(0, &commands), &expectations);
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+  }
+
+  #[test]
+  fn parses_every_command() {
+    let script = "start -n node-1\n\
+                  \n\
+                  set -n node-1 -k LOG -v \"FAILED NODE 1\"\n\
+                  get -n node-2 -k X\n\
+                  fail_node -n node-1\n\
+                  recover_node -n node-1\n\
+                  create_partition -n part -p node-1,node-2 -2 node-3\n\
+                  create_partition -n rest -p node-4\n\
+                  remove_partition -n part\n\
+                  wait -t 2.5\n\
+                  quit";
+    let node = String::from;
+    assert_eq!(
+      chi::parse(script),
+      Ok(vec![
+        Command::Start(node("node-1")),
+        Command::Set {
+          node: node("node-1"),
+          key: node("LOG"),
+          value: node("FAILED NODE 1"),
+        },
+        Command::Get {
+          node: node("node-2"),
+          key: node("X"),
+        },
+        Command::FailNode(node("node-1")),
+        Command::RecoverNode(node("node-1")),
+        Command::CreatePartition {
+          name: node("part"),
+          side: vec![node("node-1"), node("node-2")],
+          other: Some(vec![node("node-3")]),
+        },
+        Command::CreatePartition {
+          name: node("rest"),
+          side: vec![node("node-4")],
+          other: None,
+        },
+        Command::RemovePartition(node("part")),
+        Command::Wait(2500),
+        Command::Quit,
+      ])
+    );
+    assert!(chi::parse("set -n node-1 -k X").is_err());
+    assert!(chi::parse("sett -n node-1").is_err());
+  }
+
+  #[test]
+  fn parses_every_expectation() {
+    let expectations = "# comment\n\
+                        \n\
+                        node-1 X \"X: #1\" # comment\n\
+                        node-2 X any\n\
+                        node-3 X error\n\
+                        node-1 Y none";
+    let expected: Vec<Expected> = parse_expectations(expectations)
+      .expect("cannot parse expectations")
+      .into_iter()
+      .map(|e| e.expected)
+      .collect();
+    assert_eq!(
+      expected,
+      vec![
+        Expected::Value(String::from("X: #1")),
+        Expected::Any,
+        Expected::Error,
+        Expected::NoReply,
+      ]
+    );
+    assert!(parse_expectations("node-1 X").is_err());
+    assert!(parse_expectations("node-1 X error or \"1\"").is_err());
+    assert!(parse_expectations("node-1 X maybe").is_err());
+  }
+
+  #[test]
+  fn fail_recover1() {
+    run_script("fail-recover1");
+  }
+
+  #[test]
+  fn fail_recover2() {
+    run_script("fail-recover2");
+  }
+
+  #[test]
+  fn partition_heal1() {
+    run_script("partition-heal1");
+  }
+
+  #[test]
+  fn partition_heal2() {
+    run_script("partition-heal2");
+  }
+}
@@ -0,0 +1,1560 @@
+// File: sim.rs
+//
+// The purpose of this file is to run many nodes in one process over a simulated
+// network and a virtual clock, so that ring formation, failures and partitions
+// play out the same way every time for a given seed.
+
+use crate::clock::{TimeSource, VectorClock, Versioning};
+use crate::detector::{DetectorKind, DetectorSettings};
+use crate::error::MessageError;
+use crate::handler::{Handler, ANTI_ENTROPY_TIME, STABILIZE_TIME};
+use crate::hint::HintQueue;
+use crate::hash::Id;
+use crate::history::{Op, Operation};
+use crate::msg;
+use crate::node::{self, NodeEntry};
+use crate::storage::{MemoryStorage, Record};
+use crate::transport::Transport;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Longest time a message takes to reach another node (50ms)
+pub const MAX_LATENCY: u64 = 50;
+// Physical time the virtual clock starts at, in milliseconds since the Unix epoch
+pub const START_WALL: u64 = 1_600_000_000_000;
+// Number of copies of each key in a simulated cluster
+pub const REPLICATION_FACTOR: usize = 3;
+
+/// How the nodes of a simulated cluster are configured
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+  /// Width of the identifier space
+  pub ring_bits: u32,
+  /// Number of virtual nodes each node places on the ring
+  pub vnodes: usize,
+  /// How conflicting writes to the same key are resolved
+  pub versioning: Versioning,
+  /// Number of copies a write is stored on before it is confirmed, unless the client asks otherwise
+  pub write_quorum: usize,
+  /// Number of copies a read is answered from, unless the client asks otherwise
+  pub read_quorum: usize,
+}
+
+impl Default for Settings {
+  fn default() -> Settings {
+    Settings {
+      ring_bits: 160,
+      vnodes: 1,
+      versioning: Versioning::Lww,
+      write_quorum: 1,
+      read_quorum: 1,
+    }
+  }
+}
+
+/// A clock that only moves when the simulation advances it
+pub struct VirtualClock {
+  start: Instant,
+  elapsed: AtomicU64,
+}
+
+impl VirtualClock {
+  /// Returns a new VirtualClock object at time 0
+  pub fn new() -> VirtualClock {
+    VirtualClock {
+      start: Instant::now(),
+      elapsed: AtomicU64::new(0),
+    }
+  }
+
+  /// Returns the milliseconds elapsed since the simulation started
+  pub fn elapsed(&self) -> u64 {
+    self.elapsed.load(Ordering::SeqCst)
+  }
+
+  /// Moves the clock forward
+  ///
+  /// # Arguments
+  ///
+  /// * `elapsed` - The milliseconds elapsed since the simulation started
+  fn advance_to(&self, elapsed: u64) {
+    self.elapsed.fetch_max(elapsed, Ordering::SeqCst);
+  }
+}
+
+impl TimeSource for VirtualClock {
+  fn now(&self) -> Instant {
+    self.start + Duration::from_millis(self.elapsed())
+  }
+
+  fn wall_millis(&self) -> u64 {
+    START_WALL + self.elapsed()
+  }
+}
+
+/// A transport that leaves every message a node sends in an outbox the simulation drains
+/// Messages are handed to nodes by the simulation, so nothing is ever received from it
+pub struct SimTransport {
+  outbox: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+impl Transport for SimTransport {
+  fn receive(&self) -> Result<String, MessageError> {
+    Err(MessageError::Receive(String::from("the simulation delivers messages itself")))
+  }
+
+  fn send(&self, json: &serde_json::Value) -> Result<(), MessageError> {
+    self.outbox.lock().push(json.clone());
+    Ok(())
+  }
+}
+
+/// Something that happens to a node at a point of virtual time
+/// Messages from clients have no source
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+  Deliver {
+    source: Option<usize>,
+    destination: usize,
+    contents: String,
+  },
+  Stabilize(usize),
+  AntiEntropy(usize),
+}
+
+/// A node of the simulated cluster, whether it was started, and whether it was failed
+/// A failed node keeps running, as under chistributed, but no message reaches it or leaves it
+struct SimNode {
+  name: String,
+  handler: Handler,
+  outbox: Arc<Mutex<Vec<serde_json::Value>>>,
+  started: bool,
+  failed: bool,
+}
+
+/// A cluster of nodes in one process, exchanging messages over a simulated network
+/// Every message takes a random latency drawn from a seeded generator, each node's
+/// periodic tasks run on the virtual clock, and the messages a node sends in one step
+/// are ordered by contents, so the same seed and the same calls always give the same run
+/// Every client request is kept with the time it was sent, and every reply with the time
+/// it arrived, and messages between nodes are counted by type
+pub struct Simulation {
+  clock: Arc<VirtualClock>,
+  rng: StdRng,
+  nodes: Vec<SimNode>,
+  events: BinaryHeap<Reverse<(u64, u64, Event)>>,
+  seq: u64,
+  partitions: HashMap<String, (HashSet<usize>, HashSet<usize>)>,
+  drops: Vec<(String, usize, usize)>,
+  sent: HashMap<String, u64>,
+  next_id: i32,
+  requests: BTreeMap<i32, (u64, serde_json::Value)>,
+  replies: HashMap<i32, (u64, serde_json::Value)>,
+}
+
+impl Simulation {
+  /// Returns a new Simulation object with nodes that are not started yet,
+  /// configured with the default settings
+  /// Every node knows every other node as a peer
+  ///
+  /// # Arguments
+  ///
+  /// * `seed` - Seed of every random choice in the simulation
+  /// * `names` - Names of the nodes
+  pub fn new(seed: u64, names: &[&str]) -> Simulation {
+    Simulation::with_settings(seed, names, Settings::default())
+  }
+
+  /// Returns a new Simulation object with nodes that are not started yet
+  /// Every node knows every other node as a peer
+  ///
+  /// # Arguments
+  ///
+  /// * `seed` - Seed of every random choice in the simulation
+  /// * `names` - Names of the nodes
+  /// * `settings` - How every node is configured
+  pub fn with_settings(seed: u64, names: &[&str], settings: Settings) -> Simulation {
+    let clock = Arc::new(VirtualClock::new());
+    let mut rng = StdRng::seed_from_u64(seed);
+    let detector = DetectorSettings {
+      kind: DetectorKind::Phi,
+      missed_pings: 2,
+      phi_threshold: 8.0,
+    };
+    let nodes = names
+      .iter()
+      .map(|name| {
+        let outbox = Arc::new(Mutex::new(Vec::new()));
+        let peers: Vec<String> = names
+          .iter()
+          .filter(|peer| *peer != name)
+          .map(|peer| peer.to_string())
+          .collect();
+        let handler = Handler::new(
+          Box::new(SimTransport {
+            outbox: outbox.clone(),
+          }),
+          name,
+          Some(peers).filter(|peers| !peers.is_empty()),
+          settings.ring_bits,
+          settings.vnodes,
+          Box::new(MemoryStorage::default()),
+          settings.versioning,
+          REPLICATION_FACTOR,
+          settings.write_quorum,
+          settings.read_quorum,
+          HintQueue::in_memory(),
+          detector,
+          clock.clone(),
+          StdRng::seed_from_u64(rng.gen()),
+          false,
+        );
+        SimNode {
+          name: name.to_string(),
+          handler,
+          outbox,
+          started: false,
+          failed: false,
+        }
+      })
+      .collect();
+    Simulation {
+      clock,
+      rng,
+      nodes,
+      events: BinaryHeap::new(),
+      seq: 0,
+      partitions: HashMap::new(),
+      drops: Vec::new(),
+      sent: HashMap::new(),
+      next_id: 0,
+      requests: BTreeMap::new(),
+      replies: HashMap::new(),
+    }
+  }
+
+  /// Returns the milliseconds elapsed since the simulation started
+  pub fn now(&self) -> u64 {
+    self.clock.elapsed()
+  }
+
+  /// Returns the index of a node
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  fn index(&self, name: &str) -> usize {
+    self
+      .nodes
+      .iter()
+      .position(|n| n.name == name)
+      .unwrap_or_else(|| panic!("unknown node {}", name))
+  }
+
+  /// Schedules an event
+  ///
+  /// # Arguments
+  ///
+  /// * `delay` - Milliseconds from now until the event happens
+  /// * `event` - The event
+  fn schedule(&mut self, delay: u64, event: Event) {
+    self.seq += 1;
+    self
+      .events
+      .push(Reverse((self.now() + delay, self.seq, event)));
+  }
+
+  /// Starts a node: the network says hello to it and its periodic tasks begin
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn start(&mut self, name: &str) {
+    let node = self.index(name);
+    if self.nodes[node].started {
+      return;
+    }
+    self.nodes[node].started = true;
+    self.schedule(
+      0,
+      Event::Deliver {
+        source: None,
+        destination: node,
+        contents: String::from(r#"{"type": "hello"}"#),
+      },
+    );
+    self.schedule(STABILIZE_TIME as u64, Event::Stabilize(node));
+    self.schedule(ANTI_ENTROPY_TIME as u64, Event::AntiEntropy(node));
+  }
+
+  /// Cuts a node off from the network
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn fail(&mut self, name: &str) {
+    let node = self.index(name);
+    self.nodes[node].failed = true;
+  }
+
+  /// Reconnects a failed node to the network
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn recover(&mut self, name: &str) {
+    let node = self.index(name);
+    self.nodes[node].failed = false;
+  }
+
+  /// Separates two groups of nodes until the partition is removed
+  ///
+  /// # Arguments
+  ///
+  /// * `partition` - Name of the partition
+  /// * `side` - Names of the nodes on one side of it
+  /// * `other` - Names of the nodes on the other side, or None for every other node
+  pub fn create_partition(&mut self, partition: &str, side: &[&str], other: Option<&[&str]>) {
+    let side: HashSet<usize> = side.iter().map(|name| self.index(name)).collect();
+    let other = match other {
+      Some(other) => other.iter().map(|name| self.index(name)).collect(),
+      None => (0..self.nodes.len())
+        .filter(|n| !side.contains(n))
+        .collect(),
+    };
+    self.partitions.insert(partition.to_string(), (side, other));
+  }
+
+  /// Removes a partition
+  ///
+  /// # Arguments
+  ///
+  /// * `partition` - Name of the partition
+  pub fn remove_partition(&mut self, partition: &str) {
+    self.partitions.remove(partition);
+  }
+
+  /// Loses the next message of a type one node sends to another
+  ///
+  /// # Arguments
+  ///
+  /// * `kind` - Type of the message, as sent on the wire
+  /// * `source` - Name of the sending node
+  /// * `destination` - Name of the receiving node
+  pub fn drop_next(&mut self, kind: &str, source: &str, destination: &str) {
+    let drop = (kind.to_string(), self.index(source), self.index(destination));
+    self.drops.push(drop);
+  }
+
+  /// Returns how many messages of a type nodes have sent each other
+  ///
+  /// # Arguments
+  ///
+  /// * `kind` - Type of the message, as sent on the wire
+  pub fn sent(&self, kind: &str) -> u64 {
+    self.sent.get(kind).cloned().unwrap_or(0)
+  }
+
+  /// Sends a client Set to a node
+  /// Returns the id of the request, under which its reply is kept
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  /// * `value` - The value
+  pub fn set(&mut self, name: &str, key: &str, value: &str) -> i32 {
+    self.request(
+      name,
+      serde_json::json!({ "type": "set", "key": key, "value": value }),
+    )
+  }
+
+  /// Sends a client Set to a node, passing back the causal context of an earlier Get
+  /// Returns the id of the request, under which its reply is kept
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  /// * `value` - The value
+  /// * `context` - Causal context the Get was answered with
+  pub fn set_in_context(
+    &mut self,
+    name: &str,
+    key: &str,
+    value: &str,
+    context: &VectorClock,
+  ) -> i32 {
+    self.request(
+      name,
+      serde_json::json!({ "type": "set", "key": key, "value": value, "context": context }),
+    )
+  }
+
+  /// Sends a client Delete to a node
+  /// Returns the id of the request, under which its reply is kept
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  pub fn delete(&mut self, name: &str, key: &str) -> i32 {
+    self.request(name, serde_json::json!({ "type": "delete", "key": key }))
+  }
+
+  /// Sends a client Leave to a node
+  /// Returns the id of the request, under which its reply is kept
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn leave(&mut self, name: &str) -> i32 {
+    self.request(name, serde_json::json!({ "type": "leave" }))
+  }
+
+  /// Sends a client Get to a node
+  /// Returns the id of the request, under which its reply is kept
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  pub fn get(&mut self, name: &str, key: &str) -> i32 {
+    self.request(name, serde_json::json!({ "type": "get", "key": key }))
+  }
+
+  /// Sends a node contents from the network as they are, whether or not they are a valid message
+  /// Replies to them are kept by the id they hold, if any
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `contents` - The contents
+  pub fn send_raw(&mut self, name: &str, contents: &str) {
+    let destination = self.index(name);
+    self.schedule(
+      0,
+      Event::Deliver {
+        source: None,
+        destination,
+        contents: contents.to_string(),
+      },
+    );
+  }
+
+  /// Sends a client request to a node, giving it the next id
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `request` - The request without its id
+  fn request(&mut self, name: &str, mut request: serde_json::Value) -> i32 {
+    self.next_id += 1;
+    request["id"] = serde_json::json!(self.next_id);
+    let destination = self.index(name);
+    self.schedule(
+      0,
+      Event::Deliver {
+        source: None,
+        destination,
+        contents: request.to_string(),
+      },
+    );
+    self.requests.insert(self.next_id, (self.now(), request));
+    self.next_id
+  }
+
+  /// Returns the reply to a client request, if it was answered
+  ///
+  /// # Arguments
+  ///
+  /// * `id` - Id of the request
+  pub fn reply(&self, id: i32) -> Option<&serde_json::Value> {
+    self.replies.get(&id).map(|(_, reply)| reply)
+  }
+
+  /// Returns every client Set and Get so far, in the order they were requested
+  /// A Set without a successful reply may or may not have taken effect, so it has no reply time,
+  /// and neither has one only accepted as a hint, which may still take effect later
+  /// A Get that was not answered, or only with a timeout, tells nothing and is left out,
+  /// while one answered that the key does not exist found no value
+  pub fn history(&self) -> Vec<Operation> {
+    self
+      .requests
+      .iter()
+      .filter_map(|(id, (call, request))| {
+        let key = request["key"].as_str()?;
+        let reply = self.replies.get(id);
+        let succeeded =
+          reply.filter(|(_, r)| r.get("error").is_none() && r.get("hinted").is_none());
+        let (op, ret) = match request["value"].as_str() {
+          Some(value) => (Op::Set(value.to_string()), succeeded.map(|(ret, _)| *ret)),
+          None => {
+            let (ret, reply) = reply?;
+            let not_found = msg::GetFailResponse::new(*id, key).error;
+            match reply["value"].as_str() {
+              Some(value) => (Op::Get(Some(value.to_string())), Some(*ret)),
+              None if reply["error"].as_str() == Some(&not_found) => (Op::Get(None), Some(*ret)),
+              None => return None,
+            }
+          }
+        };
+        Some(Operation {
+          id: *id,
+          key: key.to_string(),
+          op,
+          call: *call,
+          ret,
+        })
+      })
+      .collect()
+  }
+
+  /// Returns the name of each of a node's virtual nodes along with the name of its successor
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn successors(&self, name: &str) -> Vec<(String, String)> {
+    self.nodes[self.index(name)].handler.successors()
+  }
+
+  /// Returns the name of each of a node's virtual nodes along with the name of its predecessor
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn predecessors(&self, name: &str) -> Vec<(String, Option<String>)> {
+    self.nodes[self.index(name)].handler.predecessors()
+  }
+
+  /// Returns the id and name of each of a node's virtual nodes
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn entries(&self, name: &str) -> Vec<NodeEntry> {
+    self.nodes[self.index(name)].handler.entries()
+  }
+
+  /// Looks a key up from a virtual node through the finger tables the nodes built,
+  /// as FindSucc messages are routed, but without sending any
+  /// Returns the owner found and the number of hops to other virtual nodes taken
+  ///
+  /// # Arguments
+  ///
+  /// * `from` - Name of the virtual node the lookup starts at
+  /// * `key` - The key
+  pub fn lookup(&self, from: &str, key: Id) -> (NodeEntry, usize) {
+    let mut at = from.to_string();
+    let mut hops = 0;
+    loop {
+      let node = &self.nodes[self.index(node::physical_name(&at))];
+      match node.handler.route(&at, key) {
+        (true, owner) => return (owner, hops),
+        (false, next) => {
+          assert_ne!(next.node_name, at, "lookup of {} stuck", key);
+          assert!(hops < 64, "lookup of {} loops", key);
+          at = next.node_name;
+          hops += 1;
+        }
+      }
+    }
+  }
+
+  /// Returns the number of writes a node keeps as hints for owners that did not confirm them
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  pub fn hints(&self, name: &str) -> usize {
+    self.nodes[self.index(name)].handler.hint_count()
+  }
+
+  /// Returns how many messages a node dropped for a kind of error
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `kind` - Short name of the kind of error
+  pub fn error_count(&self, name: &str, kind: &str) -> u64 {
+    self.nodes[self.index(name)].handler.error_count(kind)
+  }
+
+  /// Returns the record of a key in a node's own store, tombstones included
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  pub fn record(&self, name: &str, key: &str) -> Option<Record> {
+    self.nodes[self.index(name)].handler.record(key)
+  }
+
+  /// Returns the records of a key in the replicas a node holds, tombstones included
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  pub fn replica_records(&self, name: &str, key: &str) -> Vec<Record> {
+    self.nodes[self.index(name)].handler.replica_records(key)
+  }
+
+  /// Returns whether a node is still waiting for its replica holders to see the deletion of a key
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the node
+  /// * `key` - The key
+  pub fn tracks_tombstone(&self, name: &str, key: &str) -> bool {
+    self.nodes[self.index(name)].handler.tracks_tombstone(key)
+  }
+
+  /// Returns whether a message can travel between two nodes
+  /// Clients reach every node that is not failed
+  ///
+  /// # Arguments
+  ///
+  /// * `source` - Index of the sending node, if it is not a client
+  /// * `destination` - Index of the receiving node
+  fn reachable(&self, source: Option<usize>, destination: usize) -> bool {
+    if !self.nodes[destination].started || self.nodes[destination].failed {
+      return false;
+    }
+    match source {
+      Some(source) => {
+        !self.nodes[source].failed
+          && self.partitions.values().all(|(side, other)| {
+            !(side.contains(&source) && other.contains(&destination)
+              || other.contains(&source) && side.contains(&destination))
+          })
+      }
+      None => true,
+    }
+  }
+
+  /// Advances the virtual clock, running every event that falls in the meantime
+  ///
+  /// # Arguments
+  ///
+  /// * `millis` - Milliseconds to advance by
+  pub fn run_for(&mut self, millis: u64) {
+    let end = self.now() + millis;
+    while self
+      .events
+      .peek()
+      .is_some_and(|Reverse((time, _, _))| *time <= end)
+    {
+      let Reverse((time, _, event)) = self.events.pop().unwrap();
+      self.clock.advance_to(time);
+      match event {
+        Event::Deliver {
+          source,
+          destination,
+          contents,
+        } => {
+          // Partitions and failures that began while the message was in flight drop it
+          // A node that left the ring stops, as if it failed
+          if self.reachable(source, destination) {
+            self.nodes[destination].handler.deliver(&contents);
+            self.dispatch(destination);
+            if self.nodes[destination].handler.has_left() {
+              self.nodes[destination].failed = true;
+            }
+          }
+        }
+        Event::Stabilize(node) => {
+          self.nodes[node].handler.stabilize();
+          self.dispatch(node);
+          self.schedule(STABILIZE_TIME as u64, Event::Stabilize(node));
+        }
+        Event::AntiEntropy(node) => {
+          self.nodes[node].handler.anti_entropy();
+          self.dispatch(node);
+          self.schedule(ANTI_ENTROPY_TIME as u64, Event::AntiEntropy(node));
+        }
+      }
+    }
+    self.clock.advance_to(end);
+  }
+
+  /// Sends on the messages a node left in its outbox
+  /// Messages without a destination are replies to clients, and are kept by request id
+  ///
+  /// # Arguments
+  ///
+  /// * `node` - Index of the node
+  fn dispatch(&mut self, node: usize) {
+    let mut messages: Vec<String> = self.nodes[node]
+      .outbox
+      .lock()
+      .drain(..)
+      .map(|json| json.to_string())
+      .collect();
+    messages.sort();
+    for contents in messages {
+      let json: serde_json::Value =
+        serde_json::from_str(&contents).expect("cannot parse sent message");
+      match json.get("destination").and_then(|d| d.as_str()) {
+        Some(name) => {
+          let destination = match self.nodes.iter().position(|n| n.name == name) {
+            Some(destination) => destination,
+            None => continue,
+          };
+          let kind = json["type"].as_str().unwrap_or_default().to_string();
+          *self.sent.entry(kind.clone()).or_insert(0) += 1;
+          let drop = (kind, node, destination);
+          if let Some(i) = self.drops.iter().position(|d| *d == drop) {
+            self.drops.remove(i);
+            continue;
+          }
+          if self.reachable(Some(node), destination) {
+            let latency = self.rng.gen_range(1, MAX_LATENCY + 1);
+            self.schedule(
+              latency,
+              Event::Deliver {
+                source: Some(node),
+                destination,
+                contents,
+              },
+            );
+          }
+        }
+        None => {
+          if self.nodes[node].failed {
+            continue;
+          }
+          if let Some(id) = json.get("id").and_then(|id| id.as_i64()) {
+            self.replies.insert(id as i32, (self.now(), json));
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::handler::WRITE_TIMEOUT;
+  use crate::{hash, history};
+  use proptest::prelude::{any, prop, Just, ProptestConfig, Strategy};
+  use proptest::{prop_assert, prop_assert_eq, proptest};
+
+  const NODES: [&str; 4] = ["node-1", "node-2", "node-3", "node-4"];
+
+  /// Returns a simulation of the given nodes, all started and given time to form a ring
+  fn formed_ring(seed: u64, names: &[&str]) -> Simulation {
+    formed_ring_with(seed, names, Settings::default())
+  }
+
+  /// Returns a simulation of the given nodes configured with the given settings,
+  /// all started and given time to form a ring
+  fn formed_ring_with(seed: u64, names: &[&str], settings: Settings) -> Simulation {
+    let mut sim = Simulation::with_settings(seed, names, settings);
+    for name in names {
+      sim.start(name);
+      sim.run_for(500);
+    }
+    sim.run_for(20_000);
+    sim
+  }
+
+  /// Returns the value a Get was answered with, if it succeeded
+  fn value(sim: &Simulation, id: i32) -> Option<String> {
+    sim
+      .reply(id)
+      .and_then(|reply| reply.get("value"))
+      .and_then(|value| value.as_str())
+      .map(String::from)
+  }
+
+  #[test]
+  fn ring_forms_in_id_order() {
+    let sim = formed_ring(1, &NODES);
+    let mut by_id: Vec<&str> = NODES.to_vec();
+    by_id.sort_by_key(|name| hash::hash(name, 160));
+    for (i, name) in by_id.iter().enumerate() {
+      let expected = by_id[(i + 1) % by_id.len()];
+      assert_eq!(
+        sim.successors(name),
+        vec![(name.to_string(), expected.to_string())]
+      );
+    }
+  }
+
+  #[test]
+  fn every_node_reads_what_any_node_wrote() {
+    let mut sim = formed_ring(2, &NODES);
+    for (i, key) in ["X", "Y", "Z"].iter().enumerate() {
+      let id = sim.set(NODES[i], key, &i.to_string());
+      sim.run_for(1_000);
+      assert!(sim.reply(id).is_some_and(|r| r.get("error").is_none()));
+    }
+    for name in NODES.iter() {
+      for (i, key) in ["X", "Y", "Z"].iter().enumerate() {
+        let id = sim.get(name, key);
+        sim.run_for(1_000);
+        assert_eq!(value(&sim, id), Some(i.to_string()));
+      }
+    }
+  }
+
+  #[test]
+  fn keys_survive_a_failed_node() {
+    let mut sim = formed_ring(3, &NODES);
+    let keys = ["A", "B", "C", "D", "E", "F"];
+    for key in keys.iter() {
+      sim.set("node-1", key, key);
+    }
+    sim.run_for(15_000);
+    sim.fail("node-2");
+    sim.run_for(30_000);
+    for key in keys.iter() {
+      let id = sim.get("node-3", key);
+      sim.run_for(1_000);
+      assert_eq!(value(&sim, id), Some(key.to_string()));
+    }
+  }
+
+  /// Returns the node that owns a key on a ring of the given nodes, followed by the
+  /// nodes holding its replicas
+  fn copies_of<'a>(key: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let mut by_id: Vec<&str> = names.to_vec();
+    by_id.sort_by_key(|name| hash::hash(name, 160));
+    let key = hash::hash(key, 160);
+    let owner = by_id
+      .iter()
+      .position(|name| hash::hash(name, 160) >= key)
+      .unwrap_or(0);
+    (0..REPLICATION_FACTOR.min(names.len()))
+      .map(|i| by_id[(owner + i) % by_id.len()])
+      .collect()
+  }
+
+  #[test]
+  fn deleted_key_stays_deleted_when_its_owner_fails_and_returns() {
+    let mut sim = formed_ring(13, &NODES);
+    let owner = copies_of("X", &NODES)[0];
+    sim.set(NODES[0], "X", "1");
+    sim.run_for(15_000);
+    let id = sim.delete(NODES[1], "X");
+    sim.run_for(1_000);
+    assert!(sim.reply(id).is_some_and(|r| r.get("error").is_none()));
+
+    // The replica promoted in the owner's place holds the deletion, not the old value
+    sim.fail(owner);
+    sim.run_for(30_000);
+    for name in NODES.iter().filter(|name| **name != owner) {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert!(sim.reply(id).unwrap().get("error").is_some(), "from {}", name);
+    }
+
+    // The keys transferred back to the owner when it rejoins do not bring the value back
+    sim.recover(owner);
+    sim.run_for(30_000);
+    for name in NODES.iter() {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert!(sim.reply(id).unwrap().get("error").is_some(), "from {}", name);
+    }
+  }
+
+  #[test]
+  fn tombstone_is_collected_only_once_every_replica_holder_has_seen_it() {
+    let mut sim = formed_ring(14, &NODES);
+    let copies = copies_of("X", &NODES);
+    let (owner, last) = (copies[0], copies[2]);
+    sim.set(NODES[0], "X", "1");
+    sim.run_for(15_000);
+
+    // The last replica holder does not hear of the deletion while it is cut off from the owner
+    sim.create_partition("p1", &[owner], Some(&[last]));
+    sim.delete(NODES[0], "X");
+    sim.run_for(1_000);
+    assert!(sim.tracks_tombstone(owner, "X"));
+    assert!(sim.record(owner, "X").is_some_and(|r| r.is_tombstone()));
+    assert!(sim
+      .replica_records(last, "X")
+      .iter()
+      .all(|r| !r.is_tombstone()));
+
+    // Once it has seen the deletion, the tombstone is dropped everywhere
+    sim.remove_partition("p1");
+    sim.run_for(20_000);
+    assert!(!sim.tracks_tombstone(owner, "X"));
+    for name in copies.iter() {
+      assert!(sim.record(name, "X").is_none(), "on {}", name);
+      assert!(sim.replica_records(name, "X").is_empty(), "on {}", name);
+    }
+  }
+
+  /// Returns the values of every replica of a key a node holds
+  fn replica_values(sim: &Simulation, name: &str, key: &str) -> Vec<String> {
+    let records = sim.replica_records(name, key);
+    records.iter().flat_map(|r| r.values()).collect()
+  }
+
+  #[test]
+  fn replica_that_misses_an_update_asks_for_a_resync_and_catches_up() {
+    let mut sim = formed_ring(16, &NODES);
+    let copies = copies_of("X", &NODES);
+    let (owner, holder) = (copies[0], copies[1]);
+    let other = (0..)
+      .map(|i| format!("Y{}", i))
+      .find(|key| copies_of(key, &NODES)[0] == owner)
+      .unwrap();
+    sim.set(NODES[0], "X", "1");
+    sim.run_for(15_000);
+
+    // The update for the second write never reaches the holder, which keeps the old value
+    sim.drop_next("replicate", owner, holder);
+    sim.set(NODES[0], "X", "2");
+    sim.run_for(500);
+    assert_eq!(replica_values(&sim, holder, "X"), vec!["1"]);
+
+    // The next update skips a sequence number, so the holder asks for the whole replica
+    let resyncs = sim.sent("resyncRequest");
+    sim.set(NODES[0], &other, "3");
+    sim.run_for(500);
+    assert!(sim.sent("resyncRequest") > resyncs);
+    assert_eq!(replica_values(&sim, holder, "X"), vec!["2"]);
+  }
+
+  #[test]
+  fn successor_cut_off_from_its_predecessor_only_is_kept_alive_by_indirect_probes() {
+    let mut sim = formed_ring(18, &NODES);
+    let node = NODES[0];
+    let successor = sim.successors(node)[0].1.clone();
+    sim.create_partition("link", &[node], Some(&[&successor]));
+
+    // Other nodes still reach each of them, so neither is declared failed by the other
+    for _ in 0..300 {
+      sim.run_for(100);
+      assert_eq!(sim.successors(node)[0].1, successor);
+      assert_eq!(sim.predecessors(&successor)[0].1.as_deref(), Some(node));
+    }
+  }
+
+  #[test]
+  fn successor_no_prober_reaches_is_declared_failed() {
+    let mut sim = formed_ring(19, &NODES);
+    let node = NODES[0];
+    let successor = sim.successors(node)[0].1.clone();
+    sim.create_partition("alone", &[node], None);
+    sim.run_for(30_000);
+    assert_ne!(sim.successors(node)[0].1, successor);
+  }
+
+  #[test]
+  fn successor_of_a_failed_node_takes_its_keys_over_without_being_notified() {
+    let mut sim = formed_ring(20, &NODES);
+    let copies = copies_of("X", &NODES);
+    let (owner, successor) = (copies[0], copies[1]);
+    let pred = sim.predecessors(owner)[0].1.clone().unwrap();
+    sim.set(NODES[0], "X", "1");
+    sim.run_for(15_000);
+
+    // The owner's predecessor never gets to tell the successor that the owner failed
+    sim.drop_next("notify", &pred, successor);
+    sim.fail(owner);
+    let mut waited = 0;
+    while sim.predecessors(successor)[0].1.as_deref() == Some(owner) {
+      assert!(waited < 30_000, "{} still has {} as predecessor", successor, owner);
+      sim.run_for(100);
+      waited += 100;
+    }
+    sim.run_for(1_000);
+    assert!(sim.record(successor, "X").is_some());
+    for name in NODES.iter().filter(|name| **name != owner) {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert_eq!(value(&sim, id).as_deref(), Some("1"), "from {}", name);
+    }
+  }
+
+  #[test]
+  fn recovered_node_rejoins_the_ring() {
+    let mut sim = formed_ring(6, &NODES);
+    let before: Vec<Vec<(String, String)>> = NODES.iter().map(|n| sim.successors(n)).collect();
+    sim.fail("node-2");
+    sim.run_for(30_000);
+    assert!(NODES
+      .iter()
+      .filter(|n| **n != "node-2")
+      .all(|n| sim.successors(n)[0].1 != "node-2"));
+    sim.recover("node-2");
+    sim.run_for(30_000);
+    let after: Vec<Vec<(String, String)>> = NODES.iter().map(|n| sim.successors(n)).collect();
+    assert_eq!(before, after);
+  }
+
+  #[test]
+  fn ring_heals_after_a_partition() {
+    let mut sim = formed_ring(4, &NODES);
+    sim.create_partition("p1", &["node-1", "node-2"], None);
+    sim.run_for(30_000);
+    sim.remove_partition("p1");
+    sim.run_for(30_000);
+    let id = sim.set("node-1", "K", "healed");
+    sim.run_for(1_000);
+    assert!(sim.reply(id).is_some());
+    for name in NODES.iter() {
+      let id = sim.get(name, "K");
+      sim.run_for(1_000);
+      assert_eq!(value(&sim, id), Some(String::from("healed")));
+    }
+  }
+
+  #[test]
+  fn same_seed_gives_same_run() {
+    let run = |seed| {
+      let mut sim = formed_ring(seed, &NODES);
+      let ids: Vec<i32> = ["X", "Y", "Z"]
+        .iter()
+        .map(|key| sim.set("node-2", key, key))
+        .collect();
+      sim.fail("node-3");
+      sim.run_for(10_000);
+      let gets: Vec<i32> = ["X", "Y", "Z"]
+        .iter()
+        .map(|key| sim.get("node-4", key))
+        .collect();
+      sim.run_for(5_000);
+      let replies: Vec<Option<serde_json::Value>> = ids
+        .iter()
+        .chain(gets.iter())
+        .map(|id| sim.reply(*id).cloned())
+        .collect();
+      (replies, sim.successors("node-1"))
+    };
+    assert_eq!(run(5), run(5));
+  }
+
+  #[test]
+  fn concurrent_clients_see_a_linearizable_history() {
+    let mut sim = formed_ring(11, &NODES);
+    let mut rng = StdRng::seed_from_u64(11);
+    for i in 0..60 {
+      let node = NODES[rng.gen_range(0, NODES.len())];
+      let key = ["X", "Y"][rng.gen_range(0, 2)];
+      if rng.gen_bool(0.5) {
+        sim.set(node, key, &i.to_string());
+      } else {
+        sim.get(node, key);
+      }
+      sim.run_for(rng.gen_range(0, 100));
+    }
+    sim.run_for(WRITE_TIMEOUT);
+    let history = sim.history();
+    assert_eq!(history.len(), 60);
+    assert!(history.iter().all(|o| o.ret.is_some()));
+    if let Err(violation) = history::check(&history) {
+      panic!("{}", violation);
+    }
+  }
+
+  #[test]
+  fn quorum_clients_see_a_linearizable_history_across_a_node_failure() {
+    let settings = Settings {
+      write_quorum: REPLICATION_FACTOR,
+      read_quorum: REPLICATION_FACTOR,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(21, &NODES, settings);
+    let mut rng = StdRng::seed_from_u64(21);
+    let owner = copies_of("X", &NODES)[0];
+    let mut live = NODES.to_vec();
+    let mut failed_at = 0;
+    for i in 0..120 {
+      if i == 40 {
+        sim.fail(owner);
+        live.retain(|name| *name != owner);
+        failed_at = sim.now();
+      }
+      let node = live[rng.gen_range(0, live.len())];
+      let key = ["X", "Y"][rng.gen_range(0, 2)];
+      if rng.gen_bool(0.5) {
+        sim.set(node, key, &i.to_string());
+      } else {
+        sim.get(node, key);
+      }
+      sim.run_for(rng.gen_range(0, 300));
+    }
+    sim.run_for(WRITE_TIMEOUT);
+    let history = sim.history();
+    assert!(history
+      .iter()
+      .any(|o| o.key == "X" && o.call > failed_at && o.ret.is_some()));
+    if let Err(violation) = history::check(&history) {
+      panic!("{}", violation);
+    }
+  }
+
+  #[test]
+  fn stale_read_after_a_failure_is_reported_with_only_the_operations_that_show_it() {
+    let mut sim = formed_ring(22, &NODES);
+    let copies = copies_of("X", &NODES);
+    let owner = copies[0];
+    let reader = *NODES.iter().find(|name| **name != owner).unwrap();
+    sim.set(NODES[0], "X", "1");
+    sim.set(NODES[1], "Y", "1");
+    sim.run_for(15_000);
+    sim.get(reader, "X");
+    sim.get(reader, "Y");
+    sim.run_for(1_000);
+
+    // With single copy writes, the owner fails before any replica holder hears of the second
+    for holder in copies[1..].iter() {
+      sim.drop_next("replicate", owner, holder);
+    }
+    let newer = sim.set(NODES[0], "X", "2");
+    sim.run_for(500);
+    sim.fail(owner);
+    sim.run_for(30_000);
+    let stale = sim.get(reader, "X");
+    sim.run_for(1_000);
+    assert_eq!(value(&sim, stale).as_deref(), Some("1"));
+
+    let violation = history::check(&sim.history()).unwrap_err();
+    assert_eq!(violation.key, "X");
+    let ids: Vec<i32> = violation.operations.iter().map(|o| o.id).collect();
+    assert_eq!(ids, vec![1, newer, stale]);
+  }
+
+  #[test]
+  fn malformed_messages_are_counted_and_answered_without_stopping_the_node() {
+    let mut sim = formed_ring(15, &["node-1", "node-2"]);
+    let garbage = [
+      "not json",
+      r#"{"type": "frobnicate", "id": 1001}"#,
+      r#"{"type": "set", "id": 1002, "key": "X"}"#,
+      r#"{"type": "get", "id": 1003, "key": 5}"#,
+      r#"{"type": "delete", "id": "1004", "key": "X"}"#,
+      r#"{"type": "findSucc", "source": "node-2", "destination": "node-1"}"#,
+    ];
+    for contents in garbage.iter() {
+      sim.send_raw("node-1", contents);
+    }
+    // A reply meant for a client parses, but a node has nothing to do with it
+    sim.send_raw("node-1", r#"{"type": "setResponse", "id": 1005, "key": "X", "value": "1"}"#);
+    sim.run_for(100);
+    assert_eq!(sim.error_count("node-1", "malformed"), garbage.len() as u64);
+    assert_eq!(sim.error_count("node-1", "unhandled"), 1);
+
+    // Client requests with an id are told why they were dropped
+    for (id, kind) in [(1002, "setResponse"), (1003, "getResponse")].iter() {
+      let reply = sim.reply(*id).unwrap();
+      assert_eq!(reply["type"], *kind);
+      assert!(reply["error"]
+        .as_str()
+        .is_some_and(|e| e.starts_with("Malformed request: ")));
+    }
+    assert!(sim.reply(1001).is_none());
+    assert!(sim.reply(1004).is_none());
+    assert!(sim.reply(1005).is_none());
+
+    // The node still serves requests
+    let id = sim.set("node-1", "X", "1");
+    sim.run_for(1_000);
+    assert!(sim.reply(id).is_some_and(|r| r.get("error").is_none()));
+    let id = sim.get("node-2", "X");
+    sim.run_for(1_000);
+    assert_eq!(value(&sim, id).as_deref(), Some("1"));
+  }
+
+  /// Returns the virtual nodes met by following successors from the first node's first
+  /// virtual node until the walk comes back to it, or meets a node twice
+  fn walk_ring(sim: &Simulation, names: &[&str]) -> Vec<String> {
+    let successors: HashMap<String, String> =
+      names.iter().flat_map(|name| sim.successors(name)).collect();
+    let start = sim.successors(names[0])[0].0.to_string();
+    let mut walk = vec![start.to_string()];
+    let mut current = &successors[&start];
+    while *current != start && !walk.contains(current) {
+      walk.push(current.to_string());
+      current = &successors[current];
+    }
+    walk
+  }
+
+  proptest! {
+    #![proptest_config(ProptestConfig::with_cases(4))]
+    #[test]
+    fn lookups_over_the_fingers_nodes_built_reach_the_owner_in_log_hops(
+      seed in any::<u64>(),
+      order in Just((1..=12).collect::<Vec<usize>>()).prop_shuffle(),
+      key_seeds in prop::collection::vec(any::<u64>(), 20),
+    ) {
+      // Nodes join in a random order, and fix their fingers one at a time as they go
+      let names: Vec<String> = order.iter().map(|i| format!("node-{}", i)).collect();
+      let names: Vec<&str> = names.iter().map(String::as_str).collect();
+      let settings = Settings {
+        ring_bits: 16,
+        ..Settings::default()
+      };
+      let sim = formed_ring_with(seed, &names, settings);
+
+      let mut entries: Vec<NodeEntry> = names.iter().flat_map(|name| sim.entries(name)).collect();
+      entries.sort_by_key(|entry| entry.id);
+      let bound = 2 * (entries.len() as f64).log2().ceil() as usize;
+      for (i, key_seed) in key_seeds.iter().enumerate() {
+        let key = hash::hash(&key_seed.to_string(), settings.ring_bits);
+        let owner = entries.iter().find(|e| e.id >= key).unwrap_or(&entries[0]);
+        let from = &entries[i % entries.len()].node_name;
+        let (found, hops) = sim.lookup(from, key);
+        prop_assert_eq!(&found.node_name, &owner.node_name, "lookup of {} from {}", key, from);
+        prop_assert!(hops <= bound, "lookup of {} from {} took {} hops", key, from, hops);
+      }
+    }
+  }
+
+  #[test]
+  fn colliding_ids_are_moved_apart() {
+    // node-15 and node-17 hash to the same id on a ring of 2^8 ids
+    let names = ["node-15", "node-17", "node-1", "node-2"];
+    assert_eq!(hash::hash("node-15", 8), hash::hash("node-17", 8));
+    let settings = Settings {
+      ring_bits: 8,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(4, &names, settings);
+    assert_eq!(walk_ring(&sim, &names).len(), names.len());
+
+    let ids: Vec<i32> = ["X", "Y", "Z"]
+      .iter()
+      .map(|key| sim.set("node-15", key, key))
+      .collect();
+    sim.run_for(1_000);
+    assert!(ids
+      .iter()
+      .all(|id| sim.reply(*id).is_some_and(|r| r.get("error").is_none())));
+    let gets: Vec<i32> = ["X", "Y", "Z"]
+      .iter()
+      .map(|key| sim.get("node-17", key))
+      .collect();
+    sim.run_for(1_000);
+    for (key, id) in ["X", "Y", "Z"].iter().zip(gets) {
+      assert_eq!(value(&sim, id).as_deref(), Some(*key));
+    }
+  }
+
+  #[test]
+  fn virtual_nodes_form_one_ring_and_serve_reads() {
+    let names = ["node-1", "node-2", "node-3"];
+    let settings = Settings {
+      vnodes: 4,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(6, &names, settings);
+
+    // Every virtual node of every node is on the ring, in id order
+    let walk = walk_ring(&sim, &names);
+    let mut by_id: Vec<String> = names
+      .iter()
+      .flat_map(|name| (0..4).map(move |i| node::vnode_name(name, i)))
+      .collect();
+    by_id.sort_by_key(|name| hash::hash(name, 160));
+    let start = by_id.iter().position(|name| *name == walk[0]).unwrap();
+    by_id.rotate_left(start);
+    assert_eq!(walk, by_id);
+
+    let keys: Vec<String> = (0..12).map(|i| format!("key-{}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+      let id = sim.set(names[i % names.len()], key, &i.to_string());
+      sim.run_for(500);
+      assert!(sim.reply(id).is_some_and(|r| r.get("error").is_none()));
+    }
+    for name in names.iter() {
+      let ids: Vec<i32> = keys.iter().map(|key| sim.get(name, key)).collect();
+      sim.run_for(1_000);
+      for (i, id) in ids.into_iter().enumerate() {
+        assert_eq!(
+          value(&sim, id),
+          Some(i.to_string()),
+          "{} from {}",
+          keys[i],
+          name
+        );
+      }
+    }
+  }
+
+  /// Returns the sorted values a Get was answered with under vector-clock versioning
+  fn values(sim: &Simulation, id: i32) -> Vec<String> {
+    let mut values: Vec<String> = sim
+      .reply(id)
+      .and_then(|reply| reply.get("values"))
+      .and_then(|values| values.as_array())
+      .map(|values| {
+        values
+          .iter()
+          .filter_map(|value| value.as_str().map(String::from))
+          .collect()
+      })
+      .unwrap_or_default();
+    values.sort();
+    values
+  }
+
+  #[test]
+  fn concurrent_writes_are_kept_as_siblings_until_resolved() {
+    let settings = Settings {
+      versioning: Versioning::Vector,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(7, &NODES, settings);
+    let key = hash::hash("X", 160);
+    let mut by_id: Vec<&str> = NODES.to_vec();
+    by_id.sort_by_key(|name| hash::hash(name, 160));
+    let owner = *by_id
+      .iter()
+      .find(|name| hash::hash(name, 160) >= key)
+      .unwrap_or(&by_id[0]);
+    let other = *NODES.iter().find(|name| **name != owner).unwrap();
+
+    sim.set(other, "X", "a");
+    sim.run_for(15_000);
+    // The next owner never sees the second write's client read the first one
+    sim.fail(owner);
+    sim.run_for(30_000);
+    sim.set(other, "X", "b");
+    sim.run_for(1_000);
+
+    let id = sim.get(other, "X");
+    sim.run_for(1_000);
+    assert_eq!(values(&sim, id), vec!["a", "b"]);
+
+    // A write with the context of the read replaces both siblings
+    let context = sim.reply(id).unwrap()["context"].clone();
+    let context: VectorClock = serde_json::from_value(context).unwrap();
+    sim.set_in_context(other, "X", "c", &context);
+    sim.run_for(1_000);
+    let id = sim.get(other, "X");
+    sim.run_for(1_000);
+    assert_eq!(values(&sim, id), vec!["c"]);
+  }
+
+  #[test]
+  fn blind_writes_through_one_owner_are_kept_as_siblings() {
+    let settings = Settings {
+      versioning: Versioning::Vector,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(13, &NODES, settings);
+    // Neither client read the key first, so the owner stores both writes side by side
+    sim.set(NODES[0], "X", "a");
+    sim.set(NODES[1], "X", "b");
+    sim.run_for(1_000);
+
+    for name in NODES.iter() {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert_eq!(values(&sim, id), vec!["a", "b"], "from {}", name);
+    }
+  }
+
+  #[test]
+  fn quorum_writes_and_reads_are_answered_by_every_copy() {
+    let settings = Settings {
+      write_quorum: REPLICATION_FACTOR,
+      read_quorum: REPLICATION_FACTOR,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(8, &NODES, settings);
+    let keys = ["A", "B", "C", "D", "E", "F"];
+    let ids: Vec<i32> = keys
+      .iter()
+      .enumerate()
+      .map(|(i, key)| sim.set(NODES[i % NODES.len()], key, key))
+      .collect();
+    sim.run_for(1_000);
+    for id in ids {
+      assert!(sim.reply(id).is_some_and(|r| r.get("error").is_none()));
+    }
+
+    // Every copy has the writes, so a read from all of them finds them
+    for name in NODES.iter() {
+      let ids: Vec<i32> = keys.iter().map(|key| sim.get(name, key)).collect();
+      sim.run_for(1_000);
+      for (key, id) in keys.iter().zip(ids) {
+        assert_eq!(value(&sim, id).as_deref(), Some(*key));
+      }
+    }
+  }
+
+  #[test]
+  fn quorum_read_returns_the_newest_copy_and_repairs_a_stale_one() {
+    let settings = Settings {
+      read_quorum: REPLICATION_FACTOR,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(17, &NODES, settings);
+    let copies = copies_of("X", &NODES);
+    let (owner, holder) = (copies[0], copies[1]);
+    sim.set(NODES[0], "X", "1");
+    sim.run_for(15_000);
+    sim.drop_next("replicate", owner, holder);
+    sim.set(NODES[0], "X", "2");
+    sim.run_for(500);
+    assert_eq!(replica_values(&sim, holder, "X"), vec!["1"]);
+
+    // The read hears from every copy, answers with the newest, and repairs the stale one
+    let repairs = sim.sent("readRepair");
+    let id = sim.get(holder, "X");
+    sim.run_for(500);
+    assert_eq!(value(&sim, id).as_deref(), Some("2"));
+    assert!(sim.sent("readRepair") > repairs);
+    assert_eq!(replica_values(&sim, holder, "X"), vec!["2"]);
+  }
+
+  #[test]
+  fn quorum_larger_than_the_ring_waits_only_for_the_copies_it_holds() {
+    let names = ["node-1", "node-2"];
+    let settings = Settings {
+      write_quorum: REPLICATION_FACTOR,
+      read_quorum: REPLICATION_FACTOR,
+      ..Settings::default()
+    };
+    let mut sim = formed_ring_with(9, &names, settings);
+    let set = sim.set("node-1", "X", "1");
+    sim.run_for(1_000);
+    assert!(sim.reply(set).is_some_and(|r| r.get("error").is_none()));
+    let get = sim.get("node-2", "X");
+    sim.run_for(1_000);
+    assert_eq!(value(&sim, get).as_deref(), Some("1"));
+  }
+
+  #[test]
+  fn quorum_out_of_range_is_answered_with_an_error() {
+    let mut sim = formed_ring(23, &NODES);
+    let requests = [
+      r#"{"type": "set", "id": 1001, "destination": "node-1", "key": "X", "value": "1", "w": 4}"#,
+      r#"{"type": "delete", "id": 1002, "destination": "node-1", "key": "X", "w": 0}"#,
+      r#"{"type": "get", "id": 1003, "destination": "node-1", "key": "X", "r": 4}"#,
+    ];
+    for request in requests.iter() {
+      sim.send_raw("node-1", request);
+    }
+    sim.run_for(1_000);
+    let errors: Vec<&str> = (1001..=1003)
+      .map(|id| sim.reply(id).unwrap()["error"].as_str().unwrap())
+      .collect();
+    assert_eq!(
+      errors,
+      vec![
+        "Cannot store key: X: w must be between 1 and the replication factor 3, got 4",
+        "Cannot delete key: X: w must be between 1 and the replication factor 3, got 0",
+        "Cannot read key: X: r must be between 1 and the replication factor 3, got 4",
+      ]
+    );
+    assert_eq!(sim.error_count("node-1", "range"), 3);
+
+    // Nothing was written
+    let id = sim.get("node-2", "X");
+    sim.run_for(1_000);
+    assert!(sim.reply(id).unwrap().get("error").is_some());
+  }
+
+  #[test]
+  fn write_to_an_unreachable_owner_is_accepted_as_a_hint_and_handed_off() {
+    let mut sim = formed_ring(10, &NODES);
+    let key = hash::hash("X", 160);
+    let mut by_id: Vec<&str> = NODES.to_vec();
+    by_id.sort_by_key(|name| hash::hash(name, 160));
+    let owner = by_id
+      .iter()
+      .position(|name| hash::hash(name, 160) >= key)
+      .unwrap_or(0);
+    // The owner's predecessor answers the lookup itself, so the write is sent to the owner
+    let coordinator = by_id[(owner + by_id.len() - 1) % by_id.len()];
+    let owner = by_id[owner];
+
+    sim.fail(owner);
+    let id = sim.set(coordinator, "X", "1");
+    sim.run_for(WRITE_TIMEOUT + 1_000);
+    // No copy a read can reach holds the write yet, so the client is told it was
+    // accepted but not confirmed, and the write is kept as a hint
+    let reply = sim.reply(id).unwrap();
+    assert_eq!(reply["type"], "setResponse");
+    assert!(reply.get("error").is_none());
+    assert_eq!(reply["hinted"], true);
+    assert_eq!(sim.hints(coordinator), 1);
+
+    // The hint is handed to the node that takes over the key while the owner stays down,
+    // and dropped once it is stored
+    sim.run_for(20_000);
+    assert_eq!(sim.hints(coordinator), 0);
+    for name in NODES.iter().filter(|name| **name != owner) {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert_eq!(value(&sim, id).as_deref(), Some("1"), "from {}", name);
+    }
+
+    // The owner gets the key back once it returns
+    sim.recover(owner);
+    sim.run_for(20_000);
+    for name in NODES.iter() {
+      let id = sim.get(name, "X");
+      sim.run_for(1_000);
+      assert_eq!(value(&sim, id).as_deref(), Some("1"), "from {}", name);
+    }
+  }
+
+  #[test]
+  fn leaving_node_hands_its_keys_over() {
+    let mut sim = formed_ring(11, &NODES);
+    let keys: Vec<String> = (0..12).map(|i| format!("key-{}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+      sim.set(NODES[i % NODES.len()], key, &i.to_string());
+    }
+    sim.run_for(1_000);
+
+    let id = sim.leave("node-2");
+    sim.run_for(1_000);
+    let reply = sim.reply(id).unwrap();
+    assert_eq!(reply["type"], "leaveResponse");
+    assert!(reply.get("error").is_none());
+
+    // The remaining nodes still form one ring and read every key, well before
+    // the leaving node could have been detected as failed
+    let rest = ["node-1", "node-3", "node-4"];
+    assert_eq!(walk_ring(&sim, &rest).len(), rest.len());
+    for name in rest.iter() {
+      let ids: Vec<i32> = keys.iter().map(|key| sim.get(name, key)).collect();
+      sim.run_for(500);
+      for (i, id) in ids.into_iter().enumerate() {
+        assert_eq!(
+          value(&sim, id),
+          Some(i.to_string()),
+          "{} from {}",
+          keys[i],
+          name
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn leave_is_refused_while_no_other_node_can_take_the_keys() {
+    let mut sim = formed_ring(12, &["node-1"]);
+    sim.set("node-1", "X", "1");
+    sim.run_for(1_000);
+
+    let id = sim.leave("node-1");
+    sim.run_for(1_000);
+    assert!(sim.reply(id).unwrap().get("error").is_some());
+    let id = sim.get("node-1", "X");
+    sim.run_for(1_000);
+    assert_eq!(value(&sim, id).as_deref(), Some("1"));
+  }
+}
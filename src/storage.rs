@@ -0,0 +1,750 @@
+// File: storage.rs
+//
+// The purpose of this file is to define where a node keeps its key/value store
+// and replica key/value stores, either in memory or durably on disk.
+
+use crate::clock::{Dot, VectorClock, Version};
+use crate::hash::Id;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Number of log entries written before the log is compacted into a snapshot
+pub const COMPACTION_THRESHOLD: usize = 1000;
+
+/// Storage shared by every virtual node of a process
+pub type SharedStorage = Arc<RwLock<Box<dyn Storage>>>;
+
+/// A stored value, or a tombstone recording that the key was deleted,
+/// along with the version of the write that produced it
+/// Tombstones are kept so that a deletion reaches replicas instead of
+/// being undone by an older replicated value
+/// Under vector-clock versioning the record also keeps every concurrent write
+/// as a sibling, and the value and version are those of the newest sibling
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+  pub value: Option<String>,
+  pub version: Version,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub siblings: Vec<Sibling>,
+}
+
+/// One of the concurrent writes to a key, with the writes it had seen when it was made,
+/// which it replaces, and the dot identifying the write itself
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sibling {
+  pub value: Option<String>,
+  pub version: Version,
+  pub clock: VectorClock,
+  pub dot: Dot,
+}
+
+impl Record {
+  /// Returns a new Record holding a value
+  ///
+  /// # Arguments
+  ///
+  /// * `value` - the value being stored
+  /// * `version` - the version of the write
+  pub fn new(value: String, version: Version) -> Record {
+    Record {
+      value: Some(value),
+      version,
+      siblings: Vec::new(),
+    }
+  }
+
+  /// Returns a new tombstone Record
+  ///
+  /// # Arguments
+  ///
+  /// * `version` - the version of the deletion
+  pub fn tombstone(version: Version) -> Record {
+    Record {
+      value: None,
+      version,
+      siblings: Vec::new(),
+    }
+  }
+
+  /// Returns a new Record from a single write versioned with a vector clock
+  ///
+  /// # Arguments
+  ///
+  /// * `value` - the value being stored, or None for a deletion
+  /// * `version` - the version of the write
+  /// * `clock` - the writes the client had seen, which the write replaces
+  /// * `dot` - the write itself
+  pub fn causal(value: Option<String>, version: Version, clock: VectorClock, dot: Dot) -> Record {
+    Record::from_siblings(vec![Sibling {
+      value,
+      version,
+      clock,
+      dot,
+    }])
+  }
+
+  /// Returns a Record holding a set of concurrent writes
+  ///
+  /// # Arguments
+  ///
+  /// * `siblings` - the concurrent writes, at least one
+  fn from_siblings(mut siblings: Vec<Sibling>) -> Record {
+    siblings.sort_by(|a, b| a.version.cmp(&b.version));
+    let newest = siblings.last().expect("record needs a sibling").clone();
+    Record {
+      value: newest.value,
+      version: newest.version,
+      siblings,
+    }
+  }
+
+  /// Returns whether the Record is newer than another, so it wins a merge
+  ///
+  /// # Arguments
+  ///
+  /// * `other` - the Record being compared against
+  pub fn supersedes(&self, other: &Record) -> bool {
+    self.version > other.version
+  }
+
+  /// Returns the result of merging another Record into this one, or None if
+  /// the other Record adds nothing
+  /// Two Records without siblings keep the newest version, otherwise every write
+  /// no other write has seen is kept, by comparing the writes' clocks
+  ///
+  /// # Arguments
+  ///
+  /// * `other` - the Record being merged in
+  pub fn merge(&self, other: &Record) -> Option<Record> {
+    if self.siblings.is_empty() && other.siblings.is_empty() {
+      return if other.supersedes(self) {
+        Some(other.clone())
+      } else {
+        None
+      };
+    }
+
+    let mut kept: Vec<Sibling> = Vec::new();
+    for sibling in self.as_siblings().iter().chain(other.as_siblings().iter()) {
+      if kept
+        .iter()
+        .any(|k| k.dot == sibling.dot || k.clock.contains(&sibling.dot))
+      {
+        continue;
+      }
+      kept.retain(|k| !sibling.clock.contains(&k.dot));
+      kept.push(sibling.clone());
+    }
+    let merged = Record::from_siblings(kept);
+    if merged == *self {
+      None
+    } else {
+      Some(merged)
+    }
+  }
+
+  /// Returns the Record's siblings, or for a Record without any, a sibling standing for
+  /// its write: one that has seen no other write, with a dot named after its version,
+  /// which no node is named like, so that only a write that has seen it replaces it
+  fn as_siblings(&self) -> Vec<Sibling> {
+    if !self.siblings.is_empty() {
+      return self.siblings.clone();
+    }
+    let version = &self.version;
+    vec![Sibling {
+      value: self.value.clone(),
+      version: version.clone(),
+      clock: VectorClock::default(),
+      dot: Dot {
+        node_name: format!("{}:{}:{}", version.writer, version.wall, version.logical),
+        count: 1,
+      },
+    }]
+  }
+
+  /// Returns the values of every sibling that is not a deletion, or the
+  /// Record's value if it has no siblings
+  pub fn values(&self) -> Vec<String> {
+    if self.siblings.is_empty() {
+      return self.value.iter().cloned().collect();
+    }
+    self
+      .siblings
+      .iter()
+      .filter_map(|s| s.value.clone())
+      .collect()
+  }
+
+  /// Returns the causal context of the Record: a clock that has seen every sibling
+  /// A write passing this context back replaces every sibling
+  pub fn context(&self) -> VectorClock {
+    let mut context = VectorClock::default();
+    for sibling in self.siblings.iter() {
+      context.merge(&sibling.clock);
+      context.add(&sibling.dot);
+    }
+    context
+  }
+
+  /// Returns whether the Record is a tombstone, meaning every sibling is a deletion
+  pub fn is_tombstone(&self) -> bool {
+    self.values().is_empty()
+  }
+}
+
+/// Operations a node performs on its key/value store and replica key/value stores
+/// Mutations return an error when they could not be made durable, in which case
+/// the store is left as it was
+pub trait Storage: Send + Sync {
+  /// Returns the record for a given key, if it exists
+  fn get(&self, key: &str) -> Option<Record>;
+
+  /// Sets the record for a given key
+  fn set(&mut self, key: String, record: Record) -> io::Result<()>;
+
+  /// Removes a key and its record entirely, returning the record if it existed
+  fn remove(&mut self, key: &str) -> io::Result<Option<Record>>;
+
+  /// Returns every key/record pair in the store
+  fn entries(&self) -> Vec<(String, Record)>;
+
+  /// Replaces the replica key/value store for a given node's id
+  fn set_replica(&mut self, id: Id, kvs: HashMap<String, Record>) -> io::Result<()>;
+
+  /// Returns the ids of every node that has a replica stored locally
+  fn replica_ids(&self) -> Vec<Id>;
+
+  /// Returns the replica key/value store for a given node's id, if it exists
+  fn replica(&self, id: Id) -> Option<HashMap<String, Record>>;
+
+  /// Returns the record for a given key in the replica for a given node's id, if it exists
+  fn replica_entry(&self, id: Id, key: &str) -> Option<Record>;
+
+  /// Sets the record for a given key in the replica for a given node's id,
+  /// or removes the key if there is no record
+  fn set_replica_entry(&mut self, id: Id, key: String, record: Option<Record>) -> io::Result<()>;
+}
+
+/// The available storage backends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+  Memory,
+  Disk,
+}
+
+impl FromStr for StorageKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<StorageKind, String> {
+    match s {
+      "memory" => Ok(StorageKind::Memory),
+      "disk" => Ok(StorageKind::Disk),
+      _ => Err(format!("unknown storage backend: {}", s)),
+    }
+  }
+}
+
+/// Key/value store and replica key/value stores held in memory
+#[derive(Default, Serialize, Deserialize)]
+pub struct MemoryStorage {
+  store: HashMap<String, Record>,
+  replica_store: HashMap<Id, HashMap<String, Record>>,
+}
+
+impl Storage for MemoryStorage {
+  fn get(&self, key: &str) -> Option<Record> {
+    self.store.get(key).cloned()
+  }
+
+  fn set(&mut self, key: String, record: Record) -> io::Result<()> {
+    self.store.insert(key, record);
+    Ok(())
+  }
+
+  fn remove(&mut self, key: &str) -> io::Result<Option<Record>> {
+    Ok(self.store.remove(key))
+  }
+
+  fn entries(&self) -> Vec<(String, Record)> {
+    self
+      .store
+      .iter()
+      .map(|(k, r)| (k.to_string(), r.clone()))
+      .collect()
+  }
+
+  fn set_replica(&mut self, id: Id, kvs: HashMap<String, Record>) -> io::Result<()> {
+    self.replica_store.insert(id, kvs);
+    Ok(())
+  }
+
+  fn replica_ids(&self) -> Vec<Id> {
+    self.replica_store.keys().cloned().collect()
+  }
+
+  fn replica(&self, id: Id) -> Option<HashMap<String, Record>> {
+    self.replica_store.get(&id).cloned()
+  }
+
+  fn replica_entry(&self, id: Id, key: &str) -> Option<Record> {
+    self
+      .replica_store
+      .get(&id)
+      .and_then(|kvs| kvs.get(key).cloned())
+  }
+
+  fn set_replica_entry(&mut self, id: Id, key: String, record: Option<Record>) -> io::Result<()> {
+    let kvs = self.replica_store.entry(id).or_default();
+    match record {
+      Some(record) => {
+        kvs.insert(key, record);
+      }
+      None => {
+        kvs.remove(&key);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A single mutation recorded in the write-ahead log
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op")]
+enum LogEntry {
+  Set {
+    key: String,
+    record: Record,
+  },
+  Remove {
+    key: String,
+  },
+  Replica {
+    id: Id,
+    kvs: HashMap<String, Record>,
+  },
+  ReplicaEntry {
+    id: Id,
+    key: String,
+    record: Option<Record>,
+  },
+}
+
+/// Key/value store and replica key/value stores that survive crashes
+/// Every mutation is appended to a write-ahead log and synced before it is applied in memory
+/// Once the log grows past COMPACTION_THRESHOLD entries, the memory state is written
+/// to a snapshot and the log is truncated
+pub struct DiskStorage {
+  dir: PathBuf,
+  memory: MemoryStorage,
+  log: File,
+  log_len: u64,
+  log_entries: usize,
+}
+
+impl DiskStorage {
+  /// Opens the storage in a directory, recovering from its snapshot and log if they exist
+  ///
+  /// # Arguments
+  ///
+  /// * `dir` - Directory holding the snapshot and log
+  pub fn open(dir: &Path) -> io::Result<DiskStorage> {
+    fs::create_dir_all(dir)?;
+
+    let snapshot_path = dir.join("snapshot.json");
+    let mut memory: MemoryStorage = if snapshot_path.exists() {
+      serde_json::from_reader(BufReader::new(File::open(&snapshot_path)?))?
+    } else {
+      MemoryStorage::default()
+    };
+
+    // Replay the log on top of the snapshot
+    // A torn entry can only be the last line, written while the process crashed,
+    // so an entry that cannot be read anywhere else means the log is corrupt
+    let log_path = dir.join("wal.log");
+    let mut log_entries = 0;
+    if log_path.exists() {
+      let lines = BufReader::new(File::open(&log_path)?)
+        .lines()
+        .collect::<io::Result<Vec<String>>>()?;
+      for (n, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+          Ok(entry) => {
+            apply(&mut memory, entry)?;
+            log_entries += 1;
+          }
+          Err(_) if n + 1 == lines.len() => break,
+          Err(e) => {
+            return Err(io::Error::new(
+              io::ErrorKind::InvalidData,
+              format!("corrupt log entry on line {}: {}", n + 1, e),
+            ))
+          }
+        }
+      }
+    }
+
+    let log = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&log_path)?;
+
+    let mut storage = DiskStorage {
+      dir: dir.to_path_buf(),
+      memory,
+      log_len: log.metadata()?.len(),
+      log,
+      log_entries,
+    };
+    // Compacting right away also drops a torn entry from the log
+    storage.compact()?;
+    Ok(storage)
+  }
+
+  /// Appends an entry to the log and applies it once it is durable
+  /// When the entry cannot be made durable, whatever part of it reached the log is cut off
+  /// so that later entries do not follow a torn one, and the memory state is left unchanged
+  /// A failed compaction does not undo the entry, which is already durable, and is
+  /// retried on the next append
+  ///
+  /// # Arguments
+  ///
+  /// * `entry` - The mutation being recorded
+  fn append(&mut self, entry: LogEntry) -> io::Result<()> {
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    if let Err(e) = self
+      .log
+      .write_all(line.as_bytes())
+      .and_then(|_| self.log.sync_data())
+    {
+      let _ = self.log.set_len(self.log_len);
+      return Err(e);
+    }
+    self.log_len += line.len() as u64;
+    apply(&mut self.memory, entry)?;
+
+    self.log_entries += 1;
+    if self.log_entries >= COMPACTION_THRESHOLD {
+      if let Err(e) = self.compact() {
+        eprintln!("Cannot compact log: {}", e);
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes the memory state to a new snapshot, then truncates the log
+  /// The snapshot replaces the old one atomically, so a crash at any point
+  /// leaves either the old snapshot and full log or the new snapshot
+  fn compact(&mut self) -> io::Result<()> {
+    let tmp_path = self.dir.join("snapshot.json.tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut tmp, &self.memory)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, self.dir.join("snapshot.json"))?;
+    File::open(&self.dir)?.sync_all()?;
+
+    self.log.set_len(0)?;
+    self.log.sync_all()?;
+    self.log_len = 0;
+    self.log_entries = 0;
+    Ok(())
+  }
+}
+
+/// Applies a logged mutation to a memory store
+///
+/// # Arguments
+///
+/// * `memory` - The store being mutated
+/// * `entry` - The mutation
+fn apply(memory: &mut MemoryStorage, entry: LogEntry) -> io::Result<()> {
+  match entry {
+    LogEntry::Set { key, record } => memory.set(key, record),
+    LogEntry::Remove { key } => memory.remove(&key).map(|_| ()),
+    LogEntry::Replica { id, kvs } => memory.set_replica(id, kvs),
+    LogEntry::ReplicaEntry { id, key, record } => memory.set_replica_entry(id, key, record),
+  }
+}
+
+impl Storage for DiskStorage {
+  fn get(&self, key: &str) -> Option<Record> {
+    self.memory.get(key)
+  }
+
+  fn set(&mut self, key: String, record: Record) -> io::Result<()> {
+    self.append(LogEntry::Set { key, record })
+  }
+
+  fn remove(&mut self, key: &str) -> io::Result<Option<Record>> {
+    let value = self.memory.get(key);
+    if value.is_some() {
+      self.append(LogEntry::Remove {
+        key: String::from(key),
+      })?;
+    }
+    Ok(value)
+  }
+
+  fn entries(&self) -> Vec<(String, Record)> {
+    self.memory.entries()
+  }
+
+  fn set_replica(&mut self, id: Id, kvs: HashMap<String, Record>) -> io::Result<()> {
+    self.append(LogEntry::Replica { id, kvs })
+  }
+
+  fn replica_ids(&self) -> Vec<Id> {
+    self.memory.replica_ids()
+  }
+
+  fn replica(&self, id: Id) -> Option<HashMap<String, Record>> {
+    self.memory.replica(id)
+  }
+
+  fn replica_entry(&self, id: Id, key: &str) -> Option<Record> {
+    self.memory.replica_entry(id, key)
+  }
+
+  fn set_replica_entry(&mut self, id: Id, key: String, record: Option<Record>) -> io::Result<()> {
+    self.append(LogEntry::ReplicaEntry { id, key, record })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::hash;
+  use std::env;
+
+  /// Returns an empty directory for one test, under the system's temporary directory
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("halo-storage-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn record(value: &str, wall: u64) -> Record {
+    Record::new(
+      value.to_string(),
+      Version {
+        wall,
+        logical: 0,
+        writer: String::from("node-0"),
+      },
+    )
+  }
+
+  fn causal(value: Option<&str>, wall: u64, dot: (&str, u64), seen: &[(&str, u64)]) -> Record {
+    let mut clock = VectorClock::default();
+    for (name, count) in seen {
+      clock.set(name, *count);
+    }
+    let version = Version {
+      wall,
+      logical: 0,
+      writer: String::from(dot.0),
+    };
+    let dot = Dot {
+      node_name: String::from(dot.0),
+      count: dot.1,
+    };
+    Record::causal(value.map(String::from), version, clock, dot)
+  }
+
+  #[test]
+  fn newest_version_wins_without_siblings() {
+    let older = record("1", 1);
+    let newer = record("2", 2);
+    assert_eq!(older.merge(&newer), Some(newer.clone()));
+    assert_eq!(newer.merge(&older), None);
+    assert_eq!(newer.merge(&newer), None);
+  }
+
+  #[test]
+  fn causal_write_replaces_the_write_it_has_seen() {
+    let first = causal(Some("1"), 1, ("node-0", 1), &[]);
+    // Written later, but the clock says node-1 never saw the first write
+    let concurrent = causal(Some("2"), 2, ("node-1", 1), &[]);
+    // Written earlier by wall time, but the clock says it follows the first write
+    let descendant = causal(Some("3"), 0, ("node-2", 1), &[("node-0", 1)]);
+
+    assert_eq!(first.merge(&descendant), Some(descendant.clone()));
+    assert_eq!(descendant.merge(&first), None);
+    assert_eq!(first.merge(&concurrent).unwrap().values(), vec!["1", "2"]);
+  }
+
+  #[test]
+  fn record_without_siblings_is_kept_beside_siblings_until_a_write_has_seen_it() {
+    // Newer by wall time, but the sibling was not written after it, so both are kept
+    let lww = record("1", 5);
+    let sibling = causal(Some("2"), 1, ("node-1", 1), &[]);
+    let merged = lww.merge(&sibling).unwrap();
+    assert_eq!(merged.values(), vec!["2", "1"]);
+    assert_eq!(sibling.merge(&lww).unwrap(), merged);
+    assert_eq!(merged.merge(&lww), None);
+    assert_eq!(merged.merge(&sibling), None);
+
+    // A write made with the context of both replaces both
+    let dot = Dot {
+      node_name: String::from("node-1"),
+      count: 2,
+    };
+    let value = Some(String::from("3"));
+    let resolved = Record::causal(value, lww.version.clone(), merged.context(), dot);
+    assert_eq!(merged.merge(&resolved), Some(resolved.clone()));
+    assert_eq!(lww.merge(&resolved), Some(resolved));
+  }
+
+  #[test]
+  fn concurrent_writes_accumulate_as_siblings() {
+    let a = causal(Some("a"), 1, ("node-0", 1), &[]);
+    let b = causal(Some("b"), 2, ("node-1", 1), &[]);
+    let c = causal(None, 3, ("node-2", 1), &[]);
+    let merged = a.merge(&b).unwrap().merge(&c).unwrap();
+    assert_eq!(merged.siblings.len(), 3);
+    // A deletion is a sibling too, but has no value
+    assert_eq!(merged.values(), vec!["a", "b"]);
+    assert!(!merged.is_tombstone());
+    // The newest sibling gives the record its version
+    assert_eq!(merged.version.wall, 3);
+    // Merging is order independent and idempotent
+    assert_eq!(c.merge(&b).unwrap().merge(&a).unwrap(), merged);
+    assert_eq!(merged.merge(&a), None);
+  }
+
+  #[test]
+  fn blind_writes_stored_by_one_node_are_kept_as_siblings() {
+    // Neither write was made with a context, so neither has seen the other
+    let a = causal(Some("a"), 1, ("node-0", 1), &[]);
+    let b = causal(Some("b"), 2, ("node-0", 2), &[]);
+    let merged = a.merge(&b).unwrap();
+    assert_eq!(merged.values(), vec!["a", "b"]);
+    assert_eq!(b.merge(&a).unwrap(), merged);
+
+    // A write that saw only the first one replaces only that one
+    let c = causal(Some("c"), 3, ("node-0", 3), &[("node-0", 1)]);
+    assert_eq!(merged.merge(&c).unwrap().values(), vec!["b", "c"]);
+  }
+
+  #[test]
+  fn write_with_the_read_context_resolves_siblings() {
+    let siblings = causal(Some("a"), 1, ("node-0", 1), &[])
+      .merge(&causal(Some("b"), 2, ("node-1", 1), &[]))
+      .unwrap();
+    let context = siblings.context();
+    assert_eq!(context.get("node-0"), 1);
+    assert_eq!(context.get("node-1"), 1);
+
+    let version = Version {
+      wall: 3,
+      logical: 0,
+      writer: String::from("node-0"),
+    };
+    let dot = Dot {
+      node_name: String::from("node-0"),
+      count: 2,
+    };
+    let resolved = Record::causal(Some(String::from("c")), version, context, dot);
+    let merged = siblings.merge(&resolved).unwrap();
+    assert_eq!(merged, resolved);
+    assert_eq!(merged.values(), vec!["c"]);
+  }
+
+  fn value(storage: &DiskStorage, key: &str) -> Option<String> {
+    storage.get(key).map(|record| record.values().join(","))
+  }
+
+  #[test]
+  fn writes_survive_a_reopen() {
+    let dir = test_dir("reopen");
+    let id = hash::hash("node-1", 160);
+    {
+      let mut storage = DiskStorage::open(&dir).unwrap();
+      storage.set(String::from("A"), record("1", 1)).unwrap();
+      storage.set(String::from("B"), record("2", 2)).unwrap();
+      storage.remove("A").unwrap();
+      storage
+        .set_replica_entry(id, String::from("C"), Some(record("3", 3)))
+        .unwrap();
+    }
+
+    let storage = DiskStorage::open(&dir).unwrap();
+    assert_eq!(value(&storage, "A"), None);
+    assert_eq!(value(&storage, "B"), Some(String::from("2")));
+    assert_eq!(storage.replica_entry(id, "C"), Some(record("3", 3)));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn torn_last_entry_is_dropped() {
+    let dir = test_dir("torn");
+    {
+      let mut storage = DiskStorage::open(&dir).unwrap();
+      storage.set(String::from("A"), record("1", 1)).unwrap();
+    }
+    let mut log = OpenOptions::new()
+      .append(true)
+      .open(dir.join("wal.log"))
+      .unwrap();
+    log.write_all(br#"{"op":"Set","key":"B","rec"#).unwrap();
+
+    let storage = DiskStorage::open(&dir).unwrap();
+    assert_eq!(value(&storage, "A"), Some(String::from("1")));
+    assert_eq!(value(&storage, "B"), None);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn corrupt_entry_before_the_last_fails_the_open() {
+    let dir = test_dir("corrupt");
+    {
+      let mut storage = DiskStorage::open(&dir).unwrap();
+      storage.set(String::from("A"), record("1", 1)).unwrap();
+    }
+    let mut log = OpenOptions::new()
+      .append(true)
+      .open(dir.join("wal.log"))
+      .unwrap();
+    log.write_all(b"not an entry\n").unwrap();
+    // A valid entry follows the corrupt one, so it was not torn by a crash
+    let entry = LogEntry::Set {
+      key: String::from("B"),
+      record: record("2", 2),
+    };
+    log
+      .write_all(format!("{}\n", serde_json::to_string(&entry).unwrap()).as_bytes())
+      .unwrap();
+
+    let error = DiskStorage::open(&dir).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn compacted_log_round_trips_through_the_snapshot() {
+    let dir = test_dir("compact");
+    {
+      let mut storage = DiskStorage::open(&dir).unwrap();
+      for i in 0..COMPACTION_THRESHOLD + 10 {
+        storage
+          .set(format!("K{}", i % 100), record(&i.to_string(), i as u64))
+          .unwrap();
+      }
+      assert_eq!(storage.log_entries, 10);
+    }
+    assert!(dir.join("snapshot.json").exists());
+
+    let storage = DiskStorage::open(&dir).unwrap();
+    assert_eq!(storage.entries().len(), 100);
+    assert_eq!(value(&storage, "K9"), Some(String::from("1009")));
+    assert_eq!(value(&storage, "K10"), Some(String::from("910")));
+    assert_eq!(fs::metadata(dir.join("wal.log")).unwrap().len(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,273 @@
+// File: transport.rs
+//
+// The purpose of this file is to carry messages between a node and the rest
+// of the network, either through the chistributed broker or directly between
+// nodes that know each other's endpoints.
+
+use crate::error::MessageError;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// How a node exchanges messages with the rest of the network
+pub trait Transport: Send {
+  /// Blocks until the next message for this node arrives
+  /// Returns its contents, or why it could not be received
+  fn receive(&self) -> Result<String, MessageError>;
+
+  /// Sends a message to the process named in its destination,
+  /// or back to the client when it has no destination
+  /// Returns why the message could not be sent, if it was not
+  ///
+  /// # Arguments
+  ///
+  /// * `json` - The message, with virtual node names already routed to processes
+  fn send(&self, json: &serde_json::Value) -> Result<(), MessageError>;
+}
+
+/// The available transports
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportKind {
+  /// Every message goes through the chistributed broker
+  Broker,
+  /// Nodes send messages straight to each other's endpoints
+  Peer,
+}
+
+impl FromStr for TransportKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<TransportKind, String> {
+    match s {
+      "broker" => Ok(TransportKind::Broker),
+      "peer" => Ok(TransportKind::Peer),
+      _ => Err(format!("unknown transport: {}", s)),
+    }
+  }
+}
+
+/// Parses a node's endpoint given as name=endpoint on the command line
+///
+/// # Arguments
+///
+/// * `s` - The node's name and endpoint
+pub fn parse_endpoint(s: &str) -> Result<(String, String), String> {
+  match s.find('=') {
+    Some(i) if i > 0 && i + 1 < s.len() => Ok((String::from(&s[..i]), String::from(&s[i + 1..]))),
+    _ => Err(format!("expected name=endpoint, got {}", s)),
+  }
+}
+
+/// Talks to the chistributed broker: messages for this node are published on a SUB socket
+/// subscribed to its name, and every message is sent on a REQ socket the broker acknowledges
+pub struct BrokerTransport {
+  sub_socket: zmq::Socket,
+  req_socket: zmq::Socket,
+}
+
+impl BrokerTransport {
+  /// Returns a new BrokerTransport object
+  ///
+  /// # Arguments
+  ///
+  /// * `ctx` - A ZeroMQ Context for creating sockets
+  /// * `node_name` - Name of the node
+  /// * `pub_endpoint` - Endpoint for receiving messages
+  /// * `router_endpoint` - Endpoint for sending messages
+  pub fn new(
+    ctx: &zmq::Context,
+    node_name: &str,
+    pub_endpoint: &str,
+    router_endpoint: &str,
+  ) -> BrokerTransport {
+    let sub_socket = ctx.socket(zmq::SUB).unwrap();
+    sub_socket
+      .connect(pub_endpoint)
+      .expect("failed connecting subscriber");
+    sub_socket
+      .set_subscribe(node_name.as_bytes())
+      .expect("failed subscribing");
+
+    // The identity only applies to connections made after it is set
+    let req_socket = ctx.socket(zmq::REQ).unwrap();
+    req_socket
+      .set_identity(node_name.as_bytes())
+      .expect("failed requesting");
+    req_socket
+      .connect(router_endpoint)
+      .expect("failed connecting requester");
+
+    BrokerTransport {
+      sub_socket,
+      req_socket,
+    }
+  }
+}
+
+impl Transport for BrokerTransport {
+  fn receive(&self) -> Result<String, MessageError> {
+    // The broker publishes the address, an empty frame and the contents
+    let mut frames = self
+      .sub_socket
+      .recv_multipart(0)
+      .map_err(|e| MessageError::Receive(e.to_string()))?;
+    if frames.len() != 3 {
+      return Err(MessageError::Frames(frames.len()));
+    }
+    String::from_utf8(frames.remove(2)).map_err(|_| MessageError::NotUtf8)
+  }
+
+  fn send(&self, json: &serde_json::Value) -> Result<(), MessageError> {
+    self
+      .req_socket
+      .send(&json.to_string(), 0)
+      .map_err(|e| MessageError::Send(e.to_string()))?;
+
+    // Must receive acknowledgement from broker before next message can be read
+    self
+      .req_socket
+      .recv_bytes(0)
+      .map_err(|e| MessageError::Send(format!("no acknowledgement from broker: {}", e)))?;
+    Ok(())
+  }
+}
+
+/// Talks to other nodes without a broker: messages for this node, from nodes and clients
+/// alike, arrive on a PULL socket bound to its endpoint, messages for other nodes are pushed
+/// straight to theirs, and replies to clients are published on an optional PUB socket
+/// Sends never block: a message for a node that is down or unknown is dropped,
+/// as a broker would drop it, and its failure is left to the failure detectors
+pub struct PeerTransport {
+  pull_socket: zmq::Socket,
+  push_sockets: HashMap<String, zmq::Socket>,
+  reply_socket: Option<zmq::Socket>,
+}
+
+impl PeerTransport {
+  /// Returns a new PeerTransport object
+  /// No broker says hello to a standalone node, so it says hello to itself
+  /// to start joining the ring as soon as it is listening
+  ///
+  /// # Arguments
+  ///
+  /// * `ctx` - A ZeroMQ Context for creating sockets
+  /// * `node_name` - Name of the node
+  /// * `endpoint` - Endpoint the node receives messages on
+  /// * `peers` - Name and endpoint of every other node in the network
+  /// * `reply_endpoint` - Endpoint replies to clients are published on, if any
+  pub fn new(
+    ctx: &zmq::Context,
+    node_name: &str,
+    endpoint: &str,
+    peers: &[(String, String)],
+    reply_endpoint: Option<&str>,
+  ) -> PeerTransport {
+    let pull_socket = ctx.socket(zmq::PULL).unwrap();
+    pull_socket.bind(endpoint).expect("failed binding endpoint");
+
+    // A node also pushes to itself, as it pings its own virtual nodes
+    let mut push_sockets = HashMap::new();
+    let own = (String::from(node_name), String::from(endpoint));
+    for (name, peer_endpoint) in peers.iter().chain(std::iter::once(&own)) {
+      let push_socket = ctx.socket(zmq::PUSH).unwrap();
+      push_socket
+        .connect(peer_endpoint)
+        .expect("failed connecting to peer");
+      push_sockets.insert(name.to_string(), push_socket);
+    }
+
+    let reply_socket = reply_endpoint.map(|reply_endpoint| {
+      let reply_socket = ctx.socket(zmq::PUB).unwrap();
+      reply_socket
+        .bind(reply_endpoint)
+        .expect("failed binding reply endpoint");
+      reply_socket
+    });
+
+    let transport = PeerTransport {
+      pull_socket,
+      push_sockets,
+      reply_socket,
+    };
+    transport
+      .send(&serde_json::json!({ "type": "hello", "destination": node_name }))
+      .expect("failed greeting own endpoint");
+    transport
+  }
+}
+
+impl Transport for PeerTransport {
+  fn receive(&self) -> Result<String, MessageError> {
+    let contents = self
+      .pull_socket
+      .recv_bytes(0)
+      .map_err(|e| MessageError::Receive(e.to_string()))?;
+    String::from_utf8(contents).map_err(|_| MessageError::NotUtf8)
+  }
+
+  fn send(&self, json: &serde_json::Value) -> Result<(), MessageError> {
+    let socket = match json.get("destination").and_then(|d| d.as_str()) {
+      Some(destination) => match self.push_sockets.get(destination) {
+        Some(socket) => socket,
+        None => return Err(MessageError::Send(format!("unknown node {}", destination))),
+      },
+      None => match &self.reply_socket {
+        Some(socket) => socket,
+        None => return Ok(()),
+      },
+    };
+    socket
+      .send(&json.to_string(), zmq::DONTWAIT)
+      .map_err(|e| MessageError::Send(e.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Returns the next message a transport receives, or panics if none arrives within a second
+  fn next(transport: &PeerTransport) -> serde_json::Value {
+    transport.pull_socket.set_rcvtimeo(1000).unwrap();
+    let contents = transport.receive().expect("no message arrived");
+    serde_json::from_str(&contents).unwrap()
+  }
+
+  #[test]
+  fn peers_deliver_messages_to_each_other_and_publish_client_replies() {
+    let ctx = zmq::Context::new();
+    let peer = |name: &str| (String::from(name), format!("inproc://{}", name));
+    let a = PeerTransport::new(
+      &ctx,
+      "node-a",
+      "inproc://node-a",
+      &[peer("node-b")],
+      Some("inproc://replies"),
+    );
+    let b = PeerTransport::new(&ctx, "node-b", "inproc://node-b", &[peer("node-a")], None);
+
+    // Each node first greets itself
+    assert_eq!(next(&a), serde_json::json!({ "type": "hello", "destination": "node-a" }));
+    assert_eq!(next(&b), serde_json::json!({ "type": "hello", "destination": "node-b" }));
+
+    let ping = serde_json::json!({ "type": "ping", "source": "node-a", "destination": "node-b" });
+    a.send(&ping).unwrap();
+    assert_eq!(next(&b), ping);
+
+    let unknown =
+      serde_json::json!({ "type": "ping", "source": "node-a", "destination": "node-c" });
+    assert_eq!(a.send(&unknown).unwrap_err().kind(), "send");
+
+    // A subscription takes a moment to reach the publisher, so publish until one gets through
+    let client = ctx.socket(zmq::SUB).unwrap();
+    client.connect("inproc://replies").unwrap();
+    client.set_subscribe(b"").unwrap();
+    client.set_rcvtimeo(100).unwrap();
+    let reply = serde_json::json!({ "type": "setResponse", "id": 1, "key": "k", "value": "v" });
+    let published = (0..50).find_map(|_| {
+      a.send(&reply).unwrap();
+      client.recv_bytes(0).ok()
+    });
+    let published = published.expect("no reply was published");
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&published).unwrap(), reply);
+  }
+}